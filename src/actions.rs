@@ -0,0 +1,377 @@
+use bevy_ecs::event::Events;
+use bevy_ecs::prelude::*;
+
+use crate::components::*;
+use crate::devices::use_device;
+use crate::game_state::RunSummary;
+use crate::map::{Direction, Map};
+use crate::messages::Messages;
+
+pub struct MeleeEvent {
+    pub source: Entity,
+    pub target: Entity,
+}
+
+/// What an actor has chosen to do with its turn. The player's keys and the
+/// mobs' scripts both hand these in, and `resolve_actions` carries them out,
+/// so the same rules apply to everyone.
+#[derive(Debug, Component, Copy, Clone, PartialEq)]
+pub enum Action {
+    /// Step one tile. Stepping into someone you're fighting attacks them.
+    Move(Direction),
+    Melee(Entity),
+    Wait,
+    /// Pick up a device lying underfoot.
+    PickUp,
+    Use {
+        device: Entity,
+        tile: Position,
+    },
+}
+
+/// Carry out every action handed in this step, the player's first and then
+/// the mobs' in the order they were spawned. Each one sees what the ones
+/// before it did, so two mobs can't step onto the same tile.
+///
+/// The turn an action was handed in with has already been spent. The player
+/// gets it back for one that can't be carried out at all, like walking into
+/// a wall or using a device with no charge left. A mob doesn't: its script
+/// would only pick the same thing again, and the clock would never move on.
+pub fn resolve_actions(world: &mut World) {
+    let player = *world.get_resource::<Entity>().unwrap();
+    let mut actions: Vec<(Entity, Action)> = world
+        .query::<(Entity, &Action)>()
+        .iter(world)
+        .map(|(actor, action)| (actor, *action))
+        .collect();
+    actions.sort_by_key(|(actor, _)| (*actor != player, *actor));
+
+    for (actor, action) in actions {
+        world.entity_mut(actor).remove::<Action>();
+        let took_turn = resolve(world, actor, action);
+        if actor != player {
+            continue;
+        }
+        if took_turn {
+            world.get_resource_mut::<RunSummary>().unwrap().turns += 1;
+        } else if let Some(mut speed) = world.get_mut::<Speed>(actor) {
+            speed.energy += TURN_COST;
+        }
+    }
+}
+
+/// Carry out one action, and say whether it took the turn.
+fn resolve(world: &mut World, actor: Entity, action: Action) -> bool {
+    match action {
+        Action::Move(dir) => step(world, actor, dir),
+        Action::Melee(target) => melee(world, actor, target),
+        Action::Wait => true,
+        Action::PickUp => pick_up(world, actor),
+        Action::Use { device, tile } => use_device(world, device, tile),
+    }
+}
+
+/// Whether `a` and `b` attack each other on sight. For now that's the
+/// player and any mob; mobs leave each other alone.
+fn hostile(world: &World, a: Entity, b: Entity) -> bool {
+    let is_player = |e| world.get::<Player>(e).is_some();
+    a != b && (is_player(a) || is_player(b))
+}
+
+/// Whoever is standing on `tile`, the player included.
+fn occupant(world: &World, tile: &Position) -> Option<Entity> {
+    let player = *world.get_resource::<Entity>().unwrap();
+    if world.get::<Position>(player) == Some(tile) {
+        return Some(player);
+    }
+    let map = world.get_resource::<Map>().unwrap();
+    map.try_walk(tile)
+        .copied()
+        .filter(|e| world.get::<Mob>(*e).is_some())
+}
+
+/// Walking into a friend waits for them to move, which uses up the turn.
+/// Walking into a wall or off the map doesn't go anywhere, so it doesn't.
+fn step(world: &mut World, actor: Entity, dir: Direction) -> bool {
+    let from = *world.get::<Position>(actor).unwrap();
+    let map = world.get_resource::<Map>().unwrap();
+    let to = match map.step(dir, &from) {
+        Some(to) => to,
+        None => return false,
+    };
+    let walkable = map.walkable(&to);
+
+    match occupant(world, &to) {
+        Some(other) if hostile(world, actor, other) => resolve(world, actor, Action::Melee(other)),
+        Some(_) => true,
+        None if !walkable => false,
+        None => {
+            *world.get_mut::<Position>(actor).unwrap() = to;
+            if world.get::<Mob>(actor).is_some() {
+                let mut map = world.get_resource_mut::<Map>().unwrap();
+                map.move_entity(&from, &to, actor);
+            }
+            true
+        }
+    }
+}
+
+fn melee(world: &mut World, actor: Entity, target: Entity) -> bool {
+    let (from, to) = match (world.get::<Position>(actor), world.get::<Position>(target)) {
+        (Some(from), Some(to)) => (*from, *to),
+        _ => return true,
+    };
+    let adjacent = (from.x - to.x).abs() <= 1 && (from.y - to.y).abs() <= 1;
+    if !adjacent || world.get::<Stats>(target).is_none() {
+        return true;
+    }
+
+    let message = if world.get::<Player>(actor).is_some() {
+        "You attack!".to_string()
+    } else {
+        format!("{} attacks!", world.get::<Name>(actor).unwrap().name)
+    };
+    world
+        .get_resource_mut::<Events<MeleeEvent>>()
+        .unwrap()
+        .send(MeleeEvent {
+            source: actor,
+            target,
+        });
+    world.get_resource_mut::<Messages>().unwrap().add(message);
+    true
+}
+
+fn pick_up(world: &mut World, actor: Entity) -> bool {
+    let here = *world.get::<Position>(actor).unwrap();
+    let item = world
+        .query_filtered::<(Entity, &Position), (With<Device>, Without<CarriedBy>)>()
+        .iter(world)
+        .find(|(_, p)| **p == here)
+        .map(|(item, _)| item);
+    let item = match item {
+        Some(item) => item,
+        None => {
+            if world.get::<Player>(actor).is_some() {
+                let mut messages = world.get_resource_mut::<Messages>().unwrap();
+                messages.add("There is nothing here to pick up.");
+            }
+            return false;
+        }
+    };
+
+    let mut item_ref = world.entity_mut(item);
+    item_ref.remove::<Position>();
+    item_ref.insert(CarriedBy { owner: actor });
+    let name = world.get::<Name>(item).unwrap().name.clone();
+    let message = if world.get::<Player>(actor).is_some() {
+        format!("You pick up the {name}.")
+    } else {
+        format!(
+            "The {} picks up the {name}.",
+            world.get::<Name>(actor).unwrap().name
+        )
+    };
+    world.get_resource_mut::<Messages>().unwrap().add(message);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bracket_lib::prelude::*;
+
+    use super::*;
+    use crate::map::TileType;
+    use crate::script::*;
+    use crate::system::Viewshed;
+
+    /// A 5x5 room with a wall in the bottom right corner, and the player at
+    /// the bottom left.
+    fn world() -> World {
+        let mut world = World::new();
+        let mut map = Map::new(5, 5, 0, 4);
+        map.tiles.fill(TileType::Floor);
+        let corner = map.pos_to_idx(&Position { x: 4, y: 4 });
+        map.tiles[corner] = TileType::Wall;
+        world.insert_resource(map);
+        world.insert_resource(RunSummary::default());
+        world.insert_resource(Messages::default());
+        world.insert_resource(RandomNumberGenerator::seeded(1));
+        world.insert_resource(ScriptEngine::default());
+        world.init_resource::<Events<MeleeEvent>>();
+
+        let player = world
+            .spawn()
+            .insert(Player)
+            .insert(Name {
+                name: "Player".to_string(),
+            })
+            .insert(Position { x: 0, y: 4 })
+            .insert(Stats::new(10, 3))
+            .insert(Speed::new(NORMAL_SPEED))
+            .id();
+        world.insert_resource(player);
+        world
+    }
+
+    fn mob(world: &mut World, x: i32, y: i32) -> Entity {
+        let at = Position { x, y };
+        let mob = world
+            .spawn()
+            .insert(Mob { glyph: 'r' })
+            .insert(Name {
+                name: "Rat".to_string(),
+            })
+            .insert(at)
+            .insert(Stats::new(5, 0))
+            .insert(Speed::new(NORMAL_SPEED))
+            .id();
+        world
+            .get_resource_mut::<Map>()
+            .unwrap()
+            .add_entity(&at, mob);
+        mob
+    }
+
+    fn player(world: &World) -> Entity {
+        *world.get_resource::<Entity>().unwrap()
+    }
+
+    fn position(world: &World, actor: Entity) -> Position {
+        *world.get::<Position>(actor).unwrap()
+    }
+
+    /// Whether `actor` got its turn back.
+    fn refunded(world: &World, actor: Entity) -> bool {
+        world.get::<Speed>(actor).unwrap().energy == TURN_COST
+    }
+
+    #[test]
+    fn the_first_mover_gets_the_tile() {
+        let mut world = world();
+        let first = mob(&mut world, 1, 1);
+        let second = mob(&mut world, 3, 1);
+        world
+            .entity_mut(second)
+            .insert(Action::Move(Direction::West));
+        world
+            .entity_mut(first)
+            .insert(Action::Move(Direction::East));
+        resolve_actions(&mut world);
+
+        assert_eq!(position(&world, first), Position { x: 2, y: 1 });
+        assert_eq!(position(&world, second), Position { x: 3, y: 1 });
+        // the loser waited, which took its turn all the same
+        assert!(!refunded(&world, first));
+        assert!(!refunded(&world, second));
+        let map = world.get_resource::<Map>().unwrap();
+        assert_eq!(map.try_walk(&Position { x: 2, y: 1 }), Some(&first));
+    }
+
+    #[test]
+    fn moving_into_a_hostile_attacks_it() {
+        let mut world = world();
+        let rat = mob(&mut world, 1, 4);
+        world.entity_mut(rat).insert(Action::Move(Direction::West));
+        resolve_actions(&mut world);
+
+        assert_eq!(position(&world, rat), Position { x: 1, y: 4 });
+        assert!(!refunded(&world, rat));
+        let events = world.get_resource::<Events<MeleeEvent>>().unwrap();
+        let melee: Vec<(Entity, Entity)> = events
+            .get_reader()
+            .iter(events)
+            .map(|event| (event.source, event.target))
+            .collect();
+        assert_eq!(melee, [(rat, player(&world))]);
+    }
+
+    #[test]
+    fn blocked_moves_give_the_player_the_turn_back() {
+        let mut world = world();
+        let player = player(&world);
+        for (dir, x) in [(Direction::West, 0), (Direction::East, 1)] {
+            world.entity_mut(player).insert(Action::Move(dir));
+            resolve_actions(&mut world);
+            assert_eq!(position(&world, player), Position { x, y: 4 });
+        }
+        world.get_mut::<Position>(player).unwrap().x = 3;
+        world
+            .entity_mut(player)
+            .insert(Action::Move(Direction::East));
+        resolve_actions(&mut world);
+        assert_eq!(position(&world, player), Position { x: 3, y: 4 });
+
+        // off the map and into the wall, but not the step that went
+        assert_eq!(world.get::<Speed>(player).unwrap().energy, 2 * TURN_COST);
+        assert_eq!(world.get_resource::<RunSummary>().unwrap().turns, 1);
+    }
+
+    /// A mob's script would just walk into the wall again, so it waits.
+    #[test]
+    fn blocked_mobs_lose_the_turn() {
+        let mut world = world();
+        let into_wall = mob(&mut world, 3, 4);
+        let off_map = mob(&mut world, 4, 0);
+        world
+            .entity_mut(into_wall)
+            .insert(Action::Move(Direction::East));
+        world
+            .entity_mut(off_map)
+            .insert(Action::Move(Direction::North));
+        resolve_actions(&mut world);
+
+        for actor in [into_wall, off_map] {
+            assert!(!refunded(&world, actor));
+        }
+        assert_eq!(position(&world, into_wall), Position { x: 3, y: 4 });
+        assert_eq!(position(&world, off_map), Position { x: 4, y: 0 });
+    }
+
+    #[test]
+    fn using_a_device_takes_the_turn_and_the_mp() {
+        let mut world = world();
+        let player = player(&world);
+        let source = r#"fn on_use(mob, tile) { return [say("Zap.")]; }"#;
+        let program = Arc::new(Program::new("zapper", source).unwrap());
+        let device = world
+            .spawn()
+            .insert(Device { charge: 2 })
+            .insert(Name {
+                name: "zapper".to_string(),
+            })
+            .insert(CarriedBy { owner: player })
+            .insert(Script::new(program))
+            .id();
+        let tile = Position { x: 2, y: 2 };
+        let mut viewshed = Viewshed::new(8);
+        viewshed.visible_tiles.push(tile.point());
+        world.entity_mut(player).insert(viewshed);
+
+        world
+            .entity_mut(player)
+            .insert(Action::Use { device, tile });
+        resolve_actions(&mut world);
+        assert!(!refunded(&world, player));
+        assert_eq!(world.get::<Stats>(player).unwrap().mp.cur, 1);
+        assert_eq!(world.get_resource::<RunSummary>().unwrap().turns, 1);
+        let mut messages = world.get_resource_mut::<Messages>().unwrap();
+        assert_eq!(messages.current().as_deref(), Some("Zap."));
+        messages.advance();
+
+        // there isn't the MP for another go
+        world
+            .entity_mut(player)
+            .insert(Action::Use { device, tile });
+        resolve_actions(&mut world);
+        assert!(refunded(&world, player));
+        assert_eq!(world.get::<Stats>(player).unwrap().mp.cur, 1);
+        let mut messages = world.get_resource_mut::<Messages>().unwrap();
+        assert_eq!(
+            messages.current().as_deref(),
+            Some("The zapper needs 2 MP to use.")
+        );
+    }
+}
@@ -1,38 +1,88 @@
-use std::collections::HashMap;
-
 use bevy_ecs::prelude::*;
+use bracket_lib::prelude::*;
 
+use crate::actions::MeleeEvent;
 use crate::components::*;
-use crate::keyboard::*;
+use crate::game_state::RunSummary;
 use crate::map::*;
 use crate::messages::*;
 
+/// The side of the d20 that always hits, and does double damage.
+const CRITICAL: i32 = 20;
+
+/// The side of the d20 that always misses.
+const FUMBLE: i32 = 1;
+
+/// An attack roll beats this plus the defender's `defence` to hit.
+const TO_HIT: i32 = 10;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Blow {
+    Miss,
+    Hit,
+    Critical,
+}
+
 pub struct DealDamage {
     pub source: Entity,
     pub target: Entity,
     pub name: String,
     pub amount: i32,
+    pub blow: Blow,
+}
+
+/// Roll one blow of `attacker`'s against `defender`, and what it does.
+/// A d20 plus the attacker's `to_hit` has to beat `TO_HIT` plus the
+/// defender's `defence`, except that a 20 always hits, with the damage
+/// dice rolled twice, and a 1 always misses. A blow that lands does at
+/// least a point.
+pub fn roll_blow(
+    rng: &mut RandomNumberGenerator,
+    attacker: &Stats,
+    defender: &Stats,
+) -> (Blow, i32) {
+    let blow = blow_for(rng.roll_dice(1, 20), attacker, defender);
+    (blow, roll_damage(rng, blow, attacker))
+}
+
+/// Which kind of blow a d20 `roll` makes.
+fn blow_for(roll: i32, attacker: &Stats, defender: &Stats) -> Blow {
+    match roll {
+        CRITICAL => Blow::Critical,
+        FUMBLE => Blow::Miss,
+        _ if roll + attacker.to_hit >= TO_HIT + defender.defence => Blow::Hit,
+        _ => Blow::Miss,
+    }
+}
+
+fn roll_damage(rng: &mut RandomNumberGenerator, blow: Blow, attacker: &Stats) -> i32 {
+    match blow {
+        Blow::Miss => 0,
+        Blow::Hit => (rng.roll(attacker.damage) + attacker.attack).max(1),
+        Blow::Critical => {
+            (rng.roll(attacker.damage) + rng.roll(attacker.damage) + attacker.attack).max(1)
+        }
+    }
 }
 
 pub fn resolve_combat(
     mut reader: EventReader<MeleeEvent>,
     mut writer: EventWriter<DealDamage>,
-    query: Query<(Entity, &Position, &Stats, &Name)>,
+    mut rng: ResMut<RandomNumberGenerator>,
+    query: Query<(&Stats, &Name)>,
 ) {
-    let mut hm = HashMap::new();
-
-    for (entity, _, stats, name) in query.iter() {
-        hm.insert(entity, (*stats, &name.name));
-    }
-
     for event in reader.iter() {
-        let source_stats = hm.get(&event.source).unwrap();
-        let _target_stats = hm.get(&event.target).unwrap();
+        let (attacker, defender) = match (query.get(event.source), query.get(event.target)) {
+            (Ok(attacker), Ok(defender)) => (attacker, defender),
+            _ => continue,
+        };
+        let (blow, amount) = roll_blow(&mut rng, attacker.0, defender.0);
         writer.send(DealDamage {
             source: event.source,
             target: event.target,
-            name: source_stats.1.to_string(),
-            amount: 1,
+            name: attacker.1.name.clone(),
+            amount,
+            blow,
         });
     }
 }
@@ -43,30 +93,120 @@ pub fn deal_damage(
     mut messages: ResMut<Messages>,
     mut query: Query<(Entity, &mut Stats, &Name, &Position)>,
     mut map: ResMut<Map>,
+    mut summary: ResMut<RunSummary>,
     player_entity: Res<Entity>,
 ) {
-    let mut hm = HashMap::new();
     for event in reader.iter() {
-        hm.insert(event.target, (event.source, event.amount, &event.name));
-    }
+        let (_, mut stats, name, _) = match query.get_mut(event.target) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        let was_alive = stats.hp.cur >= 0;
+        stats.hp.cur -= event.amount;
 
-    for (entity, mut stats, name, pos) in query.iter_mut() {
-        if let Some(found) = hm.get(&entity) {
-            stats.hp.cur -= found.1;
-            if found.0 == *player_entity {
-                messages.add(format!("You hit {} for {} points", name.name, found.1));
-            } else {
-                messages.add(format!(
-                    "{} hits {} for {} points",
-                    found.2, name.name, found.1
-                ));
+        // something hurting itself is a status effect wearing it down,
+        // which only the player hears about
+        let own_doing = event.source == event.target;
+        let (who, miss, hit) = if event.source == *player_entity {
+            ("You".to_string(), "miss", "hit")
+        } else {
+            (event.name.clone(), "misses", "hits")
+        };
+        let amount = event.amount;
+        if own_doing {
+            if event.target == *player_entity {
+                messages.add(format!("The {} hurts you for {amount} points", event.name));
             }
+        } else {
+            messages.add(match event.blow {
+                Blow::Miss => format!("{who} {miss} {}", name.name),
+                Blow::Hit => format!("{who} {hit} {} for {amount} points", name.name),
+                Blow::Critical => {
+                    format!("{who} critically {hit} {} for {amount} points!", name.name)
+                }
+            });
         }
 
-        if stats.hp.cur < 0 {
+        if was_alive && stats.hp.cur < 0 {
+            if event.target == *player_entity {
+                messages.add("You die...");
+                summary.cause = Some(if own_doing {
+                    format!("Died of {}.", event.name)
+                } else {
+                    format!("Killed by a {}.", event.name)
+                });
+            } else if event.source == *player_entity {
+                summary.kills += 1;
+            }
+        }
+    }
+
+    for (entity, stats, _, pos) in query.iter() {
+        // the player stays put, for the game-over screen to report on
+        if stats.hp.cur < 0 && entity != *player_entity {
             map.remove_entity(&entity, pos);
             commands.entity(entity).despawn();
             messages.add("You killed it!");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two points a blow, whatever the dice roll.
+    fn fighter(to_hit: i32, attack: i32) -> Stats {
+        Stats::new(10, 0).with_attack(to_hit, DiceType::new(2, 1, 0), attack)
+    }
+
+    #[test]
+    fn a_natural_20_always_hits_for_double() {
+        let mut rng = RandomNumberGenerator::seeded(1);
+        let wall = Stats::new(10, 0).with_defence(100);
+        assert_eq!(blow_for(CRITICAL, &fighter(0, 0), &wall), Blow::Critical);
+        assert_eq!(roll_damage(&mut rng, Blow::Hit, &fighter(0, 0)), 2);
+        assert_eq!(roll_damage(&mut rng, Blow::Critical, &fighter(0, 0)), 4);
+
+        // nothing but a 20 gets through that defence
+        let mut criticals = 0;
+        for _ in 0..200 {
+            let (blow, amount) = roll_blow(&mut rng, &fighter(0, 0), &wall);
+            match blow {
+                Blow::Critical => {
+                    criticals += 1;
+                    assert_eq!(amount, 4);
+                }
+                blow => assert_eq!((blow, amount), (Blow::Miss, 0)),
+            }
+        }
+        assert!(criticals > 0);
+    }
+
+    #[test]
+    fn a_natural_1_always_misses() {
+        let target = Stats::new(10, 0);
+        assert_eq!(blow_for(FUMBLE, &fighter(100, 0), &target), Blow::Miss);
+        assert_eq!(blow_for(FUMBLE + 1, &fighter(100, 0), &target), Blow::Hit);
+    }
+
+    #[test]
+    fn defence_raises_the_target() {
+        let attacker = fighter(2, 0);
+        let unarmoured = Stats::new(10, 0);
+        let armoured = Stats::new(10, 0).with_defence(3);
+        assert_eq!(blow_for(8, &attacker, &unarmoured), Blow::Hit);
+        assert_eq!(blow_for(7, &attacker, &unarmoured), Blow::Miss);
+        assert_eq!(blow_for(11, &attacker, &armoured), Blow::Hit);
+        assert_eq!(blow_for(10, &attacker, &armoured), Blow::Miss);
+    }
+
+    #[test]
+    fn a_blow_that_lands_does_a_point() {
+        let mut rng = RandomNumberGenerator::seeded(1);
+        let feeble = fighter(0, -10);
+        assert_eq!(roll_damage(&mut rng, Blow::Hit, &feeble), 1);
+        assert_eq!(roll_damage(&mut rng, Blow::Critical, &feeble), 1);
+        assert_eq!(roll_damage(&mut rng, Blow::Miss, &feeble), 0);
+    }
+}
@@ -1,6 +1,10 @@
+use std::collections::BTreeMap;
+
 use bevy_ecs::prelude::*;
 use bracket_lib::prelude::*;
 
+use crate::script::Value;
+
 mod player;
 pub use player::Player;
 
@@ -31,6 +35,23 @@ impl From<&Point> for Position {
     }
 }
 
+/// The device the player reads the programs of other mobs with.
+#[derive(Debug, Component)]
+pub struct CodeScanner;
+
+/// An item that runs its `Script` when used: a wand, a remote control, a
+/// phaser. Every use costs whoever uses it `charge` points of MP.
+#[derive(Debug, Component, Copy, Clone)]
+pub struct Device {
+    pub charge: i32,
+}
+
+/// An item that is being carried rather than lying on the map.
+#[derive(Debug, Component, Copy, Clone)]
+pub struct CarriedBy {
+    pub owner: Entity,
+}
+
 #[derive(Debug, Component)]
 pub struct Mob {
     pub glyph: char,
@@ -42,10 +63,18 @@ pub struct Stat {
     pub cur: i32,
 }
 
+/// What something is made of. The melee side of it is used by
+/// `resolve_combat`: `to_hit` is added to the attack roll and `defence`
+/// makes it harder to beat, while a blow that lands does `damage` plus
+/// `attack` points.
 #[derive(Debug, Component, Copy, Clone)]
 pub struct Stats {
     pub hp: Stat,
     pub mp: Stat,
+    pub attack: i32,
+    pub defence: i32,
+    pub to_hit: i32,
+    pub damage: DiceType,
 }
 
 impl Stats {
@@ -53,21 +82,172 @@ impl Stats {
         Self {
             hp: Stat { max: hp, cur: hp },
             mp: Stat { max: mp, cur: mp },
+            attack: 0,
+            defence: 0,
+            to_hit: 0,
+            // a point a blow, until told otherwise
+            damage: DiceType::new(0, 0, 1),
         }
     }
+
+    pub fn with_attack(mut self, to_hit: i32, damage: DiceType, attack: i32) -> Self {
+        self.to_hit = to_hit;
+        self.damage = damage;
+        self.attack = attack;
+        self
+    }
+
+    pub fn with_defence(mut self, defence: i32) -> Self {
+        self.defence = defence;
+        self
+    }
 }
 
+/// The energy a turn costs. Anything with a `Speed` gets a turn whenever it
+/// has this much saved up.
+pub const TURN_COST: i32 = 100;
+
+/// The speed of the player and of most mobs: a turn every ten ticks.
+pub const NORMAL_SPEED: i32 = 10;
+
+/// How quickly something acts. Each tick of the clock it gains `speed`
+/// energy, so something twice as fast as normal gets two turns to everyone
+/// else's one.
 #[derive(Debug, Component, Copy, Clone)]
-pub struct WantsToMove {
-    pub location: Position,
+pub struct Speed {
+    pub speed: i32,
+    /// The speed with no status effects on it.
+    pub base: i32,
+    pub energy: i32,
 }
 
-#[derive(Debug, Component, Copy, Clone)]
-pub struct WantsToMelee {
-    pub target: Entity,
+impl Speed {
+    pub fn new(speed: i32) -> Self {
+        Self {
+            speed,
+            base: speed,
+            energy: 0,
+        }
+    }
+
+    pub fn ready(&self) -> bool {
+        self.energy >= TURN_COST
+    }
+
+    /// Use up a turn's worth of energy.
+    pub fn spend(&mut self) {
+        self.energy -= TURN_COST;
+    }
+
+    /// Ticks until there is a turn's worth of energy saved up, or None if
+    /// that will never happen.
+    pub fn ticks_until_ready(&self) -> Option<i32> {
+        if self.ready() {
+            Some(0)
+        } else if self.speed > 0 {
+            Some((TURN_COST - self.energy + self.speed - 1) / self.speed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Something that lasts a while: a turn's worth of each is applied by
+/// `tick_status_effects` every turn, at normal speed, until it runs out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Effect {
+    /// A point of damage a turn.
+    Poison,
+    /// Every turn is lost.
+    Stun,
+    /// Twice the usual speed.
+    Haste,
+    /// A point of HP back a turn.
+    Regen,
+}
+
+impl Effect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "poison" => Some(Effect::Poison),
+            "stun" => Some(Effect::Stun),
+            "haste" => Some(Effect::Haste),
+            "regen" => Some(Effect::Regen),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Effect::Poison => "poison",
+            Effect::Stun => "stun",
+            Effect::Haste => "haste",
+            Effect::Regen => "regen",
+        }
+    }
+
+    /// How to say something has it: "you are ...".
+    pub fn adjective(&self) -> &'static str {
+        match self {
+            Effect::Poison => "poisoned",
+            Effect::Stun => "stunned",
+            Effect::Haste => "hasted",
+            Effect::Regen => "regenerating",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StatusEffect {
+    pub effect: Effect,
+    /// Turns left before it wears off.
+    pub turns: i32,
+}
+
+/// The effects something is under. Each effect is there at most once.
+#[derive(Debug, Component, Default)]
+pub struct StatusEffects {
+    pub effects: Vec<StatusEffect>,
+}
+
+impl StatusEffects {
+    /// Put `effect` on for `turns` turns. One that's already on lasts for
+    /// whichever is longer, rather than stacking.
+    pub fn add(&mut self, effect: Effect, turns: i32) {
+        match self.effects.iter_mut().find(|e| e.effect == effect) {
+            Some(existing) => existing.turns = existing.turns.max(turns),
+            None => self.effects.push(StatusEffect { effect, turns }),
+        }
+    }
+
+    pub fn has(&self, effect: Effect) -> bool {
+        self.effects.iter().any(|e| e.effect == effect)
+    }
 }
 
 #[derive(Debug, Component, Clone)]
 pub struct Name {
     pub name: String,
 }
+
+/// A mob's script memory. Its script reads and writes it through `memory`,
+/// and whatever is stored stays put from one turn to the next. Keys are kept
+/// in order so the console lists them the same way every time.
+#[derive(Debug, Component, Default)]
+pub struct Blackboard {
+    pub values: BTreeMap<String, Value>,
+}
+
+impl Blackboard {
+    pub fn get(&self, key: &str) -> Value {
+        self.values.get(key).copied().unwrap_or(Value::Nil)
+    }
+
+    /// Remember `value` under `key`. Storing nil forgets the key.
+    pub fn set(&mut self, key: &str, value: Value) {
+        match value {
+            Value::Nil => self.values.remove(key),
+            _ => self.values.insert(key.to_string(), value),
+        };
+    }
+}
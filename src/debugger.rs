@@ -0,0 +1,297 @@
+use bevy_ecs::prelude::*;
+use bevy_ecs::system::SystemState;
+use bracket_lib::prelude::*;
+
+use crate::components::*;
+use crate::map::Map;
+use crate::messages::Messages;
+use crate::script::*;
+use crate::system::*;
+
+/// How many rows of source the debugger shows.
+const SOURCE_ROWS: usize = 11;
+
+/// The script debugger: the source of one mob's script with its
+/// breakpoints, where it is stopped, what its locals and memory hold, and
+/// the intents it has produced, along with a map of where the mob is.
+pub struct Debugger {
+    mob: Option<Entity>,
+    /// The source line the cursor is on, counting from 1 like the compiler.
+    cursor: usize,
+    top: usize,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            mob: None,
+            cursor: 1,
+            top: 0,
+        }
+    }
+
+    pub fn mob(&self) -> Option<Entity> {
+        self.mob
+    }
+
+    /// Start debugging `mob`, which must have a script. Only one mob can be
+    /// debugged at a time, so any other mob is let go.
+    pub fn attach(&mut self, world: &mut World, mob: Entity) {
+        if let Some(old) = self.mob.filter(|old| *old != mob) {
+            self.detach(world, old);
+        }
+        if let Some(mut script) = world.get_mut::<Script>(mob) {
+            script.debug.get_or_insert_with(DebugState::default);
+        }
+        if self.mob != Some(mob) {
+            self.mob = Some(mob);
+            self.cursor = 1;
+            self.top = 0;
+        }
+    }
+
+    /// Let `mob` run without the debugger. A call stopped at a breakpoint
+    /// carries on from there next turn.
+    pub fn detach(&mut self, world: &mut World, mob: Entity) {
+        if let Some(mut script) = world.get_mut::<Script>(mob) {
+            script.debug = None;
+        }
+        self.mob = None;
+    }
+
+    /// Move the cursor to `line`, scrolling it into view.
+    pub fn show_line(&mut self, line: usize) {
+        self.cursor = line.max(1);
+        if self.cursor <= self.top {
+            self.top = self.cursor - 1;
+        } else if self.cursor > self.top + SOURCE_ROWS {
+            self.top = self.cursor - SOURCE_ROWS;
+        }
+    }
+
+    pub fn key(&mut self, key: VirtualKeyCode, line_count: usize) {
+        let last = line_count.max(1);
+        let line = match key {
+            VirtualKeyCode::Up => self.cursor.saturating_sub(1),
+            VirtualKeyCode::Down => (self.cursor + 1).min(last),
+            VirtualKeyCode::PageUp => self.cursor.saturating_sub(SOURCE_ROWS),
+            VirtualKeyCode::PageDown => (self.cursor + SOURCE_ROWS).min(last),
+            VirtualKeyCode::Home => 1,
+            _ => self.cursor,
+        };
+        self.show_line(line);
+    }
+
+    /// Put a breakpoint on the cursor's line, or take it off again.
+    pub fn toggle_breakpoint(&self, debug: &mut DebugState) {
+        let lines = &mut debug.breakpoints.lines;
+        if !lines.remove(&self.cursor) {
+            lines.insert(self.cursor);
+        }
+    }
+
+    pub fn draw(&self, ctx: &mut BTerm, world: &World, width: i32, height: i32) {
+        ctx.print_color(
+            0,
+            height - 1,
+            RGB::named(YELLOW),
+            RGB::named(BLACK),
+            "F9 brk F10 step F5 run D detach Esc",
+        );
+
+        let mob = self.mob.and_then(|mob| {
+            let script = world.get::<Script>(mob)?;
+            Some((mob, world.get::<Name>(mob)?, script, script.debug.as_ref()?))
+        });
+        let (mob, name, script, debug) = match mob {
+            Some(mob) => mob,
+            None => {
+                ctx.print(0, 0, "Debugging: nothing");
+                return;
+            }
+        };
+        ctx.print(0, 0, format!("Debugging: {}", name.name));
+
+        let stopped_at = debug.paused.as_ref().map(|pause| pause.line);
+        let lines: Vec<&str> = script.program.source.lines().collect();
+        for (y, (idx, line)) in lines
+            .iter()
+            .enumerate()
+            .skip(self.top)
+            .take(SOURCE_ROWS)
+            .enumerate()
+        {
+            let y = y as i32 + 1;
+            let number = idx + 1;
+            ctx.print_color(
+                0,
+                y,
+                RGB::named(GREY50),
+                RGB::named(BLACK),
+                format!("{number:3}"),
+            );
+            if debug.breakpoints.lines.contains(&number) {
+                ctx.set(3, y, RGB::named(RED), RGB::named(BLACK), to_cp437('*'));
+            }
+            if stopped_at == Some(number) {
+                ctx.set(4, y, RGB::named(YELLOW), RGB::named(BLACK), to_cp437('>'));
+            }
+            let bg = if number == self.cursor {
+                RGB::named(GREY25)
+            } else {
+                RGB::named(BLACK)
+            };
+            let code: String = line.chars().take(width as usize - 5).collect();
+            ctx.print_color(5, y, RGB::named(GREEN), bg, code);
+        }
+
+        let status_row = SOURCE_ROWS as i32 + 1;
+        let status = match &debug.paused {
+            Some(pause) => format!("Stopped at line {} in {}", pause.line, pause.function),
+            None if debug.held.is_some() => "Done; waiting for the next turn".to_string(),
+            None => "Running".to_string(),
+        };
+        ctx.print_color(
+            0,
+            status_row,
+            RGB::named(MAGENTA),
+            RGB::named(BLACK),
+            status,
+        );
+
+        let top = status_row + 1;
+        let rows = height - 1 - top;
+        self.draw_map(ctx, world, mob, Rect::with_size(0, top, 15, rows));
+        self.draw_state(
+            ctx,
+            world,
+            mob,
+            debug,
+            Rect::with_size(16, top, width - 16, rows),
+        );
+    }
+
+    /// Draw the part of the map around the mob, with the mob picked out.
+    fn draw_map(&self, ctx: &mut BTerm, world: &World, mob: Entity, area: Rect) {
+        let map = world.get_resource::<Map>().unwrap();
+        let centre = match world.get::<Position>(mob) {
+            Some(p) => *p,
+            None => return,
+        };
+        let left = centre.x - area.width() / 2;
+        let top = centre.y - area.height() / 2;
+
+        for y in 0..area.height() {
+            for x in 0..area.width() {
+                let p = Position {
+                    x: left + x,
+                    y: top + y,
+                };
+                if map.point_to_idx(&p.point()).is_none() {
+                    continue;
+                }
+                let (glyph, fg, bg) = match map.try_walk(&p) {
+                    Some(&entity) => {
+                        let glyph = if world.get::<Player>(entity).is_some() {
+                            '@'
+                        } else {
+                            world.get::<Mob>(entity).map_or('?', |m| m.glyph)
+                        };
+                        if entity == mob {
+                            (glyph, RGB::named(BLACK), RGB::named(YELLOW))
+                        } else {
+                            (glyph, RGB::named(WHITE), RGB::named(BLACK))
+                        }
+                    }
+                    None if map.xy_is_opaque(&p.point()) => {
+                        ('#', RGB::named(GREY50), RGB::named(BLACK))
+                    }
+                    None => ('.', RGB::named(GREY50), RGB::named(BLACK)),
+                };
+                ctx.set(area.x1 + x, area.y1 + y, fg, bg, to_cp437(glyph));
+            }
+        }
+    }
+
+    /// Draw the locals at the pause, the mob's memory, and its latest
+    /// intents, for as many as fit.
+    fn draw_state(
+        &self,
+        ctx: &mut BTerm,
+        world: &World,
+        mob: Entity,
+        debug: &DebugState,
+        area: Rect,
+    ) {
+        let heap = &world.get_resource::<ScriptEngine>().unwrap().heap;
+        let mut lines = vec![("locals".to_string(), true)];
+        if let Some(pause) = &debug.paused {
+            for (name, value) in &pause.locals {
+                lines.push((format!("{name} = {value}"), false));
+            }
+        }
+        lines.push(("memory".to_string(), true));
+        if let Some(memory) = world.get::<Blackboard>(mob) {
+            for (key, value) in &memory.values {
+                lines.push((format!("{key} = {}", heap.format(*value)), false));
+            }
+        }
+        lines.push(("intents".to_string(), true));
+        let space = (area.height() as usize).saturating_sub(lines.len());
+        let skip = debug.intents.len().saturating_sub(space);
+        for intent in debug.intents.iter().skip(skip) {
+            lines.push((intent.clone(), false));
+        }
+
+        for (y, (line, heading)) in lines.iter().take(area.height() as usize).enumerate() {
+            let line: String = line.chars().take(area.width() as usize).collect();
+            let fg = if *heading {
+                RGB::named(MAGENTA)
+            } else {
+                RGB::named(WHITE)
+            };
+            ctx.print_color(area.x1, area.y1 + y as i32, fg, RGB::named(BLACK), line);
+        }
+    }
+}
+
+/// What running a mob's turn needs from the world, as `move_mobs` has it.
+type TurnParams<'w, 's> = (
+    ResMut<'w, ScriptEngine>,
+    ResMut<'w, RandomNumberGenerator>,
+    ResMut<'w, Messages>,
+    Res<'w, Map>,
+    Res<'w, Entity>,
+    Query<'w, 's, &'static Position>,
+    Query<'w, 's, ScriptedMob<'static>>,
+);
+
+/// Carry on with a script that is stopped at a breakpoint, outside of the
+/// mob's turn. Whatever it ends up wanting to do is held for its next turn.
+pub fn carry_on(world: &mut World, mob: Entity) {
+    let mut state: SystemState<TurnParams> = SystemState::new(world);
+    let (mut engine, mut rng, mut messages, map, player, positions, mut query) =
+        state.get_mut(world);
+
+    let player = positions.get(*player).ok().map(|p| (*player, *p));
+    let (_, position, name, stats, viewshed, mut script, mut memory) = match query.get_mut(mob) {
+        Ok(mob) => mob,
+        Err(_) => return,
+    };
+
+    let mut game = GameView {
+        map: Some(&map),
+        rng: Some(&mut rng),
+        player,
+        actor: Some(Actor::new(position, name, stats, viewshed)),
+        memory: memory.as_deref_mut(),
+        level: None,
+    };
+    let result = engine.run_turn(&mut script, &mut game);
+    let intent = script_outcome(result, &engine, &mut messages, name, &script);
+    if let Some(debug) = &mut script.debug {
+        if debug.paused.is_none() {
+            debug.held = intent;
+        }
+    }
+}
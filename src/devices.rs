@@ -0,0 +1,314 @@
+use bevy_ecs::event::Events;
+use bevy_ecs::prelude::*;
+use bevy_ecs::system::SystemState;
+use bracket_lib::prelude::*;
+
+use crate::combat::{Blow, DealDamage};
+use crate::components::*;
+use crate::map::{Direction, Map};
+use crate::messages::Messages;
+use crate::script::*;
+use crate::system::Viewshed;
+
+/// Picking a tile to use one of the player's devices on. The cursor starts
+/// on the nearest mob in sight; Tab moves it on to the next one.
+pub struct Targeting {
+    devices: Vec<Entity>,
+    current: usize,
+    targets: Vec<Position>,
+    target: usize,
+    pub cursor: Position,
+}
+
+impl Targeting {
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+            current: 0,
+            targets: Vec::new(),
+            target: 0,
+            cursor: Position { x: 0, y: 0 },
+        }
+    }
+
+    /// Start aiming one of `devices` at one of `targets`, nearest first, or
+    /// at `start` if there is nothing to aim at.
+    pub fn open(&mut self, devices: Vec<Entity>, targets: Vec<Position>, start: Position) {
+        self.devices = devices;
+        self.current = self.current.min(self.devices.len().saturating_sub(1));
+        self.cursor = targets.first().copied().unwrap_or(start);
+        self.targets = targets;
+        self.target = 0;
+    }
+
+    pub fn device(&self) -> Option<Entity> {
+        self.devices.get(self.current).copied()
+    }
+
+    pub fn next_device(&mut self) {
+        if !self.devices.is_empty() {
+            self.current = (self.current + 1) % self.devices.len();
+        }
+    }
+
+    pub fn next_target(&mut self) {
+        if !self.targets.is_empty() {
+            self.target = (self.target + 1) % self.targets.len();
+            self.cursor = self.targets[self.target];
+        }
+    }
+
+    /// Move the cursor a tile, keeping it on the map.
+    pub fn key(&mut self, key: VirtualKeyCode, map: &Map) {
+        let dir = match key {
+            VirtualKeyCode::Left | VirtualKeyCode::H => Direction::West,
+            VirtualKeyCode::Right | VirtualKeyCode::L => Direction::East,
+            VirtualKeyCode::Down | VirtualKeyCode::J => Direction::South,
+            VirtualKeyCode::Up | VirtualKeyCode::K => Direction::North,
+            VirtualKeyCode::Y => Direction::NorthWest,
+            VirtualKeyCode::U => Direction::NorthEast,
+            VirtualKeyCode::B => Direction::SouthWest,
+            VirtualKeyCode::N => Direction::SouthEast,
+            _ => return,
+        };
+        self.cursor = map.new_position(dir, &self.cursor);
+    }
+
+    /// Draw the cursor at `at` on the screen, with what the device is and
+    /// what it is pointed at.
+    pub fn draw(&self, ctx: &mut BTerm, world: &World, at: Point, height: i32) {
+        ctx.set_bg(at.x, at.y, RGB::named(YELLOW));
+
+        let device = match self.device() {
+            Some(device) => device,
+            None => return,
+        };
+        let name = world.get::<Name>(device).map_or("?", |name| &name.name);
+        let charge = world
+            .get::<Device>(device)
+            .map_or(0, |device| device.charge);
+        let map = world.get_resource::<Map>().unwrap();
+        let target = match map.try_walk(&self.cursor) {
+            Some(&mob) => world
+                .get::<Name>(mob)
+                .map_or("something", |name| &name.name),
+            None if map.walkable(&self.cursor) => "floor",
+            None => "wall",
+        };
+        ctx.print(0, 0, format!("{name} ({charge} MP) at {target}"));
+        ctx.print_color(
+            13,
+            height - 1,
+            RGB::named(YELLOW),
+            RGB::named(BLACK),
+            "Enter use Tab aim Z swap",
+        );
+    }
+}
+
+/// What using a device needs from the world.
+type DeviceParams<'w, 's> = (
+    ResMut<'w, ScriptEngine>,
+    ResMut<'w, RandomNumberGenerator>,
+    Res<'w, Map>,
+    Query<
+        'w,
+        's,
+        (
+            &'static Position,
+            &'static Name,
+            Option<&'static Stats>,
+            Option<&'static Viewshed>,
+        ),
+    >,
+    Query<'w, 's, &'static mut Script>,
+);
+
+/// Use `device` on `tile`. Its script's `on_use(mob, tile)` is called with
+/// whatever mob is standing there, or nil, and what it hands back is
+/// carried out. Returns whether that took a turn; a device its user can't
+/// pay for, or can't see to aim, does nothing. The MP is only paid once the
+/// script has run, so a device that fizzles costs nothing.
+pub fn use_device(world: &mut World, device: Entity, tile: Position) -> bool {
+    let user = match world.get::<CarriedBy>(device) {
+        Some(carried) => carried.owner,
+        None => return false,
+    };
+    let name = world.get::<Name>(device).unwrap().name.clone();
+    let charge = world.get::<Device>(device).unwrap().charge;
+
+    let in_sight = world
+        .get::<Viewshed>(user)
+        .is_some_and(|vs| vs.visible_tiles.contains(&tile.point()));
+    if !in_sight {
+        add_message(world, "You can't see there.");
+        return false;
+    }
+    let can_pay = world
+        .get::<Stats>(user)
+        .is_some_and(|stats| stats.mp.cur >= charge);
+    if !can_pay {
+        add_message(world, format!("The {name} needs {charge} MP to use."));
+        return false;
+    }
+
+    let mob = world
+        .get_resource::<Map>()
+        .unwrap()
+        .try_walk(&tile)
+        .copied();
+    let args = [mob.map_or(Value::Nil, Value::Entity), Value::Position(tile)];
+
+    let result = {
+        let mut state: SystemState<DeviceParams> = SystemState::new(world);
+        let (mut engine, mut rng, map, users, mut scripts) = state.get_mut(world);
+        let (position, user_name, stats, viewshed) = users.get(user).unwrap();
+        let mut script = scripts.get_mut(device).unwrap();
+        let mut game = GameView {
+            map: Some(&map),
+            rng: Some(&mut rng),
+            player: Some((user, *position)),
+            actor: Some(Actor::new(position, user_name, stats, viewshed)),
+            memory: None,
+            level: None,
+        };
+        engine
+            .run_effects(&mut script, &mut game, "on_use", &args)
+            .map_err(|e| format!("{name}: {} script error: {e}", script.program.name))
+    };
+
+    match result {
+        Ok(intents) => {
+            world.get_mut::<Stats>(user).unwrap().mp.cur -= charge;
+            for intent in intents {
+                carry_out(world, user, intent);
+            }
+            true
+        }
+        Err(e) => {
+            console::log(e);
+            add_message(world, format!("The {name} fizzles."));
+            false
+        }
+    }
+}
+
+/// Do what a device asked for, as far as the world allows.
+fn carry_out(world: &mut World, user: Entity, intent: Intent) {
+    match intent {
+        Intent::Say(text) => {
+            let text = world
+                .get_resource::<ScriptEngine>()
+                .unwrap()
+                .heap
+                .format(Value::Obj(text));
+            add_message(world, text);
+        }
+        Intent::Damage(target, amount) => {
+            if world.get::<Stats>(target).is_none() {
+                return;
+            }
+            let name = world.get::<Name>(user).unwrap().name.clone();
+            world
+                .get_resource_mut::<Events<DealDamage>>()
+                .unwrap()
+                .send(DealDamage {
+                    source: user,
+                    target,
+                    name,
+                    amount,
+                    blow: Blow::Hit,
+                });
+        }
+        Intent::Teleport(target, to) => teleport(world, target, to),
+        Intent::Reprogram(target, path) => {
+            let path = world
+                .get_resource::<ScriptEngine>()
+                .unwrap()
+                .heap
+                .format(Value::Obj(path));
+            reprogram(world, target, &path);
+        }
+        Intent::Afflict(target, effect, turns) => afflict(world, target, effect, turns),
+        Intent::Move(_) | Intent::Wait => {}
+    }
+}
+
+fn afflict(world: &mut World, target: Entity, effect: Effect, turns: i32) {
+    if world.get::<Stats>(target).is_none() {
+        return;
+    }
+    let mut target_ref = world.entity_mut(target);
+    match target_ref.get_mut::<StatusEffects>() {
+        Some(mut status) => status.add(effect, turns),
+        None => {
+            let mut status = StatusEffects::default();
+            status.add(effect, turns);
+            target_ref.insert(status);
+        }
+    }
+
+    let player = *world.get_resource::<Entity>().unwrap();
+    let message = if target == player {
+        format!("You are {}.", effect.adjective())
+    } else {
+        let name = &world.get::<Name>(target).unwrap().name;
+        format!("The {name} is {}.", effect.adjective())
+    };
+    add_message(world, message);
+}
+
+fn teleport(world: &mut World, target: Entity, to: Position) {
+    let from = match world.get::<Position>(target) {
+        Some(from) => *from,
+        None => return,
+    };
+    let map = world.get_resource::<Map>().unwrap();
+    let player = *world.get_resource::<Entity>().unwrap();
+    let player_at = *world.get::<Position>(player).unwrap();
+    let clear = map.point_to_idx(&to.point()).is_some()
+        && map.can_move_mob(world, &to)
+        && (to != player_at || target == player);
+    if !clear {
+        add_message(world, "Something blocks the way.");
+        return;
+    }
+
+    *world.get_mut::<Position>(target).unwrap() = to;
+    if world.get::<Mob>(target).is_some() {
+        let mut map = world.get_resource_mut::<Map>().unwrap();
+        map.move_entity(&from, &to, target);
+    }
+    if target == player {
+        add_message(world, "You vanish and reappear.");
+    } else {
+        let name = world.get::<Name>(target).unwrap().name.clone();
+        add_message(world, format!("The {name} vanishes."));
+    }
+}
+
+/// Give a mob a new program. It starts from scratch, with `on_spawn` and
+/// all, but keeps its memory.
+fn reprogram(world: &mut World, target: Entity, path: &str) {
+    if world.get::<Mob>(target).is_none() {
+        add_message(world, "Nothing happens.");
+        return;
+    }
+    match load_program(path) {
+        Ok(program) => {
+            let name = world.get::<Name>(target).unwrap().name.clone();
+            world.entity_mut(target).insert(Script::new(program));
+            add_message(world, format!("The {name} is now running {path}."));
+        }
+        Err(errors) => {
+            for e in errors {
+                console::log(e);
+            }
+            add_message(world, "Nothing happens.");
+        }
+    }
+}
+
+fn add_message<T: ToString>(world: &mut World, message: T) {
+    world.get_resource_mut::<Messages>().unwrap().add(message);
+}
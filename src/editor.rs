@@ -0,0 +1,349 @@
+use bracket_lib::prelude::*;
+
+use crate::script::*;
+
+/// What the editor fills in when a mob has no script yet.
+const TEMPLATE: &str = "fn on_turn() {\n    return wait();\n}\n";
+
+const INDENT: usize = 4;
+
+/// One row on screen: either a line of the script, or an error message
+/// shown underneath the line it belongs to.
+enum Row<'a> {
+    Text(usize),
+    Error(&'a str),
+}
+
+/// The in-game script editor. It keeps the script compiling in the
+/// background as it is typed, so errors show up as soon as they are made.
+pub struct Editor {
+    lines: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+    top: usize,
+    left: usize,
+    diagnostics: Vec<Diagnostic>,
+    pub status: String,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        let mut editor = Self {
+            lines: Vec::new(),
+            row: 0,
+            col: 0,
+            top: 0,
+            left: 0,
+            diagnostics: Vec::new(),
+            status: String::new(),
+        };
+        editor.open(None);
+        editor
+    }
+
+    /// Start editing `source`, or a fresh script if there is none.
+    pub fn open(&mut self, source: Option<&str>) {
+        self.lines = source
+            .unwrap_or(TEMPLATE)
+            .lines()
+            .map(|line| line.chars().collect())
+            .collect();
+        if self.lines.is_empty() {
+            self.lines.push(Vec::new());
+        }
+        self.row = 0;
+        self.col = 0;
+        self.top = 0;
+        self.left = 0;
+        self.status = "F2 saves, Esc leaves".to_string();
+        self.check();
+    }
+
+    pub fn source(&self) -> String {
+        let mut source = String::new();
+        for line in &self.lines {
+            source.extend(line);
+            source.push('\n');
+        }
+        source
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.diagnostics.is_empty()
+    }
+
+    /// Compile the script to find its errors. Nothing is kept but the
+    /// diagnostics.
+    fn check(&mut self) {
+        self.diagnostics = match Program::new("editor", &self.source()) {
+            Ok(_) => Vec::new(),
+            Err(diagnostics) => diagnostics,
+        };
+    }
+
+    /// Handle an editing key. Printable characters arrive separately,
+    /// through `insert`.
+    pub fn key(&mut self, key: VirtualKeyCode) {
+        let edited = match key {
+            VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => {
+                self.split_line();
+                true
+            }
+            VirtualKeyCode::Back => self.backspace(),
+            VirtualKeyCode::Delete => self.delete(),
+            VirtualKeyCode::Tab => {
+                for _ in 0..INDENT - self.col % INDENT {
+                    self.insert(' ');
+                }
+                false
+            }
+            VirtualKeyCode::Left => {
+                if self.col > 0 {
+                    self.col -= 1;
+                } else if self.row > 0 {
+                    self.row -= 1;
+                    self.col = self.lines[self.row].len();
+                }
+                false
+            }
+            VirtualKeyCode::Right => {
+                if self.col < self.lines[self.row].len() {
+                    self.col += 1;
+                } else if self.row + 1 < self.lines.len() {
+                    self.row += 1;
+                    self.col = 0;
+                }
+                false
+            }
+            VirtualKeyCode::Up => {
+                self.row = self.row.saturating_sub(1);
+                false
+            }
+            VirtualKeyCode::Down => {
+                self.row = (self.row + 1).min(self.lines.len() - 1);
+                false
+            }
+            VirtualKeyCode::PageUp => {
+                self.row = self.row.saturating_sub(10);
+                false
+            }
+            VirtualKeyCode::PageDown => {
+                self.row = (self.row + 10).min(self.lines.len() - 1);
+                false
+            }
+            VirtualKeyCode::Home => {
+                self.col = 0;
+                false
+            }
+            VirtualKeyCode::End => {
+                self.col = self.lines[self.row].len();
+                false
+            }
+            _ => false,
+        };
+
+        self.col = self.col.min(self.lines[self.row].len());
+        if edited {
+            self.check();
+        }
+    }
+
+    pub fn insert(&mut self, c: char) {
+        if c.is_control() {
+            return;
+        }
+        self.lines[self.row].insert(self.col, c);
+        self.col += 1;
+        self.check();
+    }
+
+    /// Break the line at the cursor, keeping the current indentation.
+    fn split_line(&mut self) {
+        let rest = self.lines[self.row].split_off(self.col);
+        let indent = self.lines[self.row]
+            .iter()
+            .take_while(|c| **c == ' ')
+            .count();
+        let mut new_line = vec![' '; indent];
+        new_line.extend(rest);
+        self.row += 1;
+        self.col = indent;
+        self.lines.insert(self.row, new_line);
+    }
+
+    fn backspace(&mut self) -> bool {
+        if self.col > 0 {
+            self.col -= 1;
+            self.lines[self.row].remove(self.col);
+        } else if self.row > 0 {
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.col = self.lines[self.row].len();
+            self.lines[self.row].extend(line);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn delete(&mut self) -> bool {
+        if self.col < self.lines[self.row].len() {
+            self.lines[self.row].remove(self.col);
+        } else if self.row + 1 < self.lines.len() {
+            let line = self.lines.remove(self.row + 1);
+            self.lines[self.row].extend(line);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// The lines of the script with each error tucked in under its line,
+    /// wrapped to `width`.
+    fn rows(&self, width: usize) -> Vec<Row<'_>> {
+        let mut rows = Vec::new();
+        for idx in 0..self.lines.len() {
+            rows.push(Row::Text(idx));
+            let last = idx + 1 == self.lines.len();
+            for d in &self.diagnostics {
+                // errors at the very end of the source belong to the last line
+                if d.span.line == idx + 1 || (last && d.span.line > idx + 1) {
+                    rows.extend(wrap(&d.message, width).map(Row::Error));
+                }
+            }
+        }
+        rows
+    }
+
+    /// Colour every character of the script by the token it belongs to.
+    /// Anything the scanner skips that isn't whitespace is a comment.
+    fn colours(&self) -> Vec<Vec<RGB>> {
+        let mut colours: Vec<Vec<RGB>> = self
+            .lines
+            .iter()
+            .map(|line| vec![RGB::named(GREY50); line.len()])
+            .collect();
+
+        for token in Scanner::new(&self.source()).flatten() {
+            let colour = RGB::named(token_colour(token.ttype));
+            if let Some(line) = colours.get_mut(token.span.line - 1) {
+                let start = token.span.col - 1;
+                let end = (start + token.span.len).min(line.len());
+                for c in &mut line[start.min(end)..end] {
+                    *c = colour;
+                }
+            }
+        }
+        colours
+    }
+
+    /// Draw the editor over the whole screen, with `title` on the top row
+    /// and the status line at the bottom. The first column marks lines
+    /// with errors.
+    pub fn draw(&mut self, ctx: &mut BTerm, title: &str, width: i32, height: i32) {
+        ctx.print(0, 0, title);
+        ctx.print_color(
+            0,
+            height - 1,
+            RGB::named(YELLOW),
+            RGB::named(BLACK),
+            &self.status,
+        );
+
+        let text_rows = (height - 2) as usize;
+        let text_cols = (width - 1) as usize;
+
+        // scroll so the cursor stays on screen
+        let cursor_row = self
+            .rows(text_cols)
+            .iter()
+            .position(|r| matches!(r, Row::Text(idx) if *idx == self.row))
+            .unwrap_or(0);
+        if cursor_row < self.top {
+            self.top = cursor_row;
+        } else if cursor_row >= self.top + text_rows {
+            self.top = cursor_row + 1 - text_rows;
+        }
+        if self.col < self.left {
+            self.left = self.col;
+        } else if self.col >= self.left + text_cols {
+            self.left = self.col + 1 - text_cols;
+        }
+
+        let colours = self.colours();
+        let rows = self.rows(text_cols);
+        for (y, row) in rows.iter().skip(self.top).take(text_rows).enumerate() {
+            let y = y as i32 + 1;
+            match row {
+                Row::Text(idx) => {
+                    let has_error = self.diagnostics.iter().any(|d| d.span.line == idx + 1);
+                    if has_error {
+                        ctx.set(0, y, RGB::named(WHITE), RGB::named(RED), to_cp437('!'));
+                    }
+                    let line = &self.lines[*idx];
+                    for (x, c) in line.iter().enumerate().skip(self.left).take(text_cols) {
+                        let fg = colours[*idx][x];
+                        let (fg, bg) = if *idx == self.row && x == self.col {
+                            (RGB::named(BLACK), RGB::named(WHITE))
+                        } else {
+                            (fg, RGB::named(BLACK))
+                        };
+                        ctx.set((x - self.left) as i32 + 1, y, fg, bg, to_cp437(*c));
+                    }
+                    if *idx == self.row && self.col == line.len() {
+                        ctx.set(
+                            (self.col - self.left) as i32 + 1,
+                            y,
+                            RGB::named(BLACK),
+                            RGB::named(WHITE),
+                            to_cp437(' '),
+                        );
+                    }
+                }
+                Row::Error(message) => {
+                    ctx.print_color(1, y, RGB::named(RED), RGB::named(BLACK), message);
+                }
+            }
+        }
+    }
+}
+
+/// Split `s` into pieces no more than `width` characters long.
+fn wrap(s: &str, width: usize) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest
+            .char_indices()
+            .nth(width)
+            .map_or(rest.len(), |(i, _)| i);
+        let (piece, tail) = rest.split_at(end);
+        rest = tail;
+        Some(piece)
+    })
+}
+
+fn token_colour(ttype: TokenType) -> (u8, u8, u8) {
+    match ttype {
+        TokenType::And
+        | TokenType::Else
+        | TokenType::False
+        | TokenType::Fn
+        | TokenType::If
+        | TokenType::Import
+        | TokenType::Let
+        | TokenType::Nil
+        | TokenType::Or
+        | TokenType::Return
+        | TokenType::True
+        | TokenType::While
+        | TokenType::Yield => MAGENTA,
+        TokenType::Direction(_) => ORANGE,
+        TokenType::Number => CYAN,
+        TokenType::String => GREEN,
+        TokenType::Identifier => WHITE,
+        _ => LIGHT_GRAY,
+    }
+}
@@ -1,21 +1,76 @@
 use std::fmt::Display;
+use std::sync::Arc;
 
 use bevy_ecs::event::Events;
 use bevy_ecs::prelude::*;
 use bracket_lib::prelude::*;
 
+use crate::actions::Action;
 use crate::components::*;
+use crate::debugger::*;
+use crate::devices::*;
 use crate::drawable::*;
+use crate::editor::*;
 use crate::keyboard::*;
+use crate::map::Map;
 use crate::messages::*;
+use crate::profiler::*;
+use crate::repl::*;
+use crate::scan_view::*;
+use crate::scheduler::*;
+use crate::script::*;
+use crate::spawner::start_run;
+use crate::system::Viewshed;
 
-pub struct RunSystems {
-    pub run_systems: bool,
+/// The mob that the console and the script editor are working on.
+#[derive(Default)]
+pub struct Selection {
+    pub entity: Option<Entity>,
+}
+
+impl Selection {
+    /// Move on to the next mob in `mobs`, going back to no mob at all after
+    /// the last one.
+    pub fn cycle(&mut self, mobs: &[Entity]) {
+        let current = self.entity.and_then(|e| mobs.iter().position(|m| *m == e));
+        self.entity = match current {
+            Some(i) => mobs.get(i + 1).copied(),
+            None => mobs.first().copied(),
+        };
+    }
+}
+
+/// How the current run is going, for the game-over screen.
+pub struct RunSummary {
+    /// Turns the player has taken.
+    pub turns: u32,
+    pub kills: u32,
+    /// How far down the player has got. There is only the one level so far.
+    pub depth: i32,
+    /// What killed the player, once something has.
+    pub cause: Option<String>,
+}
+
+impl Default for RunSummary {
+    fn default() -> Self {
+        Self {
+            turns: 0,
+            kills: 0,
+            depth: 1,
+            cause: None,
+        }
+    }
 }
 
 pub enum RunState {
     WelcomeScreen,
     StartGame,
+    Console,
+    Editor,
+    Scanner,
+    Debugger,
+    Targeting,
+    GameOver,
 }
 
 pub type Viewport = Rect;
@@ -50,11 +105,537 @@ impl State {
         let col = self.screen_width / 2 - s.len() as i32 / 2;
         ctx.print(col, row, s);
     }
+
+    fn selected(&self) -> Option<Entity> {
+        self.ecs.get_resource::<Selection>().unwrap().entity
+    }
+
+    fn describe_selected(&self) -> String {
+        self.selected()
+            .and_then(|e| {
+                let name = self.ecs.get::<Name>(e)?;
+                let p = self.ecs.get::<Position>(e)?;
+                Some(format!("{} at ({}, {})", name.name, p.x, p.y))
+            })
+            .unwrap_or_else(|| "no mob".to_string())
+    }
+
+    fn select_next_mob(&mut self) {
+        let mut mobs: Vec<Entity> = self
+            .ecs
+            .query_filtered::<Entity, With<Mob>>()
+            .iter(&self.ecs)
+            .collect();
+        mobs.sort();
+        let mut selection = self.ecs.get_resource_mut::<Selection>().unwrap();
+        selection.cycle(&mobs);
+        self.show_memory();
+    }
+
+    /// List what the selected mob's script has remembered on the console.
+    fn show_memory(&mut self) {
+        let entity = match self.selected() {
+            Some(entity) => entity,
+            None => return,
+        };
+        let (name, memory) = match (
+            self.ecs.get::<Name>(entity),
+            self.ecs.get::<Blackboard>(entity),
+        ) {
+            (Some(name), Some(memory)) => (name, memory),
+            _ => return,
+        };
+
+        let heap = &self.ecs.get_resource::<ScriptEngine>().unwrap().heap;
+        let mut lines = vec![if memory.values.is_empty() {
+            format!("The {} remembers nothing.", name.name)
+        } else {
+            format!("The {} remembers:", name.name)
+        }];
+        for (key, value) in &memory.values {
+            lines.push(format!("  {key} = {}", heap.format(*value)));
+        }
+
+        let mut repl = self.ecs.get_resource_mut::<Repl>().unwrap();
+        for line in lines {
+            repl.print(line);
+        }
+    }
+
+    fn open_editor(&mut self) {
+        let source = self
+            .selected()
+            .and_then(|e| self.ecs.get::<Script>(e))
+            .map(|script| script.program.source.clone());
+        let mut editor = self.ecs.get_resource_mut::<Editor>().unwrap();
+        editor.open(source.as_deref());
+        self.display = RunState::Editor;
+    }
+
+    /// Compile what's in the editor and give it to the selected mob. A
+    /// script with errors is never attached.
+    fn save_editor(&mut self) {
+        let status = match self.selected() {
+            _ if self.ecs.get_resource::<Editor>().unwrap().has_errors() => {
+                "Fix the errors first.".to_string()
+            }
+            None => "Select a mob in the console.".to_string(),
+            Some(entity) => {
+                let source = self.ecs.get_resource::<Editor>().unwrap().source();
+                let old = self.ecs.get::<Script>(entity);
+                let name = match old {
+                    Some(script) => script.program.name.clone(),
+                    None => self.ecs.get::<Name>(entity).unwrap().name.to_lowercase(),
+                };
+                let fuel = old.map_or(DEFAULT_FUEL, |script| script.fuel);
+                match Program::new(&name, &source) {
+                    Ok(program) => {
+                        let script = Script::new(Arc::new(program)).with_fuel(fuel);
+                        self.ecs.entity_mut(entity).insert(script);
+                        format!("Saved {name}.")
+                    }
+                    Err(_) => "Fix the errors first.".to_string(),
+                }
+            }
+        };
+        self.ecs.get_resource_mut::<Editor>().unwrap().status = status;
+    }
+
+    /// Point the player's code scanner at the scripted mobs in sight.
+    fn use_scanner(&mut self) {
+        let player = *self.ecs.get_resource::<Entity>().unwrap();
+        let player_ref = self.ecs.entity(player);
+        if !player_ref.contains::<CodeScanner>() {
+            self.add_message("You have nothing to scan with.");
+            return;
+        }
+        let here = player_ref.get::<Position>().unwrap().point();
+        let in_sight = player_ref
+            .get::<Viewshed>()
+            .map_or(Vec::new(), |vs| vs.visible_tiles.clone());
+
+        let mut targets: Vec<(Entity, f32)> = self
+            .ecs
+            .query_filtered::<(Entity, &Position), With<Script>>()
+            .iter(&self.ecs)
+            .filter(|(_, p)| in_sight.contains(&p.point()))
+            .map(|(e, p)| (e, DistanceAlg::Pythagoras.distance2d(here, p.point())))
+            .collect();
+        if targets.is_empty() {
+            self.add_message("Your scanner picks up no programs.");
+            return;
+        }
+        targets.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let targets = targets.into_iter().map(|(e, _)| e).collect();
+        self.ecs
+            .get_resource_mut::<ScanView>()
+            .unwrap()
+            .open(targets);
+        self.show_scan();
+        self.display = RunState::Scanner;
+    }
+
+    /// Load the program of whichever mob the scanner is pointed at, as
+    /// bytecode or as source.
+    fn show_scan(&mut self) {
+        let scan = self.ecs.get_resource::<ScanView>().unwrap();
+        let source = scan.source;
+        let lines = scan
+            .target()
+            .and_then(|e| self.ecs.get::<Script>(e))
+            .map_or(Vec::new(), |script| {
+                if source {
+                    to_source(&script.program.statements)
+                        .lines()
+                        .map(str::to_string)
+                        .collect()
+                } else {
+                    script.program.disassemble()
+                }
+            });
+        self.ecs.get_resource_mut::<ScanView>().unwrap().show(lines);
+    }
+
+    fn describe_scanned(&self) -> String {
+        self.ecs
+            .get_resource::<ScanView>()
+            .unwrap()
+            .target()
+            .and_then(|e| self.ecs.get::<Name>(e))
+            .map_or_else(|| "nothing".to_string(), |name| name.name.clone())
+    }
+
+    /// Attach the debugger to the selected mob and show it.
+    fn open_debugger(&mut self) {
+        let mob = match self.selected() {
+            Some(mob) if self.ecs.get::<Script>(mob).is_some() => mob,
+            _ => {
+                self.add_message("Select a scripted mob in the console first.");
+                return;
+            }
+        };
+        self.ecs
+            .resource_scope(|world, mut debugger: Mut<Debugger>| debugger.attach(world, mob));
+        self.display = RunState::Debugger;
+    }
+
+    /// The debugged mob's script, if the debugger is attached to one that is
+    /// still around.
+    fn debugged_script(&mut self) -> Option<Mut<'_, Script>> {
+        let mob = self.ecs.get_resource::<Debugger>().unwrap().mob()?;
+        self.ecs
+            .get_mut::<Script>(mob)
+            .filter(|script| script.debug.is_some())
+    }
+
+    /// Whether the debugged script has just stopped at a breakpoint. If it
+    /// has, the debugger is moved to the line it stopped on.
+    fn take_stop(&mut self) -> bool {
+        let line = match self.debugged_script() {
+            Some(mut script) => {
+                let debug = script.debug.as_mut().unwrap();
+                if !std::mem::take(&mut debug.stopped) {
+                    return false;
+                }
+                debug.paused.as_ref().map_or(1, |pause| pause.line)
+            }
+            None => return false,
+        };
+        self.ecs
+            .get_resource_mut::<Debugger>()
+            .unwrap()
+            .show_line(line);
+        true
+    }
+
+    /// Let the debugged script run until it next stops, either on every
+    /// line (`stepping`) or only at breakpoints. A script stopped part way
+    /// through its turn carries on straight away; otherwise it waits for
+    /// the game to go on.
+    fn carry_on(&mut self, stepping: bool) {
+        let mob = match self.ecs.get_resource::<Debugger>().unwrap().mob() {
+            Some(mob) => mob,
+            None => return,
+        };
+        let paused = match self.debugged_script() {
+            Some(mut script) => {
+                let debug = script.debug.as_mut().unwrap();
+                debug.breakpoints.stepping = stepping;
+                debug.paused.is_some()
+            }
+            None => return,
+        };
+
+        if paused {
+            carry_on(&mut self.ecs, mob);
+            if self.take_stop() || stepping {
+                return;
+            }
+        }
+        self.display = RunState::StartGame;
+    }
+
+    /// Once the player has died, the run is over, as soon as they are back
+    /// to looking at the map.
+    fn check_for_death(&mut self) {
+        let dead = self
+            .ecs
+            .get_resource::<RunSummary>()
+            .unwrap()
+            .cause
+            .is_some();
+        if dead && matches!(self.display, RunState::StartGame | RunState::Targeting) {
+            self.display = RunState::GameOver;
+        }
+    }
+
+    /// Run the world until the player has a turn to take.
+    fn run_world(&mut self) {
+        run_until_player_ready(&mut self.ecs, &mut self.schedule, stop_pending);
+    }
+
+    fn debugger_input(&mut self, events: Vec<BEvent>) {
+        for event in events {
+            let key = match event {
+                BEvent::KeyboardInput {
+                    key, pressed: true, ..
+                } => key,
+                _ => continue,
+            };
+            match key {
+                VirtualKeyCode::Escape => self.display = RunState::StartGame,
+                VirtualKeyCode::F9 => self.ecs.resource_scope(|world, debugger: Mut<Debugger>| {
+                    let mob = debugger.mob();
+                    if let Some(mut script) = mob.and_then(|mob| world.get_mut::<Script>(mob)) {
+                        if let Some(debug) = script.debug.as_mut() {
+                            debugger.toggle_breakpoint(debug);
+                        }
+                    }
+                }),
+                VirtualKeyCode::F10 => self.carry_on(true),
+                VirtualKeyCode::F5 => self.carry_on(false),
+                VirtualKeyCode::D => {
+                    self.ecs
+                        .resource_scope(|world, mut debugger: Mut<Debugger>| {
+                            if let Some(mob) = debugger.mob() {
+                                debugger.detach(world, mob);
+                            }
+                        });
+                    self.display = RunState::StartGame;
+                }
+                key => {
+                    let line_count = self
+                        .debugged_script()
+                        .map_or(0, |script| script.program.source.lines().count());
+                    self.ecs
+                        .get_resource_mut::<Debugger>()
+                        .unwrap()
+                        .key(key, line_count);
+                }
+            }
+        }
+    }
+
+    /// Start aiming one of the player's devices at the mobs in sight.
+    fn open_targeting(&mut self) {
+        let player = *self.ecs.get_resource::<Entity>().unwrap();
+        let mut devices: Vec<Entity> = self
+            .ecs
+            .query_filtered::<(Entity, &CarriedBy), With<Device>>()
+            .iter(&self.ecs)
+            .filter(|(_, carried)| carried.owner == player)
+            .map(|(e, _)| e)
+            .collect();
+        if devices.is_empty() {
+            self.add_message("You have nothing to use.");
+            return;
+        }
+        devices.sort();
+
+        let player_ref = self.ecs.entity(player);
+        let here = *player_ref.get::<Position>().unwrap();
+        let in_sight = player_ref
+            .get::<Viewshed>()
+            .map_or(Vec::new(), |vs| vs.visible_tiles.clone());
+        let mut targets: Vec<(Position, f32)> = self
+            .ecs
+            .query_filtered::<&Position, With<Mob>>()
+            .iter(&self.ecs)
+            .filter(|p| in_sight.contains(&p.point()))
+            .map(|p| {
+                (
+                    *p,
+                    DistanceAlg::Pythagoras.distance2d(here.point(), p.point()),
+                )
+            })
+            .collect();
+        targets.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let targets = targets.into_iter().map(|(p, _)| p).collect();
+        self.ecs
+            .get_resource_mut::<Targeting>()
+            .unwrap()
+            .open(devices, targets, here);
+        self.display = RunState::Targeting;
+    }
+
+    /// Use the chosen device on the tile under the cursor. Using one takes
+    /// the player's turn, so the rest of the world gets to move.
+    fn fire_device(&mut self) {
+        self.display = RunState::StartGame;
+        let targeting = self.ecs.get_resource::<Targeting>().unwrap();
+        let (device, tile) = match targeting.device() {
+            Some(device) => (device, targeting.cursor),
+            None => return,
+        };
+        let player = *self.ecs.get_resource::<Entity>().unwrap();
+        let mut player_ref = self.ecs.entity_mut(player);
+        match player_ref.get_mut::<Speed>() {
+            Some(mut speed) if speed.ready() => speed.spend(),
+            _ => return,
+        }
+        player_ref.insert(Action::Use { device, tile });
+    }
+
+    fn targeting_input(&mut self, events: Vec<BEvent>) {
+        for event in events {
+            let key = match event {
+                BEvent::KeyboardInput {
+                    key, pressed: true, ..
+                } => key,
+                _ => continue,
+            };
+            match key {
+                VirtualKeyCode::Escape => self.display = RunState::StartGame,
+                VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => {
+                    self.fire_device();
+                    return;
+                }
+                VirtualKeyCode::Tab => self
+                    .ecs
+                    .get_resource_mut::<Targeting>()
+                    .unwrap()
+                    .next_target(),
+                VirtualKeyCode::Z => self
+                    .ecs
+                    .get_resource_mut::<Targeting>()
+                    .unwrap()
+                    .next_device(),
+                key => self
+                    .ecs
+                    .resource_scope(|world, mut targeting: Mut<Targeting>| {
+                        targeting.key(key, world.get_resource::<Map>().unwrap())
+                    }),
+            }
+        }
+    }
+
+    /// Draw the map, the player and the status line, and hand back where
+    /// the top left of the map is on screen.
+    fn draw_game(&mut self, ctx: &mut BTerm) -> Position {
+        let mut messages = self.ecs.get_resource_mut::<Messages>().unwrap();
+        if let Some(msg) = messages.current() {
+            ctx.print(0, 0, msg);
+        }
+
+        let vp = self.ecs.get_resource::<Viewport>().unwrap();
+        let player = self.ecs.get_resource::<Entity>().unwrap();
+        let player_ref = self.ecs.entity(*player);
+        let p = player_ref.get::<Position>().unwrap();
+        let offset = Position {
+            x: 0.max(p.x - self.screen_width / 2),
+            y: 0.max(p.y - self.screen_height / 2),
+        };
+
+        let stats = player_ref.get::<Stats>().unwrap();
+        let mut hud = format!("HP:{} MP:{}", stats.hp.cur, stats.mp.cur);
+        if let Some(status) = player_ref.get::<StatusEffects>() {
+            for effect in &status.effects {
+                hud.push_str(&format!(" {}:{}", effect.effect.name(), effect.turns));
+            }
+        }
+        ctx.print(0, self.screen_height - 1, hud);
+
+        let drawables = self.ecs.get_resource::<DrawList>().unwrap();
+        let mut draw = drawables.items.to_vec();
+        draw.sort_by_key(|d| d.priority);
+
+        for d in &draw {
+            let point = Point {
+                x: d.pos.x - offset.x + vp.x1,
+                y: d.pos.y - offset.y + vp.y1,
+            };
+            if vp.point_in_rect(point) {
+                ctx.print(point.x, point.y, d.glyph);
+            }
+        }
+
+        ctx.print(p.x - offset.x + vp.x1, p.y - offset.y + vp.y1, '@');
+        offset
+    }
+
+    fn add_message(&mut self, message: &str) {
+        self.ecs
+            .get_resource_mut::<Messages>()
+            .unwrap()
+            .add(message);
+    }
+
+    fn scanner_input(&mut self, events: Vec<BEvent>) {
+        for event in events {
+            match event {
+                BEvent::KeyboardInput {
+                    key: VirtualKeyCode::Escape,
+                    pressed: true,
+                    ..
+                } => self.display = RunState::StartGame,
+                BEvent::KeyboardInput {
+                    key: VirtualKeyCode::Tab,
+                    pressed: true,
+                    ..
+                } => {
+                    self.ecs
+                        .get_resource_mut::<ScanView>()
+                        .unwrap()
+                        .next_target();
+                    self.show_scan();
+                }
+                BEvent::KeyboardInput {
+                    key: VirtualKeyCode::S,
+                    pressed: true,
+                    ..
+                } => {
+                    let mut scan = self.ecs.get_resource_mut::<ScanView>().unwrap();
+                    scan.source = !scan.source;
+                    self.show_scan();
+                }
+                BEvent::KeyboardInput {
+                    key, pressed: true, ..
+                } => self.ecs.get_resource_mut::<ScanView>().unwrap().key(key),
+                _ => {}
+            }
+        }
+    }
+
+    fn editor_input(&mut self, events: Vec<BEvent>) {
+        for event in events {
+            match event {
+                BEvent::KeyboardInput {
+                    key: VirtualKeyCode::Escape,
+                    pressed: true,
+                    ..
+                } => self.display = RunState::StartGame,
+                BEvent::KeyboardInput {
+                    key: VirtualKeyCode::F2,
+                    pressed: true,
+                    ..
+                } => self.save_editor(),
+                BEvent::KeyboardInput {
+                    key, pressed: true, ..
+                } => self.ecs.get_resource_mut::<Editor>().unwrap().key(key),
+                BEvent::Character { c } => self.ecs.get_resource_mut::<Editor>().unwrap().insert(c),
+                _ => {}
+            }
+        }
+    }
+
+    fn console_input(&mut self, events: Vec<BEvent>) {
+        for event in events {
+            match event {
+                BEvent::KeyboardInput {
+                    key: VirtualKeyCode::Escape,
+                    pressed: true,
+                    ..
+                } => self.display = RunState::StartGame,
+                BEvent::KeyboardInput {
+                    key: VirtualKeyCode::Tab,
+                    pressed: true,
+                    ..
+                } => self.select_next_mob(),
+                BEvent::KeyboardInput {
+                    key, pressed: true, ..
+                } => self.ecs.get_resource_mut::<Repl>().unwrap().key(key),
+                BEvent::Character { c } => self.ecs.get_resource_mut::<Repl>().unwrap().insert(c),
+                _ => {}
+            }
+        }
+    }
 }
 
 impl GameState for State {
     fn tick(&mut self, ctx: &mut BTerm) {
-        self.schedule.run(&mut self.ecs);
+        self.run_world();
+
+        // the input queue fills up whether or not anyone is listening, so
+        // always drain it
+        let events: Vec<BEvent> = std::iter::from_fn(|| INPUT.lock().pop()).collect();
+        if events.contains(&BEvent::CloseRequested) {
+            self.ecs.get_resource::<Profiler>().unwrap().dump();
+            ctx.quit();
+        }
+
+        self.check_for_death();
 
         ctx.cls();
         match self.display {
@@ -63,6 +644,11 @@ impl GameState for State {
                 self.center_at_row(ctx, 3, "A Programmable Roguelike");
 
                 self.center_at_row(ctx, 5, "Press ENTER to Start");
+                self.center_at_row(ctx, 7, "In game, ` opens the script console");
+                self.center_at_row(ctx, 8, "E edits and D debugs the selected mob");
+                self.center_at_row(ctx, 9, "S scans the programs of mobs in sight");
+                self.center_at_row(ctx, 10, "P shows what mob scripts cost");
+                self.center_at_row(ctx, 11, "Z zaps with a device");
                 if let Some(VirtualKeyCode::Return) = ctx.key {
                     self.display = RunState::StartGame;
                     self.ecs
@@ -73,7 +659,22 @@ impl GameState for State {
             }
 
             RunState::StartGame => {
-                if let Some(key) = ctx.key {
+                if self.take_stop() {
+                    self.display = RunState::Debugger;
+                } else if let Some(VirtualKeyCode::Grave) = ctx.key {
+                    self.display = RunState::Console;
+                } else if let Some(VirtualKeyCode::E) = ctx.key {
+                    self.open_editor();
+                } else if let Some(VirtualKeyCode::S) = ctx.key {
+                    self.use_scanner();
+                } else if let Some(VirtualKeyCode::D) = ctx.key {
+                    self.open_debugger();
+                } else if let Some(VirtualKeyCode::P) = ctx.key {
+                    let mut profiler = self.ecs.get_resource_mut::<Profiler>().unwrap();
+                    profiler.shown = !profiler.shown;
+                } else if let Some(VirtualKeyCode::Z) = ctx.key {
+                    self.open_targeting();
+                } else if let Some(key) = ctx.key {
                     let mut events = self
                         .ecs
                         .get_resource_mut::<Events<KeyboardEvent>>()
@@ -81,43 +682,142 @@ impl GameState for State {
                     events.send(KeyboardEvent(key));
                 }
 
-                let mut messages = self.ecs.get_resource_mut::<Messages>().unwrap();
-                if let Some(msg) = messages.current() {
-                    ctx.print(0, 0, msg);
+                self.draw_game(ctx);
+
+                let profiler = self.ecs.get_resource::<Profiler>().unwrap();
+                if profiler.shown {
+                    profiler.draw(ctx, self.screen_width, self.screen_height);
                 }
+            }
 
-                let vp = self.ecs.get_resource::<Viewport>().unwrap();
-                let player = self.ecs.get_resource::<Entity>().unwrap();
-                let player_ref = self.ecs.entity(*player);
-                let p = player_ref.get::<Position>().unwrap();
-                let offset = Position {
-                    x: 0.max(p.x - self.screen_width / 2),
-                    y: 0.max(p.y - self.screen_height / 2),
-                };
+            RunState::Console => {
+                self.console_input(events);
 
-                let stats = player_ref.get::<Stats>().unwrap();
-                ctx.print(
-                    0,
-                    self.screen_height - 1,
-                    format!("HP:{} MP:{}", stats.hp.cur, stats.mp.cur),
-                );
-
-                let drawables = self.ecs.get_resource::<DrawList>().unwrap();
-                let mut draw = drawables.items.to_vec();
-                draw.sort_by(|a, b| a.priority.cmp(&b.priority));
-
-                for d in &draw {
-                    let point = Point {
-                        x: d.pos.x - offset.x + vp.x1,
-                        y: d.pos.y - offset.y + vp.y1,
-                    };
-                    if vp.point_in_rect(point) {
-                        ctx.print(point.x, point.y, d.glyph);
-                    }
+                let title = format!("Console  self: {}", self.describe_selected());
+                let repl = self.ecs.get_resource::<Repl>().unwrap();
+                repl.draw(ctx, &title, self.screen_width, self.screen_height);
+            }
+
+            RunState::Editor => {
+                self.editor_input(events);
+
+                let title = format!("Script: {}", self.describe_selected());
+                let (width, height) = (self.screen_width, self.screen_height);
+                let mut editor = self.ecs.get_resource_mut::<Editor>().unwrap();
+                editor.draw(ctx, &title, width, height);
+            }
+
+            RunState::Scanner => {
+                self.scanner_input(events);
+
+                let title = format!("Scanning: {}", self.describe_scanned());
+                let scan = self.ecs.get_resource::<ScanView>().unwrap();
+                scan.draw(ctx, &title, self.screen_width, self.screen_height);
+            }
+
+            RunState::Debugger => {
+                self.debugger_input(events);
+
+                let debugger = self.ecs.get_resource::<Debugger>().unwrap();
+                debugger.draw(ctx, &self.ecs, self.screen_width, self.screen_height);
+            }
+
+            RunState::GameOver => {
+                let summary = self.ecs.get_resource::<RunSummary>().unwrap();
+                let lines = [
+                    (2, "You have died.".to_string()),
+                    (4, summary.cause.clone().unwrap_or_default()),
+                    (6, format!("You lasted {} turns", summary.turns)),
+                    (7, format!("and killed {} mobs", summary.kills)),
+                    (8, format!("on depth {}.", summary.depth)),
+                    (10, "Press ENTER to try again".to_string()),
+                ];
+                for (row, line) in lines {
+                    self.center_at_row(ctx, row, line);
+                }
+                if let Some(VirtualKeyCode::Return) = ctx.key {
+                    start_run(&mut self.ecs);
+                    self.add_message("Welcome back!");
+                    self.display = RunState::StartGame;
                 }
+            }
+
+            RunState::Targeting => {
+                self.targeting_input(events);
 
-                ctx.print(p.x - offset.x + vp.x1, p.y - offset.y + vp.y1, '@');
+                let offset = self.draw_game(ctx);
+                let vp = self.ecs.get_resource::<Viewport>().unwrap();
+                let targeting = self.ecs.get_resource::<Targeting>().unwrap();
+                let at = Point {
+                    x: targeting.cursor.x - offset.x + vp.x1,
+                    y: targeting.cursor.y - offset.y + vp.y1,
+                };
+                targeting.draw(ctx, &self.ecs, at, self.screen_height);
             }
         }
     }
 }
+
+/// Whether the debugged script has stopped and is waiting to be shown,
+/// which holds up the rest of the world until it has been.
+fn stop_pending(world: &World) -> bool {
+    world
+        .get_resource::<Debugger>()
+        .unwrap()
+        .mob()
+        .and_then(|mob| world.get::<Script>(mob))
+        .and_then(|script| script.debug.as_ref())
+        .is_some_and(|debug| debug.stopped)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_ecs::event::Events;
+
+    use super::*;
+    use crate::combat::{deal_damage, Blow, DealDamage};
+
+    #[test]
+    fn dying_ends_the_run_but_keeps_the_player() {
+        let mut world = World::new();
+        world.insert_resource(Map::new(5, 5, 0, 0));
+        world.insert_resource(Messages::default());
+        world.insert_resource(RunSummary::default());
+        world.init_resource::<Events<DealDamage>>();
+        let player = world
+            .spawn()
+            .insert(Name {
+                name: "you".to_string(),
+            })
+            .insert(Stats::new(3, 0))
+            .insert(Position { x: 1, y: 1 })
+            .id();
+        world.insert_resource(player);
+        let rat = world.spawn().id();
+
+        let mut state = State::new(world, RunState::StartGame, Schedule::default(), 80, 50);
+        state.check_for_death();
+        assert!(matches!(state.display, RunState::StartGame));
+
+        state
+            .ecs
+            .get_resource_mut::<Events<DealDamage>>()
+            .unwrap()
+            .send(DealDamage {
+                source: rat,
+                target: player,
+                name: "Rat".to_string(),
+                amount: 5,
+                blow: Blow::Hit,
+            });
+        SystemStage::single_threaded()
+            .with_system(deal_damage)
+            .run(&mut state.ecs);
+        state.check_for_death();
+
+        assert!(matches!(state.display, RunState::GameOver));
+        assert!(state.ecs.get_entity(player).is_some());
+        let summary = state.ecs.get_resource::<RunSummary>().unwrap();
+        assert_eq!(summary.cause.as_deref(), Some("Killed by a Rat."));
+    }
+}
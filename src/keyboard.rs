@@ -1,24 +1,20 @@
 use bevy_ecs::prelude::*;
 use bracket_lib::prelude::*;
 
+use crate::actions::Action;
 use crate::components::*;
-use crate::game_state::*;
-use crate::map::*;
+use crate::map::Direction;
 use crate::messages::*;
 
 pub struct KeyboardEvent(pub VirtualKeyCode);
-pub struct MeleeEvent {
-    pub source: Entity,
-    pub target: Entity,
-}
 
+/// Turn the player's keys into an `Action`. Only a player with a turn to
+/// take can act; Space moves the messages on either way.
 pub fn handle_key(
+    mut commands: Commands,
     mut reader: EventReader<KeyboardEvent>,
-    mut writer: EventWriter<MeleeEvent>,
     mut messages: ResMut<Messages>,
-    map: Res<Map>,
-    mut runner: ResMut<RunSystems>,
-    mut query: Query<(Entity, (&mut Position, With<Player>))>,
+    mut query: Query<(Entity, &mut Speed), With<Player>>,
 ) {
     let mut action_performed = false;
     let mut advance_message = false;
@@ -28,67 +24,34 @@ pub fn handle_key(
             continue;
         }
 
-        for (source, (mut position, _)) in query.iter_mut() {
-            let mut new_position = *position;
-            match event.0 {
-                VirtualKeyCode::Numpad4 | VirtualKeyCode::H => {
-                    new_position = map.new_position(Direction::West, &position);
-                    action_performed = true;
-                }
-                VirtualKeyCode::Numpad6 | VirtualKeyCode::L => {
-                    new_position = map.new_position(Direction::East, &position);
-                    action_performed = true;
-                }
-                VirtualKeyCode::Numpad2 | VirtualKeyCode::J => {
-                    new_position = map.new_position(Direction::South, &position);
-                    action_performed = true;
-                }
-                VirtualKeyCode::Numpad8 | VirtualKeyCode::K => {
-                    new_position = map.new_position(Direction::North, &position);
-                    action_performed = true;
-                }
-                VirtualKeyCode::Numpad7 | VirtualKeyCode::Y => {
-                    new_position = map.new_position(Direction::NorthWest, &position);
-                    action_performed = true;
-                }
-                VirtualKeyCode::Numpad9 | VirtualKeyCode::U => {
-                    new_position = map.new_position(Direction::NorthEast, &position);
-                    action_performed = true;
-                }
-                VirtualKeyCode::Numpad1 | VirtualKeyCode::B => {
-                    new_position = map.new_position(Direction::SouthWest, &position);
-                    action_performed = true;
-                }
-                VirtualKeyCode::Numpad3 | VirtualKeyCode::N => {
-                    new_position = map.new_position(Direction::SouthEast, &position);
-                    action_performed = true;
-                }
+        for (player, mut speed) in query.iter_mut() {
+            let action = match event.0 {
+                VirtualKeyCode::Numpad4 | VirtualKeyCode::H => Action::Move(Direction::West),
+                VirtualKeyCode::Numpad6 | VirtualKeyCode::L => Action::Move(Direction::East),
+                VirtualKeyCode::Numpad2 | VirtualKeyCode::J => Action::Move(Direction::South),
+                VirtualKeyCode::Numpad8 | VirtualKeyCode::K => Action::Move(Direction::North),
+                VirtualKeyCode::Numpad7 | VirtualKeyCode::Y => Action::Move(Direction::NorthWest),
+                VirtualKeyCode::Numpad9 | VirtualKeyCode::U => Action::Move(Direction::NorthEast),
+                VirtualKeyCode::Numpad1 | VirtualKeyCode::B => Action::Move(Direction::SouthWest),
+                VirtualKeyCode::Numpad3 | VirtualKeyCode::N => Action::Move(Direction::SouthEast),
+                VirtualKeyCode::Numpad5 => Action::Wait,
+                VirtualKeyCode::G => Action::PickUp,
                 VirtualKeyCode::Space => {
                     advance_message = true;
+                    continue;
                 }
-                VirtualKeyCode::Numpad5 => {
-                    action_performed = true;
-                }
-                _ => {}
-            }
+                _ => continue,
+            };
 
-            if *position != new_position && map.walkable(&new_position) {
-                if let Some(target) = map.try_walk(&new_position) {
-                    writer.send(MeleeEvent {
-                        source,
-                        target: *target,
-                    });
-                    messages.add("You attack!");
-                } else {
-                    *position = new_position;
-                }
+            if speed.ready() {
+                speed.spend();
+                commands.entity(player).insert(action);
+                action_performed = true;
             }
         }
     }
 
-    if action_performed {
-        runner.run_systems = true;
-    } else if advance_message {
+    if advance_message && !action_performed {
         messages.advance();
     }
 }
@@ -1,15 +1,44 @@
 use bevy_ecs::event::Events;
 use bevy_ecs::prelude::*;
-use bevy_ecs::schedule::ShouldRun;
 
 use bracket_lib::prelude::*;
 
 mod messages;
 use messages::*;
 
+mod repl;
+use repl::*;
+
+mod editor;
+use editor::*;
+
+mod scan_view;
+use scan_view::*;
+
+mod debugger;
+use debugger::*;
+
+mod optimiser_check;
+use optimiser_check::*;
+
+mod profiler;
+use profiler::*;
+
+mod devices;
+use devices::*;
+
+mod actions;
+use actions::*;
+
 mod combat;
 use combat::*;
 
+mod spawner;
+use spawner::*;
+
+mod scheduler;
+use scheduler::*;
+
 mod game_state;
 use game_state::*;
 
@@ -28,22 +57,69 @@ use map::*;
 mod system;
 use system::*;
 
+mod script;
+use script::*;
+
 embedded_resource!(WIDE_FONT, "../resources/terminal_10x16.png");
 embedded_resource!(VGA_FONT, "../resources/vga8x16.png");
 embedded_resource!(CHEEP_FONT, "../resources/cheepicus8x8.png");
+embedded_resource!(DOCILE_AI, "../scripts/ai/docile.prl");
+embedded_resource!(FLEE_AI, "../scripts/ai/flee.prl");
+embedded_resource!(INJECTOR_DEVICE, "../scripts/devices/injector.prl");
+embedded_resource!(PHASER_DEVICE, "../scripts/devices/phaser.prl");
+embedded_resource!(REMOTE_DEVICE, "../scripts/devices/remote.prl");
+embedded_resource!(HALLS_MAP, "../scripts/maps/halls.prl");
+embedded_resource!(SENTRY_SCRIPT, "../scripts/sentry.prl");
 
 const WIDTH: i32 = 40;
 const HEIGHT: i32 = 25;
 
-fn run_if_player_performed_an_action(rs: Res<RunSystems>) -> ShouldRun {
-    if rs.run_systems {
-        ShouldRun::Yes
-    } else {
-        ShouldRun::No
+/// Link in the scripts listed in `SHIPPED`, under the paths `import` looks
+/// for them by.
+fn link_scripts() {
+    link_resource!(DOCILE_AI, "../scripts/ai/docile.prl");
+    link_resource!(FLEE_AI, "../scripts/ai/flee.prl");
+    link_resource!(INJECTOR_DEVICE, "../scripts/devices/injector.prl");
+    link_resource!(PHASER_DEVICE, "../scripts/devices/phaser.prl");
+    link_resource!(REMOTE_DEVICE, "../scripts/devices/remote.prl");
+    link_resource!(HALLS_MAP, "../scripts/maps/halls.prl");
+    link_resource!(SENTRY_SCRIPT, "../scripts/sentry.prl");
+}
+
+/// `progrl --disassemble <script>` prints a script's bytecode instead of
+/// starting the game.
+fn dump_bytecode(path: &str) -> BError {
+    let source = std::fs::read_to_string(path)?;
+    match Program::new(path, &source) {
+        Ok(program) => {
+            for line in program.disassemble() {
+                println!("{line}");
+            }
+        }
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                eprintln!("{path}: {diagnostic}");
+            }
+        }
     }
+    Ok(())
 }
 
 fn main() -> BError {
+    // script modules are linked first, since the script tools need them too
+    link_scripts();
+
+    // `progrl --profile <file>` plays as usual, and writes what each mob's
+    // script cost to the file at the end
+    let mut profile_to = None;
+    let args: Vec<String> = std::env::args().collect();
+    match args.as_slice() {
+        [_, flag, path] if flag == "--disassemble" => return dump_bytecode(path),
+        [_, flag, paths @ ..] if flag == "--check-optimiser" => return check_optimiser(paths),
+        [_, flag, path] if flag == "--profile" => profile_to = Some(path.clone()),
+        _ => {}
+    }
+
     link_resource!(WIDE_FONT, "../resources/terminal_10x16.png");
     link_resource!(VGA_FONT, "../resources/vga8x16.png");
     link_resource!(CHEEP_FONT, "../resources/cheepicus8x8.png");
@@ -54,6 +130,7 @@ fn main() -> BError {
         .with_title("ProgRog")
         .with_font("cheepicus8x8.png", 8, 8)
         .with_fps_cap(10.0)
+        .with_advanced_input(true)
         .build()?;
 
     let schedule = Schedule::default()
@@ -65,47 +142,58 @@ fn main() -> BError {
                 .with_system(Events::<MeleeEvent>::update_system),
         )
         .with_stage("player", SystemStage::parallel().with_system(handle_key))
+        .with_stage("console", SystemStage::parallel().with_system(run_repl))
+        .with_stage("time", SystemStage::parallel().with_system(advance_time))
         .with_stage(
-            "viewshed",
-            SystemStage::parallel()
-                .with_run_criteria(run_if_player_performed_an_action)
-                .with_system(visibility_system)
-                .with_system(map_update_system),
+            "status",
+            SystemStage::parallel().with_system(tick_status_effects),
         )
         .with_stage(
             "reset",
             SystemStage::parallel()
-                .with_run_criteria(run_if_player_performed_an_action)
+                .with_run_criteria(run_if_world_is_running)
                 .with_system(clear_screen),
         )
         .with_stage(
             "AI",
             SystemStage::parallel()
-                .with_run_criteria(run_if_player_performed_an_action)
-                .with_system(move_mobs),
+                .with_run_criteria(run_if_world_is_running)
+                .with_system(spawn_hooks)
+                .with_system(sight_hooks.after(spawn_hooks))
+                .with_system(move_mobs.after(sight_hooks)),
         )
         .with_stage(
             "resolution",
             SystemStage::single_threaded()
-                .with_system(resolve_movement.exclusive_system().at_end()),
+                .with_run_criteria(run_if_world_is_running)
+                .with_system(resolve_actions.exclusive_system()),
+        )
+        .with_stage(
+            "viewshed",
+            SystemStage::parallel()
+                .with_run_criteria(run_if_world_is_running)
+                .with_system(visibility_system)
+                .with_system(map_update_system),
         )
         .with_stage(
             "combat",
             SystemStage::parallel()
-                .with_run_criteria(run_if_player_performed_an_action)
+                .with_run_criteria(run_if_world_is_running)
                 .with_system(resolve_combat)
-                .with_system(deal_damage.after(resolve_combat)),
+                .with_system(deal_damage.after(resolve_combat))
+                .with_system(damage_hooks.after(deal_damage))
+                .with_system(profile_scripts.after(damage_hooks)),
         )
         .with_stage(
             "update",
             SystemStage::single_threaded()
-                .with_run_criteria(run_if_player_performed_an_action)
+                .with_run_criteria(run_if_world_is_running)
                 .with_system(draw_map)
                 .with_system(draw_mobs.after(move_mobs)),
         )
         .with_stage(
             "cleanup",
-            SystemStage::parallel().with_system(clear_run_flag),
+            SystemStage::parallel().with_system(collect_garbage),
         );
 
     let mut gs = crate::game_state::State::new(
@@ -121,59 +209,37 @@ fn main() -> BError {
     gs.ecs.init_resource::<Events<DealDamage>>();
     gs.ecs.insert_resource(RandomNumberGenerator::new());
     gs.ecs.insert_resource(Viewport::with_size(1, 1, 37, 22));
-    gs.ecs.insert_resource(DrawList { items: Vec::new() });
-    gs.ecs.insert_resource(RunSystems { run_systems: true });
-    gs.ecs.insert_resource(Messages::default());
+    gs.ecs.insert_resource(ScriptEngine::default());
+    gs.ecs.insert_resource(Repl::new());
+    gs.ecs.insert_resource(Editor::new());
+    gs.ecs.insert_resource(ScanView::new());
+    gs.ecs.insert_resource(Debugger::new());
+    gs.ecs.insert_resource(Profiler::new(profile_to));
+    gs.ecs.insert_resource(Targeting::new());
 
     let mut factory = MapFactory::new();
-    factory.add_builder(&RectRoomMapGenerator);
-    factory.add_builder(&RoundRoomMapGenerator);
+    factory.add_builder(Box::new(RectRoomMapGenerator));
+    factory.add_builder(Box::new(RoundRoomMapGenerator));
+    for problem in factory.add_scripts(MAP_SCRIPT_DIR) {
+        console::log(problem);
+    }
 
-    let mut map = factory.create_map(&mut gs.ecs, WIDTH * 2, HEIGHT * 2);
-    let starting_position = map.center_of();
     gs.ecs.insert_resource(factory);
+    start_run(&mut gs.ecs);
 
-    // temporarly spawn some mobs
-    let mut count = 0;
-    let mut rng = RandomNumberGenerator::new();
-    while count < 10 {
+    main_loop(context, gs)
+}
+
+fn random_walkable(map: &Map, rng: &mut RandomNumberGenerator) -> Position {
+    loop {
         let pos = Position {
             x: rng.range(0, map.width()),
             y: rng.range(0, map.height()),
         };
         if map.walkable(&pos) {
-            let id = gs
-                .ecs
-                .spawn()
-                .insert(Mob { glyph: 'r' })
-                .insert(Stats::new(2, 2))
-                .insert(Viewshed::new(2))
-                .insert(pos)
-                .insert(Name {
-                    name: "Rat".to_string(),
-                })
-                .id();
-            map.add_entity(&pos, id);
-            count += 1;
+            return pos;
         }
     }
-
-    gs.ecs.insert_resource(map);
-
-    let player = gs
-        .ecs
-        .spawn()
-        .insert(Player {})
-        .insert(starting_position)
-        .insert(Viewshed::new(5))
-        .insert(Stats::new(10, 10))
-        .insert(Name {
-            name: "you".to_string(),
-        })
-        .id();
-    gs.ecs.insert_resource(player);
-
-    main_loop(context, gs)
 }
 
 fn draw_mobs(
@@ -223,107 +289,59 @@ fn draw_map(mut draw_list: ResMut<DrawList>, map: Res<Map>, query: Query<(&Playe
     }
 }
 
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 fn move_mobs(
     mut commands: Commands,
     mut rng: ResMut<RandomNumberGenerator>,
+    mut engine: ResMut<ScriptEngine>,
     map: Res<Map>,
     player_q: Query<(Entity, &Player, &Position)>,
-    mut melee: EventWriter<MeleeEvent>,
     mut messages: ResMut<Messages>,
-    mut query: Query<(Entity, &Position, &Mob, &Name)>,
+    mut query: Query<(
+        Entity,
+        &Position,
+        &Mob,
+        &Name,
+        Option<&Stats>,
+        Option<&Viewshed>,
+        &mut Speed,
+        Option<&mut Script>,
+        Option<&mut Blackboard>,
+    )>,
 ) {
     let (player_id, _, player_pos) = player_q.iter().next().unwrap();
 
-    for (id, position, _, name) in query.iter_mut() {
-        if let Some(new_pos) = match rng.range(0, 4) {
-            0 => {
-                if position.x > 0 {
-                    Some(Position {
-                        x: position.x - 1,
-                        y: position.y,
-                    })
-                } else {
-                    None
-                }
-            }
-            1 => {
-                if position.x < map.width - 1 {
-                    Some(Position {
-                        x: position.x + 1,
-                        y: position.y,
-                    })
-                } else {
-                    None
-                }
-            }
-            2 => {
-                if position.y > 0 {
-                    Some(Position {
-                        x: position.x,
-                        y: position.y - 1,
-                    })
-                } else {
-                    None
-                }
-            }
-            3 => {
-                if position.y < map.height - 1 {
-                    Some(Position {
-                        x: position.x,
-                        y: position.y + 1,
-                    })
-                } else {
-                    None
+    for (id, position, _, name, stats, viewshed, mut speed, script, mut memory) in query.iter_mut()
+    {
+        if !speed.ready() {
+            continue;
+        }
+        speed.spend();
+
+        let action = match script {
+            Some(mut script) => {
+                let mut game = GameView {
+                    map: Some(&map),
+                    rng: Some(&mut rng),
+                    player: Some((player_id, *player_pos)),
+                    actor: Some(Actor::new(position, name, stats, viewshed)),
+                    memory: memory.as_deref_mut(),
+                    level: None,
+                };
+                let result = engine.run_turn(&mut script, &mut game);
+                match script_outcome(result, &engine, &mut messages, name, &script) {
+                    Some(Intent::Move(dir)) => Action::Move(dir),
+                    _ => Action::Wait,
                 }
             }
-            _ => panic!("rng failure"),
-        } {
-            if &new_pos == player_pos {
-                melee.send(MeleeEvent {
-                    source: id,
-                    target: player_id,
-                });
-                messages.add(format!("{} attacks!", name.name))
-            } else {
-                commands
-                    .entity(id)
-                    .insert(WantsToMove { location: new_pos });
-            }
-        }
-    }
-}
-
-fn clear_run_flag(mut rs: ResMut<RunSystems>) {
-    rs.run_systems = false;
-}
-
-fn resolve_movement(world: &mut World) {
-    let mut pos_set = std::collections::HashSet::new();
-    let mut move_actions = Vec::new();
-
-    let mut query = world.query::<(Entity, &WantsToMove, &Position)>();
-    for (id, new_pos, old_pos) in query.iter(world) {
-        move_actions.push((*old_pos, new_pos.location, id));
-    }
-
-    let map = world.get_resource::<Map>().unwrap();
-    let mut do_move = std::collections::HashMap::<Entity, (Position, Position)>::new();
-    for (old_pos, new_pos, id) in move_actions {
-        if map.can_move_mob(world, &new_pos) && !pos_set.contains(&new_pos) {
-            pos_set.insert(new_pos);
-            do_move.insert(id, (old_pos, new_pos));
-        }
-    }
-
-    let mut query = world.query::<(Entity, &WantsToMove, &mut Position)>();
-    for (id, _, mut old_pos) in query.iter_mut(world) {
-        if let Some((_, new_pos)) = do_move.get(&id) {
-            *old_pos = *new_pos;
-        }
-    }
-
-    let mut map = world.get_resource_mut::<Map>().unwrap();
-    for (id, (old, new)) in do_move {
-        map.move_entity(&old, &new, id);
+            None => Action::Move(match rng.range(0, 4) {
+                0 => Direction::West,
+                1 => Direction::East,
+                2 => Direction::North,
+                3 => Direction::South,
+                _ => panic!("rng failure"),
+            }),
+        };
+        commands.entity(id).insert(action);
     }
 }
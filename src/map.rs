@@ -1,8 +1,21 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use bevy_ecs::prelude::*;
 
 use bracket_lib::prelude::*;
 
 use crate::components::*;
+use crate::script::*;
+
+/// Where `MapFactory::add_scripts` looks for map generator scripts besides
+/// the ones the game ships with: `scripts/maps/`, next to the game's
+/// `resources/`.
+pub const MAP_SCRIPT_DIR: &str = "../scripts/maps";
+
+/// How many instructions a map generator script may run. Laying out a
+/// whole map takes a good deal more thinking than a mob's turn.
+pub const MAP_FUEL: u32 = 200_000;
 
 pub struct Map {
     pub tiles: Vec<TileType>,
@@ -14,6 +27,7 @@ pub struct Map {
     start_y: i32,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Direction {
     North,
     South,
@@ -25,17 +39,32 @@ pub enum Direction {
     SouthEast,
 }
 
+impl Direction {
+    pub fn delta(&self) -> (i32, i32) {
+        match self {
+            Direction::North => (0, -1),
+            Direction::South => (0, 1),
+            Direction::East => (1, 0),
+            Direction::West => (-1, 0),
+            Direction::NorthWest => (-1, -1),
+            Direction::NorthEast => (1, -1),
+            Direction::SouthWest => (-1, 1),
+            Direction::SouthEast => (1, 1),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum TileType {
     Floor,
     Wall,
 }
 
-pub struct MapFactory<'a> {
-    builders: Vec<&'a dyn MapGenerator>,
+pub struct MapFactory {
+    builders: Vec<Box<dyn MapGenerator>>,
 }
 
-impl<'a> MapFactory<'a> {
+impl MapFactory {
     pub fn new() -> Self {
         Self {
             builders: Vec::new(),
@@ -50,9 +79,63 @@ impl<'a> MapFactory<'a> {
         self.builders[room_type].generate(ecs, width, height)
     }
 
-    pub fn add_builder(&mut self, builder: &'a dyn MapGenerator) {
+    pub fn add_builder(&mut self, builder: Box<dyn MapGenerator>) {
         self.builders.push(builder);
     }
+
+    /// Add a generator for every map script the game ships with, and for
+    /// any others in `dir`, so that new kinds of level can be added without
+    /// touching the game. Scripts that can't be read or don't compile are
+    /// left out, and what was wrong with them is returned for the caller to
+    /// log.
+    pub fn add_scripts(&mut self, dir: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut scripts: Vec<(String, String)> = SHIPPED
+            .iter()
+            .filter_map(|path| {
+                Some((
+                    path.strip_prefix("maps/")?.to_string(),
+                    module_source(path)?,
+                ))
+            })
+            .collect();
+
+        match std::fs::read_dir(dir) {
+            Ok(entries) => {
+                let mut paths: Vec<PathBuf> = entries
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "prl"))
+                    .collect();
+                paths.sort();
+
+                for path in paths {
+                    let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+                    if scripts.iter().any(|(shipped, _)| *shipped == name) {
+                        continue;
+                    }
+                    match std::fs::read_to_string(&path) {
+                        Ok(source) => scripts.push((name, source)),
+                        Err(e) => problems.push(format!("{}: {e}", path.display())),
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("Can't read map scripts from {dir}: {e}")),
+        }
+
+        for (name, source) in scripts {
+            match Program::new(&name, &source) {
+                Ok(program) => {
+                    self.add_builder(Box::new(ScriptMapGenerator::new(Arc::new(program))))
+                }
+                Err(diagnostics) => {
+                    for diagnostic in diagnostics {
+                        problems.push(format!("{name} map script: {diagnostic}"));
+                    }
+                }
+            }
+        }
+        problems
+    }
 }
 
 pub trait MapGenerator: Send + Sync {
@@ -63,6 +146,14 @@ pub struct RectRoomMapGenerator;
 
 pub struct RoundRoomMapGenerator;
 
+/// A map generator written as a script. The script defines `generate()`,
+/// which lays the map out through the `builder` namespace and can roll dice
+/// with `rng`. The map starts out solid wall. A script that fails, or that
+/// leaves nowhere to stand, gets a map of rectangular rooms instead.
+pub struct ScriptMapGenerator {
+    program: Arc<Program>,
+}
+
 //const ROOM_TYPES: [&dyn MapGenerator; 2] = [&RectRoomMapGenerator, &RectRoomMapGenerator];
 
 const MIN_WIDTH: i32 = 3;
@@ -177,6 +268,56 @@ impl MapGenerator for RectRoomMapGenerator {
     }
 }
 
+impl ScriptMapGenerator {
+    pub fn new(program: Arc<Program>) -> Self {
+        Self { program }
+    }
+
+    fn build(&self, ecs: &mut World, width: i32, height: i32) -> Result<Map, String> {
+        let mut map = Map::new(width, height, -1, -1);
+        let result = ecs.resource_scope(|ecs, mut engine: Mut<ScriptEngine>| {
+            let mut rng = ecs.get_resource_mut::<RandomNumberGenerator>().unwrap();
+            let mut game = GameView {
+                rng: Some(&mut rng),
+                level: Some(&mut map),
+                ..Default::default()
+            };
+            let mut script = Script::new(self.program.clone()).with_fuel(MAP_FUEL);
+            engine.call(&mut script, &mut game, "generate", &[])
+        });
+        match result {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err("it doesn't define generate()".to_string()),
+            Err(e) => return Err(e.to_string()),
+        }
+
+        // without a start of its own, the player starts on the first floor
+        // tile there is
+        let start = map.center_of();
+        if map.point_to_idx(&start.point()).is_none() || !map.walkable(&start) {
+            let idx = map
+                .tiles
+                .iter()
+                .position(|tile| *tile == TileType::Floor)
+                .ok_or_else(|| "it left no floor to stand on".to_string())?;
+            map.set_start((&map.idx_to_xy_point(idx)).into());
+        }
+        Ok(map)
+    }
+}
+
+impl MapGenerator for ScriptMapGenerator {
+    fn generate(&self, ecs: &mut World, width: i32, height: i32) -> Map {
+        match self.build(ecs, width, height) {
+            Ok(map) => map,
+            Err(e) => {
+                console::log(format!("{} map script: {e}", self.program.name));
+                RectRoomMapGenerator.generate(ecs, width, height)
+            }
+        }
+    }
+}
+
 impl Map {
     pub fn new(width: i32, height: i32, start_x: i32, start_y: i32) -> Self {
         Self {
@@ -227,6 +368,22 @@ impl Map {
         newpos
     }
 
+    /// Like `new_position`, but stepping off the edge of the map gives `None`
+    /// instead of staying put.
+    pub fn step(&self, dir: Direction, old_position: &Position) -> Option<Position> {
+        let (dx, dy) = dir.delta();
+        let newpos = Position {
+            x: old_position.x + dx,
+            y: old_position.y + dy,
+        };
+
+        if newpos.x < 0 || newpos.x >= self.width || newpos.y < 0 || newpos.y >= self.height {
+            None
+        } else {
+            Some(newpos)
+        }
+    }
+
     pub fn add_entity(&mut self, p: &Position, id: Entity) {
         let idx = self.pos_to_idx(p);
         self.entity[idx].push(id);
@@ -271,6 +428,12 @@ impl Map {
         true
     }
 
+    /// Move where the player starts out; see `center_of`.
+    pub fn set_start(&mut self, p: Position) {
+        self.start_x = p.x;
+        self.start_y = p.y;
+    }
+
     pub fn center_of(&self) -> Position {
         Position {
             x: self.start_x,
@@ -324,7 +487,7 @@ impl BaseMap for Map {
         if idx >= (self.width * self.height) as usize {
             panic!("bug in bracket-lib");
         } else {
-            self.tiles[idx as usize] != TileType::Floor
+            self.tiles[idx] != TileType::Floor
         }
     }
 }
@@ -355,3 +518,60 @@ fn non_overlapping_rooms(rng: &mut RandomNumberGenerator, width: i32, height: i3
     }
     rooms
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The shipped map scripts are built in, so they are there whatever
+    /// directory the game is run from.
+    #[test]
+    fn shipped_map_scripts_are_added() {
+        crate::link_scripts();
+        let mut factory = MapFactory::new();
+        factory.add_scripts("no/such/directory");
+        let shipped = SHIPPED
+            .iter()
+            .filter(|path| path.starts_with("maps/"))
+            .count();
+        assert!(shipped > 0);
+        assert_eq!(factory.builders.len(), shipped);
+
+        // and they lay out a map themselves, rather than falling back
+        let mut world = World::new();
+        world.insert_resource(RandomNumberGenerator::seeded(1));
+        world.insert_resource(ScriptEngine::default());
+        for path in SHIPPED.iter().filter(|path| path.starts_with("maps/")) {
+            let generator = ScriptMapGenerator::new(load_program(path).unwrap());
+            let map = generator.build(&mut world, 80, 50).unwrap();
+            assert!(map.walkable(&map.center_of()), "{path}");
+        }
+    }
+
+    /// A script dropped in the directory is added; a broken one is reported
+    /// and left out, and so is a copy of a shipped script.
+    #[test]
+    fn scripts_in_the_directory_are_added() {
+        crate::link_scripts();
+        let dir = std::env::temp_dir().join(format!("progrl-maps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let halls = module_source("maps/halls").unwrap();
+        std::fs::write(dir.join("extra.prl"), &halls).unwrap();
+        std::fs::write(dir.join("broken.prl"), "fn generate( {").unwrap();
+        std::fs::write(dir.join("halls.prl"), "this is not read").unwrap();
+
+        let mut factory = MapFactory::new();
+        let problems = factory.add_scripts(dir.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let shipped = SHIPPED
+            .iter()
+            .filter(|path| path.starts_with("maps/"))
+            .count();
+        assert_eq!(factory.builders.len(), shipped + 1);
+        assert!(!problems.is_empty());
+        for problem in &problems {
+            assert!(problem.starts_with("broken map script: "), "{problem}");
+        }
+    }
+}
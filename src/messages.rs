@@ -24,7 +24,7 @@ impl Messages {
 
     pub fn current(&mut self) -> Option<String> {
         let more = if self.msg.len() > 1 { " -More-" } else { "" };
-        if let Some(mut m) = self.msg.get_mut(0) {
+        if let Some(m) = self.msg.get_mut(0) {
             m.seen = true;
             let result = format!("{}{more}", m.s);
             Some(result)
@@ -0,0 +1,327 @@
+use std::sync::Arc;
+
+use bevy_ecs::prelude::*;
+use bracket_lib::prelude::*;
+
+use crate::components::*;
+use crate::map::*;
+use crate::script::*;
+use crate::system::Viewshed;
+
+/// How many turns each script gets to show a difference.
+const TURNS: i32 = 60;
+/// Every run starts from the same map, so runs can be compared line for line.
+const SEED: u64 = 1;
+
+/// The hooks the game calls on a mob, which get the calls a mob would.
+const MOB_HOOKS: [&str; 5] = ["on_spawn", "on_turn", "on_hit", "on_death", "on_see_player"];
+
+/// `progrl --check-optimiser [script...]` runs each script with and without
+/// the optimiser, on both backends, and checks that nothing but the
+/// bytecode changes. With no scripts given it checks every script the game
+/// ships with.
+pub fn check_optimiser(paths: &[String]) -> BError {
+    let mut scripts = Vec::new();
+    if paths.is_empty() {
+        for path in SHIPPED {
+            let source = module_source(path).ok_or(format!("Can't find {path}."))?;
+            scripts.push((format!("scripts/{path}.prl"), source));
+        }
+    }
+    for path in paths {
+        scripts.push((path.clone(), std::fs::read_to_string(path)?));
+    }
+
+    let mut failed = false;
+    for (path, source) in &scripts {
+        match compare(path, source) {
+            Ok((before, after)) => {
+                println!("{path}: same over {TURNS} turns, {before} bytes of code down to {after}")
+            }
+            Err(e) => {
+                println!("{path}: {e}");
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        Err("the optimiser changed what a script does".into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Run a script with and without the optimiser, on both backends, and
+/// check that the traces match line for line and are the same length.
+/// Gives back how many bytes of code it was before and after.
+pub fn compare(name: &str, source: &str) -> Result<(usize, usize), String> {
+    let (optimised, unoptimised) = match (
+        Program::new(name, source),
+        Program::unoptimised(name, source),
+    ) {
+        (Ok(a), Ok(b)) => (Arc::new(a), Arc::new(b)),
+        (Err(diagnostics), _) | (_, Err(diagnostics)) => {
+            let diagnostics: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+            return Err(format!("doesn't compile: {}", diagnostics.join(", ")));
+        }
+    };
+
+    for backend in [Backend::TreeWalker, Backend::Bytecode] {
+        let expected = trace(unoptimised.clone(), backend);
+        let got = trace(optimised.clone(), backend);
+        if let Some((a, b)) = expected.iter().zip(&got).find(|(a, b)| a != b) {
+            return Err(format!(
+                "differs on {backend:?}\n  unoptimised: {a}\n  optimised:   {b}"
+            ));
+        }
+        if expected.len() != got.len() {
+            return Err(format!(
+                "differs on {backend:?}: {} lines unoptimised, {} optimised",
+                expected.len(),
+                got.len()
+            ));
+        }
+    }
+
+    Ok((unoptimised.code_size(), optimised.code_size()))
+}
+
+/// Every function `program` defines at its top level, and how many
+/// arguments it takes.
+fn functions(program: &Program) -> Vec<(String, usize)> {
+    program
+        .statements
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Function(decl) => Some((decl.name.lexeme.clone(), decl.params.len())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Play `program` on a mob for a while, with the player wandering about,
+/// and note down everything it hands back. The game's hooks are called the
+/// way the game calls them: the mob hooks as things happen to the mob,
+/// `on_use` each turn on the player and on bare floor in turn, and
+/// `generate` once on an empty level. Any other function it defines, such
+/// as a module's, is called each turn with nil for every argument.
+fn trace(program: Arc<Program>, backend: Backend) -> Vec<String> {
+    trace_with(program, backend, false)
+}
+
+/// `trace`, with the heap collecting on every allocation if `stress` is set.
+fn trace_with(program: Arc<Program>, backend: Backend, stress: bool) -> Vec<String> {
+    let mut world = World::new();
+    world.insert_resource(RandomNumberGenerator::seeded(SEED));
+    let map = RectRoomMapGenerator.generate(&mut world, crate::WIDTH * 2, crate::HEIGHT * 2);
+    let mut rng = RandomNumberGenerator::seeded(SEED);
+    let player = world.spawn().id();
+
+    let functions = functions(&program);
+    let defines = |hook: &str| functions.iter().any(|(name, _)| name == hook);
+    let mut engine = ScriptEngine::new(backend);
+    engine.heap.stress = stress;
+    let mut script = Script::new(program.clone());
+    let mut memory = Blackboard::default();
+    let name = Name {
+        name: "Subject".to_string(),
+    };
+    let mut stats = Stats::new(10, 4);
+    let mut here = map.center_of();
+    let mut there = crate::random_walkable(&map, &mut rng);
+    let mut saw_player = false;
+    let mut lines = Vec::new();
+
+    if defines("generate") {
+        let mut level = Map::new(map.width, map.height, -1, -1);
+        let mut game = GameView {
+            rng: Some(&mut rng),
+            level: Some(&mut level),
+            ..Default::default()
+        };
+        let mut script = Script::new(program.clone()).with_fuel(MAP_FUEL);
+        let result = engine.call(&mut script, &mut game, "generate", &[]);
+        lines.push(format!("generate: {}", outcome(&engine, result)));
+        for row in level.tiles.chunks(level.width as usize) {
+            let row: String = row
+                .iter()
+                .map(|tile| if *tile == TileType::Floor { '.' } else { '#' })
+                .collect();
+            lines.push(row);
+        }
+    }
+
+    for turn in 0..=TURNS {
+        let mut viewshed = Viewshed::new(6);
+        viewshed.visible_tiles = field_of_view(here.point(), 6, &map);
+        let sees_player = viewshed.visible_tiles.contains(&there.point());
+
+        let mut hooks = Vec::new();
+        if turn == TURNS {
+            // the mob's last turn: it dies
+            hooks.push(("on_death", vec![]));
+        } else {
+            if turn == 0 {
+                hooks.push(("on_spawn", vec![]));
+            }
+            if sees_player && !saw_player {
+                hooks.push(("on_see_player", vec![]));
+            }
+            saw_player = sees_player;
+            if turn % 7 == 6 {
+                stats.hp.cur -= 1;
+                hooks.push(("on_hit", vec![Value::Entity(player), Value::Number(1.0)]));
+            }
+            hooks.push(("on_turn", vec![]));
+
+            let mob = if turn % 2 == 0 {
+                Value::Entity(player)
+            } else {
+                Value::Nil
+            };
+            hooks.push(("on_use", vec![mob, Value::Position(there)]));
+
+            for (function, arity) in &functions {
+                if !MOB_HOOKS.contains(&function.as_str()) && function != "on_use" {
+                    hooks.push((function, vec![Value::Nil; *arity]));
+                }
+            }
+        }
+
+        for (hook, args) in hooks {
+            if !defines(hook) {
+                continue;
+            }
+            let mut game = GameView {
+                map: Some(&map),
+                rng: Some(&mut rng),
+                player: Some((player, there)),
+                actor: Some(Actor::new(&here, &name, Some(&stats), Some(&viewshed))),
+                memory: Some(&mut memory),
+                level: None,
+            };
+            let outcome = match hook {
+                "on_turn" => {
+                    let result = engine.run_turn(&mut script, &mut game);
+                    if let Ok(Some(Intent::Move(dir))) = result {
+                        match map.step(dir, &here) {
+                            Some(step) if map.walkable(&step) && step != there => here = step,
+                            _ => {}
+                        }
+                    }
+                    intents(&engine, result.map(|intent| intent.into_iter().collect()))
+                }
+                "on_use" => {
+                    let result = engine.run_effects(&mut script, &mut game, hook, &args);
+                    intents(&engine, result)
+                }
+                hook if MOB_HOOKS.contains(&hook) => {
+                    let result = engine.run_hook(&mut script, &mut game, hook, &args);
+                    intents(&engine, result.map(|intent| intent.into_iter().collect()))
+                }
+                _ => {
+                    let result = engine.call(&mut script, &mut game, hook, &args);
+                    outcome(&engine, result)
+                }
+            };
+            lines.push(format!("turn {turn} {hook}: {outcome}"));
+        }
+
+        let dir = match rng.range(0, 4) {
+            0 => Direction::North,
+            1 => Direction::South,
+            2 => Direction::East,
+            _ => Direction::West,
+        };
+        match map.step(dir, &there) {
+            Some(step) if map.walkable(&step) && step != here => there = step,
+            _ => {}
+        }
+    }
+
+    for (key, value) in &memory.values {
+        lines.push(format!("remembers {key} = {}", engine.heap.format(*value)));
+    }
+    lines
+}
+
+fn outcome(engine: &ScriptEngine, result: Result<Option<Value>, RuntimeError>) -> String {
+    match result {
+        Ok(Some(value)) => engine.heap.format(value),
+        Ok(None) => "nothing".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+fn intents(engine: &ScriptEngine, result: Result<Vec<Intent>, RuntimeError>) -> String {
+    match result {
+        Ok(intents) if intents.is_empty() => "nothing".to_string(),
+        Ok(intents) => {
+            let intents: Vec<String> = intents
+                .iter()
+                .map(|intent| engine.heap.format(Value::Intent(*intent)))
+                .collect();
+            intents.join(", ")
+        }
+        Err(e) => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::corpus::PROGRAMS;
+
+    /// The shipped scripts and the corpus, by name.
+    fn scripts() -> Vec<(String, String)> {
+        crate::link_scripts();
+        let shipped = SHIPPED
+            .iter()
+            .map(|path| (path.to_string(), module_source(path).unwrap()));
+        let corpus = PROGRAMS
+            .iter()
+            .map(|(name, source)| (name.to_string(), source.to_string()));
+        shipped.chain(corpus).collect()
+    }
+
+    #[test]
+    fn optimiser_keeps_behaviour() {
+        for (path, source) in scripts() {
+            if let Err(e) = compare(&path, &source) {
+                panic!("{path}: {e}");
+            }
+        }
+    }
+
+    /// Collecting all the time mustn't free anything a script still uses.
+    #[test]
+    fn shipped_scripts_survive_gc_stress() {
+        crate::link_scripts();
+        for path in SHIPPED {
+            let program = load_program(path).unwrap();
+            assert_eq!(
+                trace_with(program.clone(), Backend::Bytecode, false),
+                trace_with(program, Backend::Bytecode, true),
+                "{path}"
+            );
+        }
+    }
+
+    /// A trace that never reaches a script's code would match whatever the
+    /// optimiser did to it.
+    #[test]
+    fn every_function_is_called() {
+        for (path, source) in scripts() {
+            let program = Arc::new(Program::new(&path, &source).unwrap());
+            let lines = trace(program.clone(), Backend::Bytecode);
+            for (function, _) in functions(&program) {
+                let called = lines.iter().any(|line| {
+                    line.starts_with(&format!("{function}: "))
+                        || line.contains(&format!(" {function}: "))
+                });
+                assert!(called, "{path}: {function}() was never called");
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use bevy_ecs::prelude::*;
+use bracket_lib::prelude::*;
+
+use crate::components::*;
+use crate::script::*;
+
+/// What one mob's script has cost, turn by turn.
+pub struct MobProfile {
+    pub name: String,
+    /// Turns the script has run in, which isn't every turn for a script
+    /// with no `on_turn`.
+    pub turns: u64,
+    pub last: Counters,
+    pub total: Counters,
+}
+
+impl MobProfile {
+    /// Average time a turn takes, in microseconds, which is what the
+    /// profile is sorted by.
+    pub fn cost(&self) -> u128 {
+        self.total.time.as_micros() / self.turns.max(1) as u128
+    }
+}
+
+/// Keeps track of which mob scripts eat the frame budget. Mobs stay in the
+/// profile after they die, so the dump covers the whole game.
+pub struct Profiler {
+    mobs: HashMap<Entity, MobProfile>,
+    pub shown: bool,
+    /// Where to write the profile when the game ends, if anywhere.
+    pub dump_to: Option<String>,
+}
+
+impl Profiler {
+    pub fn new(dump_to: Option<String>) -> Self {
+        Self {
+            mobs: HashMap::new(),
+            shown: false,
+            dump_to,
+        }
+    }
+
+    pub fn record(&mut self, mob: Entity, name: &str, cost: Counters) {
+        let profile = self.mobs.entry(mob).or_insert_with(|| MobProfile {
+            name: name.to_string(),
+            turns: 0,
+            last: Counters::default(),
+            total: Counters::default(),
+        });
+        profile.turns += 1;
+        profile.last = cost;
+        profile.total += cost;
+    }
+
+    /// Every mob profiled so far, the costliest first.
+    pub fn by_cost(&self) -> Vec<(Entity, &MobProfile)> {
+        let mut mobs: Vec<_> = self.mobs.iter().map(|(e, p)| (*e, p)).collect();
+        mobs.sort_by(|a, b| b.1.cost().cmp(&a.1.cost()).then(a.0.cmp(&b.0)));
+        mobs
+    }
+
+    /// Draw last turn's costs over the top of the map, for as many of the
+    /// costliest mobs as fit.
+    pub fn draw(&self, ctx: &mut BTerm, width: i32, height: i32) {
+        let fg = RGB::named(WHITE);
+        let bg = RGB::named(NAVY);
+        ctx.draw_box(0, 1, width - 1, height - 3, fg, bg);
+        ctx.print_color(
+            2,
+            2,
+            RGB::named(YELLOW),
+            bg,
+            format!(
+                "{:<10}{:>6}{:>5}{:>5}{:>7}",
+                "Mob", "ins", "nat", "new", "avg us"
+            ),
+        );
+
+        let rows = (height - 6).max(0) as usize;
+        let mobs = self.by_cost();
+        if mobs.is_empty() {
+            ctx.print_color(2, 3, fg, bg, "No scripts have run yet.");
+        }
+        for (y, (entity, mob)) in mobs.iter().take(rows).enumerate() {
+            let name: String = format!("{} {}", mob.name, entity.id())
+                .chars()
+                .take(9)
+                .collect();
+            ctx.print_color(
+                2,
+                3 + y as i32,
+                fg,
+                bg,
+                format!(
+                    "{name:<10}{:>6}{:>5}{:>5}{:>7}",
+                    mob.last.instructions,
+                    mob.last.native_calls,
+                    mob.last.allocations,
+                    mob.cost()
+                ),
+            );
+        }
+        ctx.print_color(2, height - 3, RGB::named(GREY50), bg, "last turn; P hides");
+    }
+
+    /// The whole profile as a table, for the dump.
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "{:<16}{:>8}{:>14}{:>10}{:>13}{:>10}{:>8}\n",
+            "mob", "turns", "instructions", "natives", "allocations", "total us", "avg us"
+        );
+        for (entity, mob) in self.by_cost() {
+            let _ = writeln!(
+                report,
+                "{:<16}{:>8}{:>14}{:>10}{:>13}{:>10}{:>8}",
+                format!("{} {}", mob.name, entity.id()),
+                mob.turns,
+                mob.total.instructions,
+                mob.total.native_calls,
+                mob.total.allocations,
+                mob.total.time.as_micros(),
+                mob.cost()
+            );
+        }
+        report
+    }
+
+    /// Write the profile out, if the game was asked to.
+    pub fn dump(&self) {
+        if let Some(path) = &self.dump_to {
+            if let Err(e) = std::fs::write(path, self.report()) {
+                console::log(format!("Couldn't write the profile to {path}: {e}"));
+            }
+        }
+    }
+}
+
+/// Take what each script has cost this turn and add it to the profile.
+/// This runs after the last of the hooks, but before the dead are cleared
+/// away, so that their last turn is counted too.
+pub fn profile_scripts(
+    mut profiler: ResMut<Profiler>,
+    mut query: Query<(Entity, &Name, &mut Script)>,
+) {
+    for (entity, name, mut script) in query.iter_mut() {
+        if script.cost != Counters::default() {
+            let cost = std::mem::take(&mut script.cost);
+            profiler.record(entity, &name.name, cost);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use bevy_ecs::prelude::*;
+use bracket_lib::prelude::*;
+
+use crate::components::*;
+use crate::game_state::Selection;
+use crate::map::Map;
+use crate::script::*;
+use crate::system::Viewshed;
+
+/// How many instructions a line typed at the console may run.
+const REPL_FUEL: u32 = 100_000;
+
+/// Lines of scrollback kept before the oldest are dropped.
+const SCROLLBACK: usize = 200;
+
+/// The in-game REPL. Designers type mob-language at a prompt and it runs
+/// against the live world, which stays paused while the console is open.
+/// `self` refers to whichever mob is selected.
+pub struct Repl {
+    input: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    history_pos: Option<usize>,
+    scrollback: Vec<String>,
+    pending: Option<String>,
+    globals: Option<ObjRef>,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        let mut repl = Self {
+            input: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_pos: None,
+            scrollback: Vec::new(),
+            pending: None,
+            globals: None,
+        };
+        repl.print("Tab picks the mob that 'self' means.");
+        repl.print("Esc goes back to the game.");
+        repl
+    }
+
+    /// The console's global scope, once anything has been run in it.
+    pub fn globals(&self) -> Option<ObjRef> {
+        self.globals
+    }
+
+    pub fn print<T: ToString>(&mut self, text: T) {
+        for line in text.to_string().lines() {
+            self.scrollback.push(line.to_string());
+        }
+        while self.scrollback.len() > SCROLLBACK {
+            self.scrollback.remove(0);
+        }
+    }
+
+    /// Handle an editing key. Printable characters arrive separately,
+    /// through `insert`.
+    pub fn key(&mut self, key: VirtualKeyCode) {
+        match key {
+            VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => self.submit(),
+            VirtualKeyCode::Back if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            VirtualKeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            VirtualKeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            VirtualKeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            VirtualKeyCode::Home => self.cursor = 0,
+            VirtualKeyCode::End => self.cursor = self.input.len(),
+            VirtualKeyCode::Up => self.recall_older(),
+            VirtualKeyCode::Down => self.recall_newer(),
+            _ => {}
+        }
+    }
+
+    pub fn insert(&mut self, c: char) {
+        if !c.is_control() {
+            self.input.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    fn submit(&mut self) {
+        let line: String = self.input.drain(..).collect();
+        self.cursor = 0;
+        self.history_pos = None;
+        self.print(format!("> {line}"));
+
+        if line.trim().is_empty() {
+            return;
+        }
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        self.pending = Some(line);
+    }
+
+    fn recall_older(&mut self) {
+        let pos = match self.history_pos {
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+            Some(n) => n.saturating_sub(1),
+        };
+        self.recall(Some(pos));
+    }
+
+    fn recall_newer(&mut self) {
+        match self.history_pos {
+            Some(n) if n + 1 < self.history.len() => self.recall(Some(n + 1)),
+            Some(_) => self.recall(None),
+            None => {}
+        }
+    }
+
+    fn recall(&mut self, pos: Option<usize>) {
+        self.history_pos = pos;
+        self.input = match pos {
+            Some(n) => self.history[n].chars().collect(),
+            None => Vec::new(),
+        };
+        self.cursor = self.input.len();
+    }
+
+    /// Draw the console over the whole screen, with `title` on the top row.
+    pub fn draw(&self, ctx: &mut BTerm, title: &str, width: i32, height: i32) {
+        ctx.print(0, 0, title);
+
+        let width = width as usize;
+        let lines: Vec<String> = self
+            .scrollback
+            .iter()
+            .flat_map(|line| wrap(line, width))
+            .collect();
+        let rows = (height - 2) as usize;
+        let first = lines.len().saturating_sub(rows);
+        for (row, line) in lines[first..].iter().enumerate() {
+            ctx.print(0, row as i32 + 1, line);
+        }
+
+        // scroll the prompt sideways to keep the cursor on screen
+        let prompt_row = height - 1;
+        let room = width - 3;
+        let start = self.cursor.saturating_sub(room);
+        let shown: String = self.input[start..].iter().take(room + 1).collect();
+        ctx.print(0, prompt_row, "> ");
+        ctx.print(2, prompt_row, shown);
+
+        let under_cursor = self.input.get(self.cursor).copied().unwrap_or(' ');
+        ctx.set(
+            (2 + self.cursor - start) as i32,
+            prompt_row,
+            RGB::named(BLACK),
+            RGB::named(WHITE),
+            to_cp437(under_cursor),
+        );
+    }
+}
+
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars.chunks(width).map(|c| c.iter().collect()).collect()
+}
+
+/// Run whatever line was entered at the console since the last tick.
+#[allow(clippy::too_many_arguments)]
+pub fn run_repl(
+    mut repl: ResMut<Repl>,
+    selection: Res<Selection>,
+    mut engine: ResMut<ScriptEngine>,
+    mut rng: ResMut<RandomNumberGenerator>,
+    map: Res<Map>,
+    player: Res<Entity>,
+    query: Query<(&Position, &Name, Option<&Stats>, Option<&Viewshed>)>,
+    mut memories: Query<&mut Blackboard>,
+) {
+    let line = match repl.pending.take() {
+        Some(line) => line,
+        None => return,
+    };
+
+    let globals = match repl.globals {
+        Some(globals) => globals,
+        None => {
+            let globals = engine.new_globals();
+            repl.globals = Some(globals);
+            globals
+        }
+    };
+
+    // let the last expression on a line go without its semicolon
+    let known = engine.global_names(globals);
+    let parsed = crate::script::parse(&line, &known).or_else(|diagnostics| {
+        crate::script::parse(&format!("{line};"), &known).map_err(|_| diagnostics)
+    });
+    let statements = match parsed {
+        Ok(statements) => statements,
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                repl.print(diagnostic);
+            }
+            return;
+        }
+    };
+
+    let actor = selection
+        .entity
+        .and_then(|target| query.get(target).ok())
+        .map(|(position, name, stats, viewshed)| Actor::new(position, name, stats, viewshed));
+    let mut memory = selection
+        .entity
+        .and_then(|target| memories.get_mut(target).ok());
+    let mut game = GameView {
+        map: Some(&map),
+        rng: Some(&mut rng),
+        player: query
+            .get(*player)
+            .ok()
+            .map(|(position, ..)| (*player, *position)),
+        actor,
+        memory: memory.as_deref_mut(),
+        level: None,
+    };
+
+    match engine.eval(globals, &statements, &mut game, REPL_FUEL) {
+        Ok(Some(value)) => {
+            let result = engine.heap.format(value);
+            repl.print(result);
+        }
+        Ok(None) => {}
+        Err(e) => repl.print(e),
+    }
+}
@@ -0,0 +1,88 @@
+use bevy_ecs::prelude::*;
+use bracket_lib::prelude::*;
+
+/// What the player's code scanner shows: the disassembled program of one of
+/// the mobs in sight, or the program printed back out as source. Tab moves
+/// on to the next mob.
+pub struct ScanView {
+    targets: Vec<Entity>,
+    current: usize,
+    lines: Vec<String>,
+    top: usize,
+    /// Whether to show source rather than bytecode.
+    pub source: bool,
+}
+
+impl ScanView {
+    pub fn new() -> Self {
+        Self {
+            targets: Vec::new(),
+            current: 0,
+            lines: Vec::new(),
+            top: 0,
+            source: false,
+        }
+    }
+
+    /// Start scanning `targets`, nearest first.
+    pub fn open(&mut self, targets: Vec<Entity>) {
+        self.targets = targets;
+        self.current = 0;
+    }
+
+    pub fn target(&self) -> Option<Entity> {
+        self.targets.get(self.current).copied()
+    }
+
+    /// Move on to the next mob in sight, wrapping round to the first.
+    pub fn next_target(&mut self) {
+        if !self.targets.is_empty() {
+            self.current = (self.current + 1) % self.targets.len();
+        }
+    }
+
+    pub fn show(&mut self, lines: Vec<String>) {
+        self.lines = lines;
+        self.top = 0;
+    }
+
+    pub fn key(&mut self, key: VirtualKeyCode) {
+        let last = self.lines.len().saturating_sub(1);
+        self.top = match key {
+            VirtualKeyCode::Up => self.top.saturating_sub(1),
+            VirtualKeyCode::Down => (self.top + 1).min(last),
+            VirtualKeyCode::PageUp => self.top.saturating_sub(10),
+            VirtualKeyCode::PageDown => (self.top + 10).min(last),
+            VirtualKeyCode::Home => 0,
+            _ => self.top,
+        };
+    }
+
+    /// Draw the listing over the whole screen, with `title` on the top row.
+    pub fn draw(&self, ctx: &mut BTerm, title: &str, width: i32, height: i32) {
+        ctx.print(0, 0, title);
+        ctx.print_color(
+            0,
+            height - 1,
+            RGB::named(YELLOW),
+            RGB::named(BLACK),
+            "Tab next mob, S source/bytecode, arrows scroll, Esc",
+        );
+
+        let rows = (height - 2) as usize;
+        for (y, line) in self.lines.iter().skip(self.top).take(rows).enumerate() {
+            let y = y as i32 + 1;
+            let line: String = line.chars().take(width as usize).collect();
+            if self.source {
+                ctx.print_color(0, y, RGB::named(GREEN), RGB::named(BLACK), line);
+            } else if line.starts_with("==") {
+                ctx.print_color(0, y, RGB::named(MAGENTA), RGB::named(BLACK), line);
+            } else {
+                // the offset and line number are just for reference
+                let (margin, code) = line.split_at(line.len().min(10));
+                ctx.print_color(0, y, RGB::named(GREY50), RGB::named(BLACK), margin);
+                ctx.print_color(10, y, RGB::named(GREEN), RGB::named(BLACK), code);
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use bevy_ecs::prelude::*;
+use bevy_ecs::schedule::ShouldRun;
+
+use crate::components::*;
+
+/// How many times the schedule may run in one frame while waiting for the
+/// player to get a turn, so a player who can't act doesn't hang the game.
+pub const MAX_PASSES: usize = 100;
+
+/// Keeps the game clock. The world stands still while the player has a
+/// turn saved up, and otherwise runs until they have one again, skipping
+/// straight to the next tick on which anyone gets to act.
+#[derive(Default)]
+pub struct Scheduler {
+    /// Ticks since the game started.
+    pub ticks: u64,
+    /// Whole turns, at normal speed, that went by in the last step. Things
+    /// that last a number of turns count down by this much.
+    pub turns_passed: u64,
+    running: bool,
+}
+
+pub fn run_if_world_is_running(scheduler: Res<Scheduler>) -> ShouldRun {
+    if scheduler.running {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+/// The ticks in a turn taken at normal speed.
+const TICKS_PER_TURN: u64 = (TURN_COST / NORMAL_SPEED) as u64;
+
+/// Whether the player has a turn to take. A player with no `Speed` is
+/// always ready, so the world only moves when they do. A stunned player
+/// isn't, since their turn is going to be lost anyway.
+pub fn player_ready(world: &World) -> bool {
+    let player = *world.get_resource::<Entity>().unwrap();
+    let stunned = world
+        .get::<StatusEffects>(player)
+        .is_some_and(|status| status.has(Effect::Stun));
+    world.get::<Speed>(player).is_none_or(Speed::ready) && !stunned
+}
+
+/// Run `schedule` until the player has a turn to take, or until `stop`
+/// says the world has to wait. Each pass of the schedule is one step of the
+/// clock, with whoever can act then acting.
+pub fn run_until_player_ready(
+    world: &mut World,
+    schedule: &mut Schedule,
+    stop: impl Fn(&World) -> bool,
+) {
+    schedule.run(world);
+    for _ in 1..MAX_PASSES {
+        if player_ready(world) || stop(world) {
+            break;
+        }
+        schedule.run(world);
+    }
+}
+
+/// What gets turns. Anything else with a `Speed` would never use its
+/// energy up, and would hold the clock still.
+type TakesTurns = Or<(With<Mob>, With<Player>)>;
+
+/// Run the clock on to the next tick that anyone can act on, and give
+/// everything the energy it built up along the way. Nothing happens while
+/// the player is still to take their turn.
+pub fn advance_time(
+    mut scheduler: ResMut<Scheduler>,
+    player: Res<Entity>,
+    mut query: Query<&mut Speed, TakesTurns>,
+) {
+    scheduler.turns_passed = 0;
+    if query.get(*player).map_or(true, |speed| speed.ready()) {
+        scheduler.running = false;
+        return;
+    }
+
+    let wait = query
+        .iter()
+        .filter_map(|speed| speed.ticks_until_ready())
+        .min()
+        .unwrap_or(1);
+    for mut speed in query.iter_mut() {
+        speed.energy += speed.speed * wait;
+    }
+    let turns_before = scheduler.ticks / TICKS_PER_TURN;
+    scheduler.ticks += wait as u64;
+    scheduler.turns_passed = scheduler.ticks / TICKS_PER_TURN - turns_before;
+    scheduler.running = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How many turns a mob has taken.
+    #[derive(Component)]
+    struct Turns(u32);
+
+    /// Every whole turn that has gone by.
+    #[derive(Default)]
+    struct TurnsPassed(u64);
+
+    /// Whatever has a turn saved up takes it.
+    fn take_turns(mut query: Query<(&mut Speed, &mut Turns), With<Mob>>) {
+        for (mut speed, mut turns) in query.iter_mut() {
+            while speed.ready() {
+                speed.spend();
+                turns.0 += 1;
+            }
+        }
+    }
+
+    fn count_turns(scheduler: Res<Scheduler>, mut passed: ResMut<TurnsPassed>) {
+        passed.0 += scheduler.turns_passed;
+    }
+
+    fn world(player_speed: i32) -> (World, Schedule) {
+        let mut world = World::new();
+        let player = world
+            .spawn()
+            .insert(Player)
+            .insert(Speed::new(player_speed))
+            .id();
+        world.insert_resource(player);
+        world.init_resource::<Scheduler>();
+        world.init_resource::<TurnsPassed>();
+        let schedule = Schedule::default()
+            .with_stage("time", SystemStage::parallel().with_system(advance_time))
+            .with_stage(
+                "act",
+                SystemStage::parallel()
+                    .with_run_criteria(run_if_world_is_running)
+                    .with_system(take_turns)
+                    .with_system(count_turns),
+            );
+        (world, schedule)
+    }
+
+    fn mob(world: &mut World, speed: i32) -> Entity {
+        world
+            .spawn()
+            .insert(Mob { glyph: 'm' })
+            .insert(Speed::new(speed))
+            .insert(Turns(0))
+            .id()
+    }
+
+    /// Let the player take `turns` turns, running the world in between.
+    fn play(world: &mut World, schedule: &mut Schedule, turns: u32) {
+        let player = *world.get_resource::<Entity>().unwrap();
+        for _ in 0..turns {
+            run_until_player_ready(world, schedule, |_| false);
+            world.get_mut::<Speed>(player).unwrap().spend();
+        }
+    }
+
+    #[test]
+    fn double_speed_gets_double_the_turns() {
+        let (mut world, mut schedule) = world(NORMAL_SPEED);
+        let fast = mob(&mut world, 2 * NORMAL_SPEED);
+        let slow = mob(&mut world, NORMAL_SPEED);
+        play(&mut world, &mut schedule, 10);
+        assert_eq!(world.get::<Turns>(fast).unwrap().0, 20);
+        assert_eq!(world.get::<Turns>(slow).unwrap().0, 10);
+    }
+
+    #[test]
+    fn a_turn_passes_per_turn_cost() {
+        let (mut world, mut schedule) = world(NORMAL_SPEED);
+        // a fast mob makes the clock stop halfway through each turn
+        mob(&mut world, 2 * NORMAL_SPEED);
+        play(&mut world, &mut schedule, 10);
+        let scheduler = world.get_resource::<Scheduler>().unwrap();
+        assert_eq!(scheduler.ticks, 10 * TICKS_PER_TURN);
+        assert_eq!(world.get_resource::<TurnsPassed>().unwrap().0, 10);
+    }
+
+    #[test]
+    fn time_stops_while_the_player_is_ready() {
+        let (mut world, mut schedule) = world(NORMAL_SPEED);
+        let other = mob(&mut world, NORMAL_SPEED);
+        let player = *world.get_resource::<Entity>().unwrap();
+        world.get_mut::<Speed>(player).unwrap().energy = TURN_COST;
+
+        for _ in 0..3 {
+            schedule.run(&mut world);
+        }
+        let scheduler = world.get_resource::<Scheduler>().unwrap();
+        assert_eq!(scheduler.ticks, 0);
+        assert!(!scheduler.running);
+        assert_eq!(world.get::<Speed>(other).unwrap().energy, 0);
+        assert_eq!(world.get::<Turns>(other).unwrap().0, 0);
+    }
+
+    /// A player who can never act doesn't hang the game.
+    #[test]
+    fn the_world_runs_for_at_most_max_passes() {
+        let (mut world, mut schedule) = world(0);
+        run_until_player_ready(&mut world, &mut schedule, |_| false);
+        // with nobody to wait for, each pass is a single tick
+        let scheduler = world.get_resource::<Scheduler>().unwrap();
+        assert_eq!(scheduler.ticks, MAX_PASSES as u64);
+    }
+}
@@ -0,0 +1,459 @@
+use bevy_ecs::prelude::*;
+use bracket_lib::prelude::*;
+
+use crate::components::{Blackboard, Name, Position, Stats};
+use crate::map::{Map, TileType};
+use crate::script::heap::*;
+use crate::script::value::*;
+use crate::system::Viewshed;
+
+/// What a script can see of the world while it runs. Everything in here is
+/// either a copy or a shared borrow, so a running script can look at the game
+/// but never change it; the only way to affect the world is to return an
+/// intent. The exceptions are the mob's own blackboard, which is the
+/// script's to scribble on, and the map a map generator script is building.
+/// Anything that isn't available (say, there is no map yet) is left as
+/// `None`, and the natives that need it report an error.
+#[derive(Default)]
+pub struct GameView<'w> {
+    pub map: Option<&'w Map>,
+    pub rng: Option<&'w mut RandomNumberGenerator>,
+    pub player: Option<(Entity, Position)>,
+    pub actor: Option<Actor<'w>>,
+    pub memory: Option<&'w mut Blackboard>,
+    pub level: Option<&'w mut Map>,
+}
+
+/// The mob whose script is running, as seen through `self`.
+pub struct Actor<'w> {
+    pub position: Position,
+    pub name: &'w str,
+    pub stats: Option<Stats>,
+    pub visible_tiles: &'w [Point],
+}
+
+impl<'w> Actor<'w> {
+    /// Snapshot a mob from the components a query hands back.
+    pub fn new(
+        position: &Position,
+        name: &'w Name,
+        stats: Option<&Stats>,
+        viewshed: Option<&'w Viewshed>,
+    ) -> Self {
+        Self {
+            position: *position,
+            name: &name.name,
+            stats: stats.copied(),
+            visible_tiles: viewshed.map_or(&[], |vs| &vs.visible_tiles),
+        }
+    }
+}
+
+/// A group of natives reached through a global, like `map.walkable`.
+#[derive(Debug)]
+pub struct Namespace {
+    pub name: &'static str,
+    pub members: &'static [&'static Native],
+}
+
+impl Namespace {
+    pub fn get(&self, name: &str) -> Option<&'static Native> {
+        self.members.iter().copied().find(|n| n.name == name)
+    }
+}
+
+macro_rules! native {
+    ($name:ident, $arity:expr, $function:ident) => {
+        static $name: Native = Native {
+            name: stringify!($function),
+            arity: $arity,
+            function: $function,
+        };
+    };
+}
+
+/// Namespaces defined in every script's global scope.
+pub static NAMESPACES: [&Namespace; 6] = [&MAP, &SELF, &PLAYER, &RNG, &MEMORY, &BUILDER];
+
+static MAP: Namespace = Namespace {
+    name: "map",
+    members: &[&WALKABLE, &TRY_WALK, &NEW_POSITION],
+};
+
+static SELF: Namespace = Namespace {
+    name: "self",
+    members: &[
+        &POSITION,
+        &NAME,
+        &HP,
+        &MAX_HP,
+        &MP,
+        &MAX_MP,
+        &VISIBLE_TILES,
+        &CAN_SEE,
+    ],
+};
+
+static PLAYER: Namespace = Namespace {
+    name: "player",
+    members: &[&PLAYER_POSITION, &PLAYER_ID],
+};
+
+static RNG: Namespace = Namespace {
+    name: "rng",
+    members: &[&RANGE, &ROLL_DICE],
+};
+
+static MEMORY: Namespace = Namespace {
+    name: "memory",
+    members: &[&RECALL, &REMEMBER, &KEYS],
+};
+
+static BUILDER: Namespace = Namespace {
+    name: "builder",
+    members: &[
+        &WIDTH,
+        &HEIGHT,
+        &TILE,
+        &SET_TILE,
+        &CARVE_ROOM,
+        &CARVE_CORRIDOR,
+        &SET_START,
+    ],
+};
+
+native!(WALKABLE, 1, walkable);
+native!(TRY_WALK, 1, try_walk);
+native!(NEW_POSITION, 2, new_position);
+native!(POSITION, 0, position);
+native!(NAME, 0, name);
+native!(HP, 0, hp);
+native!(MAX_HP, 0, max_hp);
+native!(MP, 0, mp);
+native!(MAX_MP, 0, max_mp);
+native!(VISIBLE_TILES, 0, visible_tiles);
+native!(CAN_SEE, 1, can_see);
+native!(RANGE, 2, range);
+native!(ROLL_DICE, 2, roll_dice);
+
+static RECALL: Native = Native {
+    name: "get",
+    arity: 1,
+    function: recall,
+};
+
+static REMEMBER: Native = Native {
+    name: "set",
+    arity: 2,
+    function: remember,
+};
+
+native!(KEYS, 0, keys);
+
+native!(WIDTH, 0, width);
+native!(HEIGHT, 0, height);
+native!(TILE, 1, tile);
+native!(SET_TILE, 2, set_tile);
+native!(CARVE_ROOM, 3, carve_room);
+native!(CARVE_CORRIDOR, 2, carve_corridor);
+native!(SET_START, 1, set_start);
+
+static PLAYER_POSITION: Native = Native {
+    name: "position",
+    arity: 0,
+    function: player_position,
+};
+
+static PLAYER_ID: Native = Native {
+    name: "id",
+    arity: 0,
+    function: player_id,
+};
+
+fn the_map<'a>(game: &'a GameView) -> Result<&'a Map, String> {
+    game.map.ok_or_else(|| "There is no map here.".to_string())
+}
+
+fn the_actor<'a, 'w>(game: &'a GameView<'w>) -> Result<&'a Actor<'w>, String> {
+    game.actor
+        .as_ref()
+        .ok_or_else(|| "There is no mob for 'self' here.".to_string())
+}
+
+fn the_stats(game: &GameView) -> Result<Stats, String> {
+    the_actor(game)?
+        .stats
+        .ok_or_else(|| "This mob has no stats.".to_string())
+}
+
+fn the_rng<'a>(game: &'a mut GameView) -> Result<&'a mut RandomNumberGenerator, String> {
+    game.rng
+        .as_deref_mut()
+        .ok_or_else(|| "There is no random number generator here.".to_string())
+}
+
+fn expect_position(value: Value, function: &str) -> Result<Position, String> {
+    match value {
+        Value::Position(p) => Ok(p),
+        _ => Err(format!("{function}() expects a position.")),
+    }
+}
+
+fn expect_int(value: Value, function: &str) -> Result<i32, String> {
+    match value {
+        Value::Number(n) if n.fract() == 0.0 => Ok(n as i32),
+        _ => Err(format!("{function}() expects whole numbers.")),
+    }
+}
+
+fn in_bounds(map: &Map, p: &Position) -> bool {
+    map.point_to_idx(&p.point()).is_some()
+}
+
+fn walkable(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let map = the_map(game)?;
+    let p = expect_position(args[0], "walkable")?;
+    Ok(Value::Bool(in_bounds(map, &p) && map.walkable(&p)))
+}
+
+fn try_walk(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let map = the_map(game)?;
+    let p = expect_position(args[0], "try_walk")?;
+    if !in_bounds(map, &p) {
+        return Ok(Value::Nil);
+    }
+    Ok(map.try_walk(&p).map_or(Value::Nil, |e| Value::Entity(*e)))
+}
+
+fn new_position(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let map = the_map(game)?;
+    let dir = match args[0] {
+        Value::Direction(dir) => dir,
+        _ => return Err("new_position() expects a direction.".to_string()),
+    };
+    let p = expect_position(args[1], "new_position")?;
+    Ok(Value::Position(map.new_position(dir, &p)))
+}
+
+fn position(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Position(the_actor(game)?.position))
+}
+
+fn name(heap: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Obj(heap.intern(the_actor(game)?.name)))
+}
+
+fn hp(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(the_stats(game)?.hp.cur as f64))
+}
+
+fn max_hp(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(the_stats(game)?.hp.max as f64))
+}
+
+fn mp(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(the_stats(game)?.mp.cur as f64))
+}
+
+fn max_mp(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(the_stats(game)?.mp.max as f64))
+}
+
+fn visible_tiles(heap: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    let tiles = the_actor(game)?
+        .visible_tiles
+        .iter()
+        .map(|p| Value::Position(p.into()))
+        .collect();
+    Ok(Value::Obj(heap.alloc(Obj::List(tiles))))
+}
+
+fn can_see(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let p = expect_position(args[0], "can_see")?.point();
+    Ok(Value::Bool(the_actor(game)?.visible_tiles.contains(&p)))
+}
+
+fn the_player(game: &GameView) -> Result<(Entity, Position), String> {
+    game.player
+        .ok_or_else(|| "There is no player here.".to_string())
+}
+
+fn player_position(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Position(the_player(game)?.1))
+}
+
+/// The player as an entity, to compare against the `source` of `on_hit`.
+fn player_id(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Entity(the_player(game)?.0))
+}
+
+fn range(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let lo = expect_int(args[0], "range")?;
+    let hi = expect_int(args[1], "range")?;
+    if lo >= hi {
+        return Err("range() needs a low bound below its high bound.".to_string());
+    }
+    Ok(Value::Number(the_rng(game)?.range(lo, hi) as f64))
+}
+
+/// The most dice, and the biggest die, a script can roll at once. Rolling is
+/// one native call however many dice there are, so fuel doesn't bound it.
+pub const MAX_DICE: i32 = 100;
+pub const MAX_SIDES: i32 = 1000;
+
+fn roll_dice(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let n = expect_int(args[0], "roll_dice")?;
+    let die = expect_int(args[1], "roll_dice")?;
+    if n < 0 || die < 1 {
+        return Err("roll_dice() needs a positive count and die size.".to_string());
+    }
+    if n > MAX_DICE || die > MAX_SIDES {
+        return Err(format!(
+            "roll_dice() rolls at most {MAX_DICE} dice of up to {MAX_SIDES} sides."
+        ));
+    }
+    Ok(Value::Number(the_rng(game)?.roll_dice(n, die) as f64))
+}
+
+fn the_memory<'a>(game: &'a mut GameView) -> Result<&'a mut Blackboard, String> {
+    game.memory
+        .as_deref_mut()
+        .ok_or_else(|| "This mob has no memory.".to_string())
+}
+
+fn expect_key<'h>(heap: &'h Heap, value: Value, function: &str) -> Result<&'h str, String> {
+    heap.as_str(value)
+        .ok_or_else(|| format!("{function}() expects a string key."))
+}
+
+fn recall(heap: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let key = expect_key(heap, args[0], "get")?;
+    Ok(the_memory(game)?.get(key))
+}
+
+/// Only plain data goes on a blackboard: functions belong to the script that
+/// made them, and would outlive it there.
+fn remember(heap: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let key = expect_key(heap, args[0], "set")?;
+    let value = args[1];
+    let storable = match value {
+        Value::Native(_) | Value::Namespace(_) => false,
+        Value::Obj(r) => !matches!(
+            heap.get(r),
+            Obj::Closure(_) | Obj::Function(_) | Obj::Module(_)
+        ),
+        _ => true,
+    };
+    if !storable {
+        return Err(format!(
+            "Can't remember a {}; only data goes in memory.",
+            value.type_name(heap)
+        ));
+    }
+    the_memory(game)?.set(key, value);
+    heap.root(value);
+    Ok(value)
+}
+
+fn keys(heap: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    let keys: Vec<String> = the_memory(game)?.values.keys().cloned().collect();
+    let keys = keys.iter().map(|k| Value::Obj(heap.intern(k))).collect();
+    Ok(Value::Obj(heap.alloc(Obj::List(keys))))
+}
+
+fn the_level<'a>(game: &'a mut GameView) -> Result<&'a mut Map, String> {
+    game.level
+        .as_deref_mut()
+        .ok_or_else(|| "There is no map being built here.".to_string())
+}
+
+/// `position` as a tile index, as long as it is on the level.
+fn level_idx(level: &Map, position: Value, function: &str) -> Result<usize, String> {
+    let p = expect_position(position, function)?;
+    level
+        .point_to_idx(&p.point())
+        .ok_or_else(|| format!("{function}() was given a position off the map."))
+}
+
+fn tile_name(tile: TileType) -> &'static str {
+    match tile {
+        TileType::Floor => "floor",
+        TileType::Wall => "wall",
+    }
+}
+
+fn width(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(the_level(game)?.width() as f64))
+}
+
+fn height(_: &mut Heap, game: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(the_level(game)?.height() as f64))
+}
+
+fn tile(heap: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let level = the_level(game)?;
+    let idx = level_idx(level, args[0], "tile")?;
+    Ok(Value::Obj(heap.intern(tile_name(level.tiles[idx]))))
+}
+
+/// Tiles go by name: `builder.set_tile(p, "wall")`.
+fn set_tile(heap: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let tile = match heap.as_str(args[1]) {
+        Some("floor") => TileType::Floor,
+        Some("wall") => TileType::Wall,
+        _ => return Err("set_tile() expects \"floor\" or \"wall\".".to_string()),
+    };
+    let level = the_level(game)?;
+    let idx = level_idx(level, args[0], "set_tile")?;
+    level.tiles[idx] = tile;
+    Ok(Value::Nil)
+}
+
+/// Floor a `width` by `height` room with its top left corner at `corner`.
+fn carve_room(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let corner = expect_position(args[0], "carve_room")?;
+    let width = expect_int(args[1], "carve_room")?;
+    let height = expect_int(args[2], "carve_room")?;
+    if width < 1 || height < 1 {
+        return Err("carve_room() needs a room at least one tile across.".to_string());
+    }
+    let level = the_level(game)?;
+    let far = Point::new(corner.x + width - 1, corner.y + height - 1);
+    if level.point_to_idx(&corner.point()).is_none() || level.point_to_idx(&far).is_none() {
+        return Err("carve_room() was given a room that goes off the map.".to_string());
+    }
+    for y in corner.y..=far.y {
+        for x in corner.x..=far.x {
+            let idx = level.pos_to_idx(&Position { x, y });
+            level.tiles[idx] = TileType::Floor;
+        }
+    }
+    Ok(Value::Nil)
+}
+
+/// Floor a corridor from `from` to `to`, going across first and then up or
+/// down, like the corridors between the game's own rooms.
+fn carve_corridor(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let level = the_level(game)?;
+    level_idx(level, args[0], "carve_corridor")?;
+    level_idx(level, args[1], "carve_corridor")?;
+    let from = expect_position(args[0], "carve_corridor")?;
+    let to = expect_position(args[1], "carve_corridor")?;
+    for x in from.x.min(to.x)..=from.x.max(to.x) {
+        let idx = level.pos_to_idx(&Position { x, y: from.y });
+        level.tiles[idx] = TileType::Floor;
+    }
+    for y in from.y.min(to.y)..=from.y.max(to.y) {
+        let idx = level.pos_to_idx(&Position { x: to.x, y });
+        level.tiles[idx] = TileType::Floor;
+    }
+    Ok(Value::Nil)
+}
+
+/// Where the player starts out on the new map.
+fn set_start(_: &mut Heap, game: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let level = the_level(game)?;
+    level_idx(level, args[0], "set_start")?;
+    level.set_start(expect_position(args[0], "set_start")?);
+    Ok(Value::Nil)
+}
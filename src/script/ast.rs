@@ -0,0 +1,310 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::map::Direction;
+use crate::script::token::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Direction(Direction),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Assign {
+        name: Token,
+        value: Box<Expr>,
+        depth: Option<usize>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        paren: Token,
+        arguments: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
+    Grouping(Box<Expr>),
+    Index {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+    },
+    List {
+        bracket: Token,
+        elements: Vec<Expr>,
+    },
+    Literal {
+        value: Literal,
+        span: Span,
+    },
+    Logical {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    SetIndex {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    Unary {
+        operator: Token,
+        right: Box<Expr>,
+    },
+    /// `depth` is filled in by the resolver: how many scopes out from the
+    /// use the variable was declared, or `None` for a global.
+    Variable {
+        name: Token,
+        depth: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDecl {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Block(Vec<Stmt>),
+    Expression(Expr),
+    Function(Arc<FunctionDecl>),
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    /// `import "ai/flee";` makes the module `ai/flee` available as `flee`.
+    Import {
+        keyword: Token,
+        path: String,
+        name: String,
+    },
+    Let {
+        name: Token,
+        initializer: Option<Expr>,
+    },
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    Yield {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+}
+
+impl Expr {
+    /// The span used when reporting a problem with this expression.
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Assign { name, .. } | Expr::Variable { name, .. } | Expr::Get { name, .. } => {
+                name.span
+            }
+            Expr::Binary { operator, .. }
+            | Expr::Logical { operator, .. }
+            | Expr::Unary { operator, .. } => operator.span,
+            Expr::Call { paren, .. } => paren.span,
+            Expr::Index { bracket, .. }
+            | Expr::List { bracket, .. }
+            | Expr::SetIndex { bracket, .. } => bracket.span,
+            Expr::Grouping(expr) => expr.span(),
+            Expr::Literal { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Nil => write!(f, "nil"),
+            Literal::Bool(b) => write!(f, "{b}"),
+            Literal::Number(n) => write!(f, "{n}"),
+            Literal::Str(s) => write!(f, "\"{s}\""),
+            Literal::Direction(d) => write!(f, "{}", direction_name(*d)),
+        }
+    }
+}
+
+pub fn direction_name(dir: Direction) -> &'static str {
+    match dir {
+        Direction::North => "north",
+        Direction::South => "south",
+        Direction::East => "east",
+        Direction::West => "west",
+        Direction::NorthWest => "northwest",
+        Direction::NorthEast => "northeast",
+        Direction::SouthWest => "southwest",
+        Direction::SouthEast => "southeast",
+    }
+}
+
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter<'_>, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Assign { name, value, .. } => write!(f, "{} = {value}", name.lexeme),
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            }
+            | Expr::Logical {
+                left,
+                operator,
+                right,
+            } => write!(f, "{left} {} {right}", operator.lexeme),
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                write!(f, "{callee}(")?;
+                write_list(f, arguments)?;
+                write!(f, ")")
+            }
+            Expr::Get { object, name } => write!(f, "{object}.{}", name.lexeme),
+            Expr::Grouping(expr) => write!(f, "({expr})"),
+            Expr::Index { object, index, .. } => write!(f, "{object}[{index}]"),
+            Expr::List { elements, .. } => {
+                write!(f, "[")?;
+                write_list(f, elements)?;
+                write!(f, "]")
+            }
+            Expr::Literal { value, .. } => write!(f, "{value}"),
+            Expr::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => write!(f, "{object}[{index}] = {value}"),
+            Expr::Unary { operator, right } => write!(f, "{}{right}", operator.lexeme),
+            Expr::Variable { name, .. } => write!(f, "{}", name.lexeme),
+        }
+    }
+}
+
+const INDENT: &str = "    ";
+
+impl Stmt {
+    fn write_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let pad = INDENT.repeat(depth);
+        match self {
+            Stmt::Block(statements) => {
+                write!(f, "{pad}")?;
+                write_block(f, statements, depth)?;
+                writeln!(f)
+            }
+            Stmt::Expression(expr) => writeln!(f, "{pad}{expr};"),
+            Stmt::Function(decl) => {
+                write!(f, "{pad}fn {}(", decl.name.lexeme)?;
+                let params: Vec<&str> = decl.params.iter().map(|p| p.lexeme.as_str()).collect();
+                write_list(f, &params)?;
+                write!(f, ") ")?;
+                write_block(f, &decl.body, depth)?;
+                writeln!(f)
+            }
+            Stmt::If { .. } => {
+                write!(f, "{pad}")?;
+                self.write_if(f, depth)?;
+                writeln!(f)
+            }
+            Stmt::Import { path, .. } => writeln!(f, "{pad}import \"{path}\";"),
+            Stmt::Let { name, initializer } => match initializer {
+                Some(value) => writeln!(f, "{pad}let {} = {value};", name.lexeme),
+                None => writeln!(f, "{pad}let {};", name.lexeme),
+            },
+            Stmt::Return { value, .. } => match value {
+                Some(value) => writeln!(f, "{pad}return {value};"),
+                None => writeln!(f, "{pad}return;"),
+            },
+            Stmt::While { condition, body } => {
+                write!(f, "{pad}while {condition} ")?;
+                write_branch(f, body, depth)?;
+                writeln!(f)
+            }
+            Stmt::Yield { value, .. } => match value {
+                Some(value) => writeln!(f, "{pad}yield {value};"),
+                None => writeln!(f, "{pad}yield;"),
+            },
+        }
+    }
+
+    // `if` chains are written without a trailing newline so that an
+    // `else if` can continue on the same line as the closing brace.
+    fn write_if(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        if let Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        } = self
+        {
+            write!(f, "if {condition} ")?;
+            write_branch(f, then_branch, depth)?;
+            match else_branch.as_deref() {
+                Some(chained @ Stmt::If { .. }) => {
+                    write!(f, " else ")?;
+                    chained.write_if(f, depth)?;
+                }
+                Some(branch) => {
+                    write!(f, " else ")?;
+                    write_branch(f, branch, depth)?;
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_branch(f: &mut fmt::Formatter<'_>, branch: &Stmt, depth: usize) -> fmt::Result {
+    match branch {
+        Stmt::Block(statements) => write_block(f, statements, depth),
+        other => write_block(f, std::slice::from_ref(other), depth),
+    }
+}
+
+fn write_block(f: &mut fmt::Formatter<'_>, statements: &[Stmt], depth: usize) -> fmt::Result {
+    writeln!(f, "{{")?;
+    for stmt in statements {
+        stmt.write_indented(f, depth + 1)?;
+    }
+    write!(f, "{}}}", INDENT.repeat(depth))
+}
+
+/// Statements print back out as source text, indented four spaces per block.
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_indented(f, 0)
+    }
+}
+
+/// Print a whole program back out as source text.
+pub fn to_source(statements: &[Stmt]) -> String {
+    statements.iter().map(|stmt| stmt.to_string()).collect()
+}
@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use crate::script::ops::BinaryOp;
+
+macro_rules! opcodes {
+    ($($name:ident),* $(,)?) => {
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($name),*
+        }
+
+        impl OpCode {
+            const ALL: &'static [OpCode] = &[$(OpCode::$name),*];
+
+            pub fn from_byte(byte: u8) -> Option<Self> {
+                Self::ALL.get(byte as usize).copied()
+            }
+        }
+    };
+}
+
+opcodes! {
+    Constant,
+    Nil,
+    True,
+    False,
+    Direction,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetIndex,
+    SetIndex,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+    Not,
+    Negate,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    CloseUpvalue,
+    List,
+    Return,
+    Yield,
+    // only the optimiser writes these
+    ReturnNil,
+    StoreLocal,
+    StoreGlobal,
+    PopN,
+}
+
+impl OpCode {
+    pub fn binary_op(self) -> Option<BinaryOp> {
+        Some(match self {
+            OpCode::Add => BinaryOp::Add,
+            OpCode::Subtract => BinaryOp::Subtract,
+            OpCode::Multiply => BinaryOp::Multiply,
+            OpCode::Divide => BinaryOp::Divide,
+            OpCode::Modulo => BinaryOp::Modulo,
+            OpCode::Greater => BinaryOp::Greater,
+            OpCode::GreaterEqual => BinaryOp::GreaterEqual,
+            OpCode::Less => BinaryOp::Less,
+            OpCode::LessEqual => BinaryOp::LessEqual,
+            OpCode::Equal => BinaryOp::Equal,
+            OpCode::NotEqual => BinaryOp::NotEqual,
+            _ => return None,
+        })
+    }
+
+    pub fn from_binary_op(op: BinaryOp) -> Self {
+        match op {
+            BinaryOp::Add => OpCode::Add,
+            BinaryOp::Subtract => OpCode::Subtract,
+            BinaryOp::Multiply => OpCode::Multiply,
+            BinaryOp::Divide => OpCode::Divide,
+            BinaryOp::Modulo => OpCode::Modulo,
+            BinaryOp::Greater => OpCode::Greater,
+            BinaryOp::GreaterEqual => OpCode::GreaterEqual,
+            BinaryOp::Less => OpCode::Less,
+            BinaryOp::LessEqual => OpCode::LessEqual,
+            BinaryOp::Equal => OpCode::Equal,
+            BinaryOp::NotEqual => OpCode::NotEqual,
+        }
+    }
+}
+
+/// Constants live in the chunk as plain Rust data so that compiled code can
+/// be shared between mobs. Strings are interned into the heap when loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    Str(Arc<str>),
+    Function(Arc<FunctionProto>),
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub lines: Vec<usize>,
+    pub constants: Vec<Constant>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, byte: u8, line: usize) {
+        self.code.push(byte);
+        self.lines.push(line);
+    }
+
+    /// How many bytes the instruction at `offset` takes up, operands and
+    /// all.
+    pub fn instruction_len(&self, offset: usize) -> usize {
+        match OpCode::from_byte(self.code[offset]) {
+            Some(
+                OpCode::Constant
+                | OpCode::Direction
+                | OpCode::GetLocal
+                | OpCode::SetLocal
+                | OpCode::GetGlobal
+                | OpCode::DefineGlobal
+                | OpCode::SetGlobal
+                | OpCode::GetUpvalue
+                | OpCode::SetUpvalue
+                | OpCode::GetProperty
+                | OpCode::Call
+                | OpCode::List
+                | OpCode::StoreLocal
+                | OpCode::StoreGlobal
+                | OpCode::PopN,
+            ) => 2,
+            Some(OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop) => 3,
+            Some(OpCode::Closure) => match self.constants.get(self.code[offset + 1] as usize) {
+                Some(Constant::Function(function)) => 2 + 2 * function.upvalue_count,
+                _ => 2,
+            },
+            _ => 1,
+        }
+    }
+
+    /// Add `constant`, or find the same one already there. Numbers are the
+    /// same only bit for bit: `0` and `-0` compare equal, but aren't.
+    pub fn add_constant(&mut self, constant: Constant) -> usize {
+        let same = |c: &Constant| match (c, &constant) {
+            (Constant::Number(a), Constant::Number(b)) => a.to_bits() == b.to_bits(),
+            (c, constant) => c == constant,
+        };
+        if let Some(idx) = self.constants.iter().position(same) {
+            return idx;
+        }
+        self.constants.push(constant);
+        self.constants.len() - 1
+    }
+}
+
+/// Where a local variable lives, for the debugger. It is in stack slot
+/// `slot` of its call while the code offset is in `start..end`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A compiled function. The top level of a script compiles to one of these
+/// too, with no name and no parameters.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FunctionProto {
+    pub name: String,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub locals: Vec<LocalInfo>,
+}
+
+impl FunctionProto {
+    /// Bytes of code in this function and every function it defines.
+    pub fn code_size(&self) -> usize {
+        let inner: usize = self
+            .chunk
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                Constant::Function(function) => function.code_size(),
+                _ => 0,
+            })
+            .sum();
+        self.chunk.code.len() + inner
+    }
+}
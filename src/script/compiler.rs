@@ -0,0 +1,555 @@
+use std::sync::Arc;
+
+use crate::map::Direction;
+use crate::script::ast::*;
+use crate::script::chunk::*;
+use crate::script::error::Diagnostic;
+use crate::script::ops::BinaryOp;
+use crate::script::token::*;
+
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+struct Local {
+    name: String,
+    depth: Option<usize>,
+    is_captured: bool,
+    /// Index of the local's entry in the function's `locals`, once it has
+    /// been initialized.
+    info: Option<usize>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct UpvalueRef {
+    index: u8,
+    is_local: bool,
+}
+
+/// Per-function compilation state. Compiling a nested function pushes a new
+/// one of these; finishing it pops back to the enclosing function.
+struct FunctionState {
+    proto: FunctionProto,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(name: &str, arity: usize, scope_depth: usize) -> Self {
+        Self {
+            proto: FunctionProto {
+                name: name.to_string(),
+                arity,
+                ..Default::default()
+            },
+            // slot zero holds the function being called
+            locals: vec![Local {
+                name: String::new(),
+                depth: Some(0),
+                is_captured: false,
+                info: None,
+            }],
+            upvalues: Vec::new(),
+            scope_depth,
+        }
+    }
+}
+
+/// Compiles a parsed script to bytecode in a single walk over the AST.
+pub struct Compiler {
+    functions: Vec<FunctionState>,
+    errors: Vec<Diagnostic>,
+    span: Span,
+}
+
+pub fn compile(statements: &[Stmt]) -> Result<Arc<FunctionProto>, Vec<Diagnostic>> {
+    let mut compiler = Compiler {
+        functions: vec![FunctionState::new("", 0, 0)],
+        errors: Vec::new(),
+        span: Span::default(),
+    };
+
+    for stmt in statements {
+        compiler.statement(stmt);
+    }
+    compiler.emit_op(OpCode::Nil);
+    compiler.emit_op(OpCode::Return);
+
+    if compiler.errors.is_empty() {
+        let state = compiler.functions.pop().unwrap();
+        Ok(Arc::new(state.proto))
+    } else {
+        Err(compiler.errors)
+    }
+}
+
+impl Compiler {
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().proto.chunk
+    }
+
+    fn error(&mut self, message: &str) {
+        self.errors.push(Diagnostic::new(self.span, message));
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.span.line;
+        self.chunk().write(byte, line);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit_byte(op as u8);
+    }
+
+    fn emit_op_arg(&mut self, op: OpCode, arg: u8) {
+        self.emit_op(op);
+        self.emit_byte(arg);
+    }
+
+    fn make_constant(&mut self, constant: Constant) -> u8 {
+        let idx = self.chunk().add_constant(constant);
+        if idx > u8::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        idx as u8
+    }
+
+    fn emit_constant(&mut self, constant: Constant) {
+        let idx = self.make_constant(constant);
+        self.emit_op_arg(OpCode::Constant, idx);
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> u8 {
+        self.make_constant(Constant::Str(name.into()))
+    }
+
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op(op);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+        let code = &mut self.chunk().code;
+        code[offset] = ((jump >> 8) & 0xff) as u8;
+        code[offset + 1] = (jump & 0xff) as u8;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+        self.emit_byte(((offset >> 8) & 0xff) as u8);
+        self.emit_byte((offset & 0xff) as u8);
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current().scope_depth -= 1;
+        loop {
+            let state = self.current();
+            let captured = match state.locals.last() {
+                Some(Local {
+                    depth: Some(depth),
+                    is_captured,
+                    ..
+                }) if *depth > state.scope_depth => *is_captured,
+                _ => break,
+            };
+            if let Some(info) = state.locals.pop().and_then(|local| local.info) {
+                let end = state.proto.chunk.code.len();
+                state.proto.locals[info].end = end;
+            }
+            self.emit_op(if captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            });
+        }
+    }
+
+    fn add_local(&mut self, name: &str) {
+        if self.current().locals.len() >= MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        self.current().locals.push(Local {
+            name: name.to_string(),
+            depth: None,
+            is_captured: false,
+            info: None,
+        });
+    }
+
+    fn declare_variable(&mut self, name: &str) {
+        let state = self.current();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        let depth = state.scope_depth;
+        let duplicate = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d >= depth))
+            .any(|local| local.name == name);
+        if duplicate {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.current();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        let slot = state.locals.len() - 1;
+        let start = state.proto.chunk.code.len();
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(depth);
+            local.info = Some(state.proto.locals.len());
+            state.proto.locals.push(LocalInfo {
+                name: local.name.clone(),
+                slot,
+                start,
+                // until the end of the function, unless a scope ends first
+                end: usize::MAX,
+            });
+        }
+    }
+
+    fn define_variable(&mut self, name: &str) {
+        if self.current().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        let global = self.identifier_constant(name);
+        self.emit_op_arg(OpCode::DefineGlobal, global);
+    }
+
+    fn resolve_local(&mut self, level: usize, name: &str) -> Option<u8> {
+        let (slot, initialized) = self.functions[level]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+            .map(|(slot, local)| (slot, local.depth.is_some()))?;
+
+        if !initialized {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn add_upvalue(&mut self, level: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = UpvalueRef { index, is_local };
+        let state = &mut self.functions[level];
+        if let Some(existing) = state.upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+        if state.upvalues.len() >= MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        state.upvalues.push(upvalue);
+        state.proto.upvalue_count = state.upvalues.len();
+        (state.upvalues.len() - 1) as u8
+    }
+
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<u8> {
+        if level == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(level - 1, name) {
+            self.functions[level - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(level, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(level - 1, name)?;
+        Some(self.add_upvalue(level, upvalue, false))
+    }
+
+    fn named_variable(&mut self, name: &str, assign: Option<&Expr>) {
+        let level = self.functions.len() - 1;
+        let (get, set, arg) = if let Some(slot) = self.resolve_local(level, name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(slot) = self.resolve_upvalue(level, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, slot)
+        } else {
+            let global = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, global)
+        };
+
+        match assign {
+            Some(value) => {
+                self.expression(value);
+                self.emit_op_arg(set, arg);
+            }
+            None => self.emit_op_arg(get, arg),
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                for stmt in statements {
+                    self.statement(stmt);
+                }
+                self.end_scope();
+            }
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                self.emit_op(OpCode::Pop);
+            }
+            Stmt::Function(decl) => {
+                self.span = decl.name.span;
+                self.declare_variable(&decl.name.lexeme);
+                // a function may refer to itself, so it's ready before its body
+                self.mark_initialized();
+                self.function(decl);
+                self.define_variable(&decl.name.lexeme);
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch);
+
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit_op(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            Stmt::Let { name, initializer } => {
+                self.span = name.span;
+                self.declare_variable(&name.lexeme);
+                match initializer {
+                    Some(expr) => self.expression(expr),
+                    None => self.emit_op(OpCode::Nil),
+                }
+                self.span = name.span;
+                self.define_variable(&name.lexeme);
+            }
+            Stmt::Return { keyword, value } => {
+                self.span = keyword.span;
+                if self.functions.len() == 1 {
+                    self.error("Can't return from top-level code.");
+                }
+                match value {
+                    Some(expr) => self.expression(expr),
+                    None => self.emit_op(OpCode::Nil),
+                }
+                self.span = keyword.span;
+                self.emit_op(OpCode::Return);
+            }
+            Stmt::While { condition, body } => {
+                let loop_start = self.chunk().code.len();
+                self.expression(condition);
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(body);
+                self.emit_loop(loop_start);
+
+                self.patch_jump(exit_jump);
+                self.emit_op(OpCode::Pop);
+            }
+            // the engine binds imported modules before the script runs
+            Stmt::Import { .. } => {}
+            Stmt::Yield { keyword, value } => {
+                self.span = keyword.span;
+                if self.functions.len() == 1 {
+                    self.error("Can't yield from top-level code.");
+                }
+                match value {
+                    Some(expr) => self.expression(expr),
+                    None => self.emit_op(OpCode::Nil),
+                }
+                self.span = keyword.span;
+                self.emit_op(OpCode::Yield);
+            }
+        }
+    }
+
+    fn function(&mut self, decl: &FunctionDecl) {
+        let depth = self.current().scope_depth;
+        self.functions.push(FunctionState::new(
+            &decl.name.lexeme,
+            decl.params.len(),
+            depth + 1,
+        ));
+
+        for param in &decl.params {
+            self.span = param.span;
+            self.declare_variable(&param.lexeme);
+            self.mark_initialized();
+        }
+        for stmt in &decl.body {
+            self.statement(stmt);
+        }
+        self.emit_op(OpCode::Nil);
+        self.emit_op(OpCode::Return);
+
+        let state = self.functions.pop().unwrap();
+        self.span = decl.name.span;
+        let constant = self.make_constant(Constant::Function(Arc::new(state.proto)));
+        self.emit_op_arg(OpCode::Closure, constant);
+        for upvalue in state.upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        self.span = expr.span();
+        match expr {
+            Expr::Assign { name, value, .. } => self.named_variable(&name.lexeme, Some(value)),
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                self.span = operator.span;
+                let op = BinaryOp::from_token(operator.ttype).unwrap();
+                self.emit_op(OpCode::from_binary_op(op));
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                self.expression(callee);
+                for arg in arguments {
+                    self.expression(arg);
+                }
+                self.span = paren.span;
+                self.emit_op_arg(OpCode::Call, arguments.len() as u8);
+            }
+            Expr::Get { object, name } => {
+                self.expression(object);
+                self.span = name.span;
+                let name = self.identifier_constant(&name.lexeme);
+                self.emit_op_arg(OpCode::GetProperty, name);
+            }
+            Expr::Grouping(expr) => self.expression(expr),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                self.expression(object);
+                self.expression(index);
+                self.span = bracket.span;
+                self.emit_op(OpCode::GetIndex);
+            }
+            Expr::List { bracket, elements } => {
+                for element in elements {
+                    self.expression(element);
+                }
+                self.span = bracket.span;
+                self.emit_op_arg(OpCode::List, elements.len() as u8);
+            }
+            Expr::Literal { value, .. } => match value {
+                Literal::Nil => self.emit_op(OpCode::Nil),
+                Literal::Bool(true) => self.emit_op(OpCode::True),
+                Literal::Bool(false) => self.emit_op(OpCode::False),
+                Literal::Number(n) => self.emit_constant(Constant::Number(*n)),
+                Literal::Str(s) => self.emit_constant(Constant::Str(s.as_str().into())),
+                Literal::Direction(d) => self.emit_op_arg(OpCode::Direction, direction_to_byte(*d)),
+            },
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                self.expression(left);
+                self.span = operator.span;
+                if operator.ttype == TokenType::And {
+                    let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    self.emit_op(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                } else {
+                    let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    let end_jump = self.emit_jump(OpCode::Jump);
+                    self.patch_jump(else_jump);
+                    self.emit_op(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                }
+            }
+            Expr::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+                self.span = bracket.span;
+                self.emit_op(OpCode::SetIndex);
+            }
+            Expr::Unary { operator, right } => {
+                self.expression(right);
+                self.span = operator.span;
+                if operator.ttype == TokenType::Bang {
+                    self.emit_op(OpCode::Not);
+                } else {
+                    self.emit_op(OpCode::Negate);
+                }
+            }
+            Expr::Variable { name, .. } => self.named_variable(&name.lexeme, None),
+        }
+    }
+}
+
+const DIRECTIONS: [Direction; 8] = [
+    Direction::North,
+    Direction::South,
+    Direction::East,
+    Direction::West,
+    Direction::NorthWest,
+    Direction::NorthEast,
+    Direction::SouthWest,
+    Direction::SouthEast,
+];
+
+pub fn direction_to_byte(dir: Direction) -> u8 {
+    DIRECTIONS.iter().position(|d| *d == dir).unwrap() as u8
+}
+
+pub fn direction_from_byte(byte: u8) -> Direction {
+    DIRECTIONS[byte as usize]
+}
@@ -0,0 +1,59 @@
+/// Small programs that between them use every part of the language. Each
+/// defines `test()`, whose result (or error) the tests compare.
+pub const PROGRAMS: &[(&str, &str)] = &[
+    ("arithmetic", "fn test() { return 1 + 2 * 3 - 8 / 4 % 3; }"),
+    ("comparison", "fn test() { return [1 < 2, 2 <= 2, 3 > 4, 4 >= 5, 1 == 1, 1 != 1]; }"),
+    ("negation", "fn test() { return [-3, !nil, !0, !false]; }"),
+    ("strings", r#"fn test() { return "rat" + "s" == "rats"; }"#),
+    ("logic", "fn test() { return [nil or 2, 1 and nil, false or false, 1 and 2]; }"),
+    (
+        "lists",
+        "fn test() { let xs = [1, [2, 3]]; xs[0] = xs[1][1] + 1; return xs; }",
+    ),
+    (
+        "globals",
+        "let count = 0; fn bump() { count = count + 1; } fn test() { bump(); bump(); return count; }",
+    ),
+    (
+        "while",
+        "fn test() { let i = 0; let sum = 0; while i < 10 { sum = sum + i; i = i + 1; } return sum; }",
+    ),
+    (
+        "if chains",
+        "fn pick(n) { if n < 0 { return \"neg\"; } else if n == 0 { return \"zero\"; } else { return \"pos\"; } }
+         fn test() { return [pick(-1), pick(0), pick(1)]; }",
+    ),
+    (
+        "recursion",
+        "fn fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); } fn test() { return fib(10); }",
+    ),
+    (
+        "closures",
+        "fn counter() { let n = 0; fn next() { n = n + 1; return n; } return next; }
+         fn test() { let a = counter(); let b = counter(); a(); a(); return [a(), b()]; }",
+    ),
+    (
+        "shadowing",
+        "let x = \"global\"; fn test() { let x = \"outer\"; { let x = \"inner\"; } return x; }",
+    ),
+    ("no return", "fn test() { let x = 1; }"),
+    (
+        "intents",
+        r#"fn test() { return [move(north), wait(), say("hi")]; }"#,
+    ),
+    ("directions", "fn test() { return [north, southwest]; }"),
+    (
+        "negative zero",
+        "fn test() { let a = 0; let b = -0; return [1 / a, 1 / b]; }",
+    ),
+    ("runtime error", "fn test() { return nil + 1; }"),
+    ("bad call", "fn test() { let x = 1; return x(); }"),
+    ("arity", "fn f(a) { return a; } fn test() { return f(1, 2); }"),
+    ("bad index", "fn test() { return [1, 2][5]; }"),
+    ("defined later", "fn test() { return later; } let later = 1;"),
+    ("missing hook", "fn other() { return 1; }"),
+    (
+        "stack overflow",
+        "fn down(n) { return down(n + 1); } fn test() { return down(0); }",
+    ),
+];
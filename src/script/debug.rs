@@ -0,0 +1,51 @@
+use std::collections::BTreeSet;
+
+use crate::script::value::Intent;
+
+/// How many intents the debugger keeps a record of.
+const INTENT_LOG: usize = 20;
+
+/// Where the VM should stop: on any of `lines`, or on every new line while
+/// `stepping`.
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    pub lines: BTreeSet<usize>,
+    pub stepping: bool,
+}
+
+/// Where a script stopped, and what its locals held at the time. Values are
+/// already formatted, so nothing on the heap needs keeping alive for them.
+#[derive(Debug, Clone)]
+pub struct Pause {
+    pub line: usize,
+    pub function: String,
+    pub locals: Vec<(String, String)>,
+}
+
+/// A mob's script with the debugger attached. Breakpoints only apply to
+/// `on_turn`, and only on the bytecode backend.
+#[derive(Debug, Default)]
+pub struct DebugState {
+    pub breakpoints: Breakpoints,
+    /// Where `on_turn` is stopped, if it is. The call stays suspended until
+    /// the debugger carries on with it.
+    pub paused: Option<Pause>,
+    /// Set when the script stops, and cleared once the debugger has shown
+    /// where.
+    pub stopped: bool,
+    /// The intents `on_turn` has produced, newest last.
+    pub intents: Vec<String>,
+    /// An intent the debugger finished working out after the mob's turn had
+    /// already gone by. The mob carries it out next turn instead of running
+    /// its script.
+    pub held: Option<Intent>,
+}
+
+impl DebugState {
+    pub fn log_intent(&mut self, intent: String) {
+        self.intents.push(intent);
+        if self.intents.len() > INTENT_LOG {
+            self.intents.remove(0);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use crate::script::ast::direction_name;
+use crate::script::chunk::*;
+use crate::script::compiler::direction_from_byte;
+
+/// Render a compiled function as text, one instruction to a line, followed
+/// by every function it defines. Each line shows the offset of the
+/// instruction, the source line it came from (or `|` if that is the same
+/// as the line above), the opcode, and its operands.
+pub fn disassemble(function: &FunctionProto, name: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    disassemble_into(function, name, &mut lines);
+    lines
+}
+
+fn disassemble_into(function: &FunctionProto, name: &str, lines: &mut Vec<String>) {
+    let chunk = &function.chunk;
+    lines.push(format!("== {name} =="));
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (text, next) = instruction(chunk, offset);
+        let line = if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+            "   |".to_string()
+        } else {
+            format!("{:4}", chunk.lines[offset])
+        };
+        lines.push(format!("{offset:04} {line} {text}"));
+        offset = next;
+    }
+
+    for constant in &chunk.constants {
+        if let Constant::Function(inner) = constant {
+            lines.push(String::new());
+            disassemble_into(inner, &format!("fn {}", inner.name), lines);
+        }
+    }
+}
+
+/// Describe the instruction at `offset`, and say where the next one starts.
+fn instruction(chunk: &Chunk, offset: usize) -> (String, usize) {
+    let byte = chunk.code[offset];
+    let op = match OpCode::from_byte(byte) {
+        Some(op) => op,
+        None => return (format!("<unknown opcode {byte}>"), offset + 1),
+    };
+    let name = format!("{op:?}");
+    let arg = chunk.code.get(offset + 1).copied().unwrap_or(0);
+
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::StoreGlobal
+        | OpCode::GetProperty => (
+            format!("{name:<12} {arg:3} {}", constant(chunk, arg)),
+            offset + 2,
+        ),
+        OpCode::Direction => (
+            format!(
+                "{name:<12} {arg:3} {}",
+                direction_name(direction_from_byte(arg))
+            ),
+            offset + 2,
+        ),
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call
+        | OpCode::List
+        | OpCode::StoreLocal
+        | OpCode::PopN => (format!("{name:<12} {arg:3}"), offset + 2),
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let jump = ((arg as usize) << 8) | chunk.code[offset + 2] as usize;
+            let target = if op == OpCode::Loop {
+                offset + 3 - jump
+            } else {
+                offset + 3 + jump
+            };
+            (format!("{name:<12} -> {target:04}"), offset + 3)
+        }
+        OpCode::Closure => {
+            let mut text = format!("{name:<12} {arg:3} {}", constant(chunk, arg));
+            let mut next = offset + 2;
+            if let Some(Constant::Function(function)) = chunk.constants.get(arg as usize) {
+                for _ in 0..function.upvalue_count {
+                    let kind = if chunk.code[next] == 1 {
+                        "local"
+                    } else {
+                        "upvalue"
+                    };
+                    text.push_str(&format!(", {kind} {}", chunk.code[next + 1]));
+                    next += 2;
+                }
+            }
+            (text, next)
+        }
+        _ => (name, offset + 1),
+    }
+}
+
+fn constant(chunk: &Chunk, idx: u8) -> String {
+    match chunk.constants.get(idx as usize) {
+        Some(Constant::Number(n)) => format!("'{n}'"),
+        Some(Constant::Str(s)) => format!("'{s}'"),
+        Some(Constant::Function(function)) => format!("<fn {}>", function.name),
+        None => "<missing constant>".to_string(),
+    }
+}
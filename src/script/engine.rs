@@ -0,0 +1,639 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bevy_ecs::prelude::*;
+use instant::Instant;
+
+use crate::script::api::GameView;
+use crate::script::ast::Stmt;
+use crate::script::chunk::FunctionProto;
+use crate::script::compiler;
+use crate::script::debug::*;
+use crate::script::disasm::disassemble;
+use crate::script::error::*;
+use crate::script::heap::*;
+use crate::script::interpreter::Interpreter;
+use crate::script::library::load_module;
+use crate::script::optimise;
+use crate::script::profile::Counters;
+use crate::script::value::*;
+use crate::script::vm::{Coroutine, Vm};
+
+/// A parsed and compiled script, ready to be attached to any number of mobs.
+/// It carries both the syntax tree and the bytecode so either backend can
+/// run it, along with every module it imports.
+#[derive(Debug)]
+pub struct Program {
+    pub name: String,
+    pub source: String,
+    pub statements: Vec<Stmt>,
+    pub function: Arc<FunctionProto>,
+    pub imports: Vec<Import>,
+}
+
+/// A module a program imports, and the name it goes by there.
+#[derive(Debug)]
+pub struct Import {
+    pub name: String,
+    pub module: Arc<Program>,
+}
+
+impl Program {
+    pub fn new(name: &str, source: &str) -> Result<Self, Vec<Diagnostic>> {
+        Self::with_loading(name, source, true, &mut Vec::new())
+    }
+
+    /// Compile a program exactly as written, without the optimiser. Its
+    /// imports are shared, and so are optimised as usual.
+    pub fn unoptimised(name: &str, source: &str) -> Result<Self, Vec<Diagnostic>> {
+        Self::with_loading(name, source, false, &mut Vec::new())
+    }
+
+    /// Compile a program whose imports may be part way through loading
+    /// themselves; see `load_module`.
+    pub(super) fn with_loading(
+        name: &str,
+        source: &str,
+        optimise: bool,
+        loading: &mut Vec<String>,
+    ) -> Result<Self, Vec<Diagnostic>> {
+        let mut statements = crate::script::parse(source, &[])?;
+        if optimise {
+            optimise::fold(&mut statements);
+        }
+        let mut function = compiler::compile(&statements)?;
+        if optimise {
+            optimise::peephole(Arc::make_mut(&mut function));
+        }
+
+        let mut imports = Vec::new();
+        let mut diagnostics = Vec::new();
+        for stmt in &statements {
+            if let Stmt::Import {
+                keyword,
+                path,
+                name,
+            } = stmt
+            {
+                match load_module(path, loading) {
+                    Ok(module) => imports.push(Import {
+                        name: name.clone(),
+                        module,
+                    }),
+                    Err(errors) => diagnostics
+                        .extend(errors.into_iter().map(|e| Diagnostic::new(keyword.span, e))),
+                }
+            }
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            source: source.to_string(),
+            statements,
+            function,
+            imports,
+        })
+    }
+
+    /// The program's bytecode as text, for a developer or a scanner.
+    pub fn disassemble(&self) -> Vec<String> {
+        disassemble(&self.function, &self.name)
+    }
+
+    /// How many bytes of bytecode the program compiled to.
+    pub fn code_size(&self) -> usize {
+        self.function.code_size()
+    }
+}
+
+/// How many instructions a script may run each time the game calls it.
+pub const DEFAULT_FUEL: u32 = 2000;
+
+/// Gives a mob a script to run instead of the default random walk. The
+/// script's top level runs once, the first time it is called; after that
+/// the game calls whichever hooks it defines:
+///
+/// * `on_spawn()` once, when the script is given to the mob
+/// * `on_turn()` every turn, returning what the mob should do
+/// * `on_hit(source, amount)` when the mob takes damage
+/// * `on_death()` when the mob is about to die
+/// * `on_see_player()` when the player comes into view
+///
+/// `on_turn` may also `yield` an intent instead of returning it. The mob
+/// carries that out, and next turn the script picks up from just after the
+/// `yield` rather than starting `on_turn` again. This needs the bytecode
+/// backend, as does the debugger.
+#[derive(Component)]
+pub struct Script {
+    pub program: Arc<Program>,
+    pub fuel: u32,
+    /// Whether the player was in view last turn, so that `on_see_player`
+    /// only fires when they first show up.
+    pub saw_player: bool,
+    globals: Option<ObjRef>,
+    /// The `on_turn` call that yielded last turn, if there is one.
+    suspended: Option<Coroutine>,
+    pub debug: Option<DebugState>,
+    /// What the script has cost since the profiler last took it.
+    pub cost: Counters,
+}
+
+impl Script {
+    pub fn new(program: Arc<Program>) -> Self {
+        Self {
+            program,
+            fuel: DEFAULT_FUEL,
+            saw_player: false,
+            globals: None,
+            suspended: None,
+            debug: None,
+            cost: Counters::default(),
+        }
+    }
+
+    /// Give the script a bigger (or smaller) instruction budget, so that
+    /// bosses can think harder than rats.
+    pub fn with_fuel(mut self, fuel: u32) -> Self {
+        self.fuel = fuel;
+        self
+    }
+
+    /// Every heap value the script holds on to between turns.
+    pub fn roots(&self) -> impl Iterator<Item = Value> + '_ {
+        let globals = self.globals.map(Value::Obj);
+        let suspended = self.suspended.iter().flat_map(Coroutine::roots);
+        let held = self.debug.iter().filter_map(|debug| debug.held);
+        globals
+            .into_iter()
+            .chain(suspended)
+            .chain(held.map(Value::Intent))
+    }
+
+    /// The breakpoints to run hook `name` with. Only `on_turn` can stop at
+    /// them, since only `on_turn` can be carried on with later.
+    fn breakpoints(&self, name: &str) -> Option<Breakpoints> {
+        match &self.debug {
+            Some(debug) if name == "on_turn" => Some(debug.breakpoints.clone()),
+            _ => None,
+        }
+    }
+
+    fn note_pause(&mut self, name: &str, pause: Option<Pause>) {
+        if let Some(debug) = &mut self.debug {
+            if name == "on_turn" {
+                debug.stopped |= pause.is_some();
+                debug.paused = pause;
+            }
+        }
+    }
+}
+
+/// Which implementation runs the scripts. Both give the same results; the
+/// tree-walker is simpler, the bytecode VM is faster. The exceptions are
+/// `yield` and breakpoints, which only the VM has, and fuel, which the
+/// tree-walker counts by syntax node rather than by instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Backend {
+    TreeWalker,
+    Bytecode,
+}
+
+/// Runs mob scripts. There is one of these in the world, and every script
+/// shares its heap.
+pub struct ScriptEngine {
+    pub heap: Heap,
+    pub backend: Backend,
+    /// Each module that has been imported, by path. A module's top level
+    /// runs once, the first time anything imports it, and every script
+    /// that imports it after that shares the result. The console gets
+    /// modules of its own, since it runs on the tree-walker and can't call
+    /// bytecode functions.
+    modules: HashMap<(Backend, String), ObjRef>,
+}
+
+impl Default for ScriptEngine {
+    fn default() -> Self {
+        Self::new(Backend::Bytecode)
+    }
+}
+
+impl ScriptEngine {
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            heap: Heap::default(),
+            backend,
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Every module imported so far, which stay loaded for good.
+    pub fn roots(&self) -> impl Iterator<Item = Value> + '_ {
+        self.modules.values().map(|module| Value::Obj(*module))
+    }
+
+    /// Run the top level of `program` in the scope `globals`, after binding
+    /// the modules it imports.
+    fn run_top_level(
+        &mut self,
+        backend: Backend,
+        program: &Program,
+        globals: ObjRef,
+        game: &mut GameView,
+        fuel: u32,
+    ) -> Result<(), RuntimeError> {
+        for import in &program.imports {
+            self.import(backend, globals, &import.name, &import.module, game)?;
+        }
+        match backend {
+            Backend::TreeWalker => Interpreter::new(&mut self.heap, game, globals, fuel)
+                .execute_program(&program.statements),
+            Backend::Bytecode => {
+                Vm::new(&mut self.heap, game, globals, fuel).run_script(program.function.clone())
+            }
+        }
+    }
+
+    /// Define `name` in the scope `globals` as the module `program`.
+    fn import(
+        &mut self,
+        backend: Backend,
+        globals: ObjRef,
+        name: &str,
+        program: &Program,
+        game: &mut GameView,
+    ) -> Result<(), RuntimeError> {
+        let module = self.module(backend, program, game)?;
+        self.heap
+            .env_mut(globals)
+            .values
+            .insert(name.to_string(), Value::Obj(module));
+        Ok(())
+    }
+
+    /// The loaded module for `program`, running its top level if this is
+    /// the first time it has been imported.
+    fn module(
+        &mut self,
+        backend: Backend,
+        program: &Program,
+        game: &mut GameView,
+    ) -> Result<ObjRef, RuntimeError> {
+        let key = (backend, program.name.clone());
+        if let Some(module) = self.modules.get(&key) {
+            return Ok(*module);
+        }
+
+        let globals = self.new_globals();
+        self.run_top_level(backend, program, globals, game, DEFAULT_FUEL)?;
+        let module = self.heap.alloc(Obj::Module(Module {
+            path: program.name.clone(),
+            globals,
+        }));
+        self.heap.root(Value::Obj(module));
+        self.modules.insert(key, module);
+        Ok(module)
+    }
+
+    fn globals(
+        &mut self,
+        script: &mut Script,
+        game: &mut GameView,
+    ) -> Result<ObjRef, RuntimeError> {
+        if let Some(globals) = script.globals {
+            return Ok(globals);
+        }
+
+        let globals = self.new_globals();
+        self.run_top_level(self.backend, &script.program, globals, game, script.fuel)?;
+        script.globals = Some(globals);
+        Ok(globals)
+    }
+
+    /// Call a function the script defines, if it defines one. The script
+    /// sees the world through `game`.
+    pub fn call(
+        &mut self,
+        script: &mut Script,
+        game: &mut GameView,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Option<Value>, RuntimeError> {
+        let start = self.start_charging();
+        let result = self.call_uncharged(script, game, name, args);
+        self.charge(script, start);
+        result
+    }
+
+    fn call_uncharged(
+        &mut self,
+        script: &mut Script,
+        game: &mut GameView,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Option<Value>, RuntimeError> {
+        let globals = self.globals(script, game)?;
+        match self.backend {
+            Backend::TreeWalker => {
+                Interpreter::new(&mut self.heap, game, globals, script.fuel).call_global(name, args)
+            }
+            Backend::Bytecode => {
+                let mut vm = Vm::new(&mut self.heap, game, globals, script.fuel)
+                    .with_breakpoints(script.breakpoints(name));
+                let result = vm.call_global(name, args)?;
+                let (line, pause, suspended) = (vm.line(), vm.pause(), vm.suspend());
+                self.park(script, name, line, suspended)?;
+                script.note_pause(name, pause);
+                Ok(result)
+            }
+        }
+    }
+
+    /// Carry on with the `on_turn` call that yielded last turn.
+    fn resume(
+        &mut self,
+        script: &mut Script,
+        game: &mut GameView,
+        coroutine: Coroutine,
+    ) -> Result<Value, RuntimeError> {
+        let start = self.start_charging();
+        let result = self.resume_uncharged(script, game, coroutine);
+        self.charge(script, start);
+        result
+    }
+
+    fn resume_uncharged(
+        &mut self,
+        script: &mut Script,
+        game: &mut GameView,
+        coroutine: Coroutine,
+    ) -> Result<Value, RuntimeError> {
+        let globals = self.globals(script, game)?;
+        let mut vm = Vm::new(&mut self.heap, game, globals, script.fuel)
+            .with_breakpoints(script.breakpoints("on_turn"));
+        let result = vm.resume(coroutine)?;
+        let (line, pause, suspended) = (vm.line(), vm.pause(), vm.suspend());
+        self.park(script, "on_turn", line, suspended)?;
+        script.note_pause("on_turn", pause);
+        Ok(result)
+    }
+
+    fn start_charging(&self) -> (Instant, Counters) {
+        (Instant::now(), self.heap.counters)
+    }
+
+    /// Charge `script` for everything run since `start_charging`.
+    fn charge(&mut self, script: &mut Script, (then, counters): (Instant, Counters)) {
+        self.heap.counters.time += then.elapsed();
+        script.cost += self.heap.counters - counters;
+    }
+
+    /// If the call `name` yielded at `line`, keep it on the mob to resume
+    /// next turn. Only `on_turn` has a next time to resume in.
+    fn park(
+        &mut self,
+        script: &mut Script,
+        name: &str,
+        line: usize,
+        suspended: Option<Coroutine>,
+    ) -> Result<(), RuntimeError> {
+        match suspended {
+            Some(_) if name != "on_turn" => Err(RuntimeError::new(
+                line,
+                format!("Only on_turn() can yield, not {name}()."),
+            )),
+            Some(coroutine) => {
+                // it lives on the mob now, out of sight of the heap
+                for value in coroutine.roots() {
+                    self.heap.root(value);
+                }
+                script.suspended = Some(coroutine);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// A fresh global scope. It is rooted straight away, since whoever
+    /// asked for it is going to keep it.
+    pub fn new_globals(&mut self) -> ObjRef {
+        let globals = Interpreter::new_globals(&mut self.heap);
+        self.heap.root(Value::Obj(globals));
+        globals
+    }
+
+    /// The names defined in a global scope, so that code run in it later
+    /// can be resolved against them.
+    pub fn global_names(&self, globals: ObjRef) -> Vec<String> {
+        self.heap.env(globals).values.keys().cloned().collect()
+    }
+
+    /// Evaluate code typed at the console in the scope `globals`. Console
+    /// code always runs on the tree-walker, since it is only ever run once.
+    pub fn eval(
+        &mut self,
+        globals: ObjRef,
+        statements: &[Stmt],
+        game: &mut GameView,
+        fuel: u32,
+    ) -> Result<Option<Value>, RuntimeError> {
+        for stmt in statements {
+            if let Stmt::Import {
+                keyword,
+                path,
+                name,
+            } = stmt
+            {
+                let program = load_module(path, &mut Vec::new())
+                    .map_err(|errors| RuntimeError::new(keyword.span.line, errors.join("\n")))?;
+                self.import(Backend::TreeWalker, globals, name, &program, game)?;
+            }
+        }
+        Interpreter::new(&mut self.heap, game, globals, fuel).execute_line(statements)
+    }
+
+    /// Call one of a mob's event hooks (`on_turn`, `on_hit` and so on) and
+    /// return what it wants to do. A script that doesn't define the hook
+    /// just does nothing.
+    pub fn run_hook(
+        &mut self,
+        script: &mut Script,
+        game: &mut GameView,
+        hook: &str,
+        args: &[Value],
+    ) -> Result<Option<Intent>, RuntimeError> {
+        let result = self.call(script, game, hook, args)?;
+        self.intent(hook, result)
+    }
+
+    /// Call a device's hook and return everything it wants done. Unlike a
+    /// mob, a device can do several things at once by handing back a list
+    /// of intents.
+    pub fn run_effects(
+        &mut self,
+        script: &mut Script,
+        game: &mut GameView,
+        hook: &str,
+        args: &[Value],
+    ) -> Result<Vec<Intent>, RuntimeError> {
+        let result = self.call(script, game, hook, args)?;
+        let items = match result {
+            Some(Value::Obj(r)) => match self.heap.get(r) {
+                Obj::List(items) => items.clone(),
+                _ => vec![Value::Obj(r)],
+            },
+            other => other.into_iter().collect(),
+        };
+        let mut intents = Vec::new();
+        for item in items {
+            intents.extend(self.intent(hook, Some(item))?);
+        }
+        Ok(intents)
+    }
+
+    /// Check that a hook handed back something the mob can act on.
+    fn intent(&self, hook: &str, result: Option<Value>) -> Result<Option<Intent>, RuntimeError> {
+        match result {
+            Some(Value::Intent(intent)) => Ok(Some(intent)),
+            Some(Value::Nil) | None => Ok(None),
+            Some(other) => Err(RuntimeError::new(
+                0,
+                format!(
+                    "{hook}() must return an intent, not a {}.",
+                    other.type_name(&self.heap)
+                ),
+            )),
+        }
+    }
+
+    /// Run one turn of a mob's script and return what it wants to do. If
+    /// the last turn ended in a `yield`, this one carries on from there.
+    /// Should the resumed call fail, it is dropped and next turn starts
+    /// `on_turn` afresh.
+    pub fn run_turn(
+        &mut self,
+        script: &mut Script,
+        game: &mut GameView,
+    ) -> Result<Option<Intent>, RuntimeError> {
+        if let Some(intent) = script.debug.as_mut().and_then(|debug| debug.held.take()) {
+            return Ok(Some(intent));
+        }
+
+        let result = match script.suspended.take() {
+            Some(coroutine) => {
+                let result = self.resume(script, game, coroutine)?;
+                self.intent("on_turn", Some(result))
+            }
+            None => self.run_hook(script, game, "on_turn", &[]),
+        };
+
+        if let (Some(debug), Ok(Some(intent))) = (&mut script.debug, &result) {
+            debug.log_intent(self.heap.format(Value::Intent(*intent)));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::corpus::PROGRAMS;
+
+    /// The backends count fuel differently, the tree-walker by syntax node
+    /// and the VM by instruction, so the corpus gets plenty of it.
+    const FUEL: u32 = 100_000;
+
+    fn run(backend: Backend, source: &str, hook: &str) -> String {
+        let program = Arc::new(Program::new("test", source).unwrap());
+        let mut engine = ScriptEngine::new(backend);
+        let mut script = Script::new(program).with_fuel(FUEL);
+        let mut game = GameView::default();
+        match engine.call(&mut script, &mut game, hook, &[]) {
+            Ok(Some(value)) => engine.heap.format(value),
+            Ok(None) => "no such hook".to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn backends_agree() {
+        for (name, source) in PROGRAMS {
+            let walked = run(Backend::TreeWalker, source, "test");
+            let compiled = run(Backend::Bytecode, source, "test");
+            assert_eq!(walked, compiled, "{name}");
+        }
+    }
+
+    #[test]
+    fn backends_run_out_of_fuel_alike() {
+        let source = "fn test() { while true { } }";
+        for backend in [Backend::TreeWalker, Backend::Bytecode] {
+            assert_eq!(run(backend, source, "test"), "[line 1] Out of fuel.");
+        }
+    }
+
+    /// A native call costs the same fuel however much work it does, so the
+    /// dice a script rolls are capped instead.
+    #[test]
+    fn dice_are_capped() {
+        let source = "fn test() { return rng.roll_dice(1000000000, 6); }";
+        for backend in [Backend::TreeWalker, Backend::Bytecode] {
+            assert_eq!(
+                run(backend, source, "test"),
+                "[line 1] roll_dice() rolls at most 100 dice of up to 1000 sides."
+            );
+        }
+    }
+
+    /// `yield` only works on the bytecode backend: the tree-walker keeps
+    /// its place on the Rust stack and has nowhere to park a call. These
+    /// cases are checked on their own rather than left out of the corpus.
+    #[test]
+    fn yield_is_bytecode_only() {
+        let source = "fn on_turn() { yield move(north); yield wait(); return move(south); }";
+        let program = Arc::new(Program::new("test", source).unwrap());
+
+        let mut engine = ScriptEngine::new(Backend::Bytecode);
+        let mut script = Script::new(program.clone());
+        let mut turns = Vec::new();
+        for _ in 0..4 {
+            let intent = engine
+                .run_turn(&mut script, &mut GameView::default())
+                .unwrap()
+                .unwrap();
+            turns.push(engine.heap.format(Value::Intent(intent)));
+        }
+        assert_eq!(
+            turns,
+            ["<move north>", "<wait>", "<move south>", "<move north>"]
+        );
+
+        let mut engine = ScriptEngine::new(Backend::TreeWalker);
+        let mut script = Script::new(program);
+        let error = engine
+            .run_turn(&mut script, &mut GameView::default())
+            .unwrap_err();
+        assert_eq!(error.message, "yield needs the bytecode backend.");
+    }
+
+    /// Nothing resumes the top level, so a yield reached from it is an
+    /// error rather than a move that never happens.
+    #[test]
+    fn top_level_code_cannot_yield() {
+        let source =
+            "fn step() {\n    yield move(north);\n}\nstep();\nfn on_turn() { return wait(); }";
+        let program = Arc::new(Program::new("test", source).unwrap());
+        let mut engine = ScriptEngine::new(Backend::Bytecode);
+        let mut script = Script::new(program);
+        let error = engine
+            .run_turn(&mut script, &mut GameView::default())
+            .unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.message,
+            "Only on_turn() can yield, not top-level code."
+        );
+    }
+}
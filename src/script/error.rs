@@ -0,0 +1,67 @@
+use std::fmt;
+
+use crate::script::token::Span;
+
+/// An error found in a script before it runs: a bad character, a syntax
+/// error, and so on. Errors are handed back as values so the caller can
+/// show all of them at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new<T: ToString>(span: Span, message: T) -> Self {
+        Self {
+            span,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] Error: {}", self.span, self.message)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The script did something wrong, like adding a number to nil.
+    Runtime,
+    /// The script used up its instruction budget for this turn.
+    OutOfFuel,
+}
+
+/// An error raised while a script is running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub line: usize,
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub fn new<T: ToString>(line: usize, message: T) -> Self {
+        Self {
+            kind: ErrorKind::Runtime,
+            line,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn out_of_fuel(line: usize) -> Self {
+        Self {
+            kind: ErrorKind::OutOfFuel,
+            line,
+            message: "Out of fuel.".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
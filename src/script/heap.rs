@@ -0,0 +1,471 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::script::ast::{direction_name, FunctionDecl};
+use crate::script::chunk::FunctionProto;
+use crate::script::profile::Counters;
+use crate::script::value::{Intent, Value};
+
+/// A handle to an object on the script heap. Handles are plain indexes, so
+/// values holding them can be stored in ECS components.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+#[derive(Debug)]
+pub struct Environment {
+    pub values: HashMap<String, Value>,
+    pub enclosing: Option<ObjRef>,
+}
+
+impl Environment {
+    pub fn new(enclosing: Option<ObjRef>) -> Self {
+        Self {
+            values: HashMap::new(),
+            enclosing,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub decl: Arc<FunctionDecl>,
+    pub closure: ObjRef,
+}
+
+/// A compiled function together with the variables it captured, and the
+/// global scope it was defined in. Imported functions keep seeing their own
+/// module's globals, whoever calls them.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Arc<FunctionProto>,
+    pub upvalues: Vec<ObjRef>,
+    pub globals: ObjRef,
+}
+
+/// A captured variable. It points at a stack slot while the variable is
+/// still in scope, and holds the value itself once the scope has ended.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// An imported module: the globals its top level left behind, which a
+/// script reaches as properties, like `flee.should_flee()`.
+#[derive(Debug)]
+pub struct Module {
+    pub path: String,
+    pub globals: ObjRef,
+}
+
+#[derive(Debug)]
+pub enum Obj {
+    Str(String),
+    List(Vec<Value>),
+    Function(Function),
+    Env(Environment),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Module(Module),
+}
+
+impl Obj {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Obj::Str(_) => "string",
+            Obj::List(_) => "list",
+            Obj::Function(_) => "function",
+            Obj::Env(_) => "environment",
+            Obj::Closure(_) => "function",
+            Obj::Upvalue(_) => "upvalue",
+            Obj::Module(_) => "module",
+        }
+    }
+}
+
+/// How many objects the heap holds before the first collection.
+const FIRST_COLLECTION: usize = 1024;
+
+/// Every object a script creates lives here, until a mark-and-sweep
+/// collection finds that nothing can reach it any more. Freed slots are
+/// reused, so a handle must never outlive the object it points at.
+///
+/// Collections only happen at safe points: between systems, where every
+/// component holding script values is rooted, and between instructions of
+/// the VM, which roots its own stack. The VM can't see the world, so it
+/// goes by the roots from the last collection between systems, plus
+/// anything handed to `root` since. Whatever puts a value somewhere outside
+/// a running script must root it that way.
+pub struct Heap {
+    objects: Vec<Option<Obj>>,
+    free: Vec<usize>,
+    strings: HashMap<String, ObjRef>,
+    roots: HashSet<ObjRef>,
+    /// Whether the VM has collected since the world last set the roots.
+    /// Some of the roots it went by may be gone, so the world should
+    /// collect again.
+    roots_stale: bool,
+    /// Objects allocated since the last collection.
+    allocated: usize,
+    next_collection: usize,
+    /// Collect at the first safe point after every allocation, to shake out
+    /// values that should have been rooted and weren't.
+    pub stress: bool,
+    /// What every script run on this heap has cost, for the profiler.
+    pub counters: Counters,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::new(),
+            roots: HashSet::new(),
+            roots_stale: false,
+            allocated: 0,
+            next_collection: FIRST_COLLECTION,
+            stress: false,
+            counters: Counters::default(),
+        }
+    }
+}
+
+impl Heap {
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.allocated += 1;
+        self.counters.allocations += 1;
+        match self.free.pop() {
+            Some(idx) => {
+                self.objects[idx] = Some(obj);
+                ObjRef(idx)
+            }
+            None => {
+                self.objects.push(Some(obj));
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    pub fn intern(&mut self, s: &str) -> ObjRef {
+        if let Some(r) = self.strings.get(s) {
+            return *r;
+        }
+        let r = self.alloc(Obj::Str(s.to_string()));
+        self.strings.insert(s.to_string(), r);
+        r
+    }
+
+    pub fn get(&self, r: ObjRef) -> &Obj {
+        match &self.objects[r.0] {
+            Some(obj) => obj,
+            None => panic!("object {} was used after it was collected", r.0),
+        }
+    }
+
+    pub fn get_mut(&mut self, r: ObjRef) -> &mut Obj {
+        match &mut self.objects[r.0] {
+            Some(obj) => obj,
+            None => panic!("object {} was used after it was collected", r.0),
+        }
+    }
+
+    /// How many objects are alive (or not yet known to be dead).
+    pub fn live_objects(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    /// Keep `value` alive until the world next sets the roots.
+    pub fn root(&mut self, value: Value) {
+        let mut grey = Vec::new();
+        trace_value(value, &mut grey);
+        self.roots.extend(grey);
+    }
+
+    pub fn should_collect(&self) -> bool {
+        if self.stress {
+            self.allocated > 0
+        } else {
+            self.live_objects() >= self.next_collection
+        }
+    }
+
+    /// Whether the world should collect, giving the heap a fresh set of
+    /// roots.
+    pub fn should_collect_world(&self) -> bool {
+        self.should_collect() || self.roots_stale
+    }
+
+    /// Collect with `roots` as everything held outside the scripts. Only the
+    /// world can say what that is, so this happens between systems.
+    pub fn collect_world(&mut self, roots: impl IntoIterator<Item = Value>) {
+        self.roots.clear();
+        for value in roots {
+            self.root(value);
+        }
+        self.collect(&[]);
+        self.roots_stale = false;
+    }
+
+    /// Free every object that can't be reached from `roots` or from the
+    /// heap's own roots.
+    pub fn collect(&mut self, roots: &[Value]) {
+        let mut marked = vec![false; self.objects.len()];
+        let mut grey: Vec<ObjRef> = self.roots.iter().copied().collect();
+        for value in roots {
+            trace_value(*value, &mut grey);
+        }
+
+        while let Some(r) = grey.pop() {
+            if marked[r.0] {
+                continue;
+            }
+            marked[r.0] = true;
+            match self.get(r) {
+                Obj::Str(_) => {}
+                Obj::List(items) => {
+                    for item in items {
+                        trace_value(*item, &mut grey);
+                    }
+                }
+                Obj::Function(function) => grey.push(function.closure),
+                Obj::Env(env) => {
+                    for value in env.values.values() {
+                        trace_value(*value, &mut grey);
+                    }
+                    grey.extend(env.enclosing);
+                }
+                Obj::Closure(closure) => {
+                    grey.extend(&closure.upvalues);
+                    grey.push(closure.globals);
+                }
+                Obj::Upvalue(Upvalue::Closed(value)) => trace_value(*value, &mut grey),
+                Obj::Upvalue(Upvalue::Open(_)) => {}
+                Obj::Module(module) => grey.push(module.globals),
+            }
+        }
+
+        for (idx, slot) in self.objects.iter_mut().enumerate() {
+            if !marked[idx] && slot.is_some() {
+                *slot = None;
+                self.free.push(idx);
+            }
+        }
+        // the intern table doesn't keep strings alive
+        self.strings.retain(|_, r| marked[r.0]);
+
+        self.allocated = 0;
+        self.roots_stale = true;
+        self.next_collection = (self.live_objects() * 2).max(FIRST_COLLECTION);
+    }
+
+    pub fn env(&self, r: ObjRef) -> &Environment {
+        match self.get(r) {
+            Obj::Env(env) => env,
+            other => panic!("expected an environment, found a {}", other.type_name()),
+        }
+    }
+
+    pub fn env_mut(&mut self, r: ObjRef) -> &mut Environment {
+        match self.get_mut(r) {
+            Obj::Env(env) => env,
+            other => panic!("expected an environment, found a {}", other.type_name()),
+        }
+    }
+
+    pub fn as_str(&self, value: Value) -> Option<&str> {
+        match value {
+            Value::Obj(r) => match self.get(r) {
+                Obj::Str(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Render a value the way a script would print it.
+    pub fn format(&self, value: Value) -> String {
+        match value {
+            Value::Nil => "nil".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::Direction(d) => direction_name(d).to_string(),
+            Value::Intent(Intent::Say(r)) => format!("<say {}>", self.format(Value::Obj(r))),
+            Value::Intent(Intent::Reprogram(target, r)) => {
+                format!(
+                    "<reprogram {} as {}>",
+                    target.id(),
+                    self.format(Value::Obj(r))
+                )
+            }
+            Value::Intent(i) => i.to_string(),
+            Value::Position(p) => format!("({}, {})", p.x, p.y),
+            Value::Entity(e) => format!("<entity {}>", e.id()),
+            Value::Native(n) => format!("<native fn {}>", n.name),
+            Value::Namespace(ns) => format!("<namespace {}>", ns.name),
+            Value::Obj(r) => match self.get(r) {
+                Obj::Str(s) => s.clone(),
+                Obj::List(items) => {
+                    let items: Vec<String> = items.iter().map(|v| self.format(*v)).collect();
+                    format!("[{}]", items.join(", "))
+                }
+                Obj::Function(f) => format!("<fn {}>", f.decl.name.lexeme),
+                Obj::Env(_) => "<environment>".to_string(),
+                Obj::Closure(c) if c.function.name.is_empty() => "<script>".to_string(),
+                Obj::Closure(c) => format!("<fn {}>", c.function.name),
+                Obj::Upvalue(_) => "<upvalue>".to_string(),
+                Obj::Module(m) => format!("<module {}>", m.path),
+            },
+        }
+    }
+}
+
+/// Queue up the object a value points at, if it points at one.
+fn trace_value(value: Value, grey: &mut Vec<ObjRef>) {
+    match value {
+        Value::Obj(r) | Value::Intent(Intent::Say(r)) | Value::Intent(Intent::Reprogram(_, r)) => {
+            grey.push(r)
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::script::api::GameView;
+    use crate::script::corpus::PROGRAMS;
+    use crate::script::engine::*;
+
+    fn engine(stress: bool) -> ScriptEngine {
+        let mut engine = ScriptEngine::new(Backend::Bytecode);
+        engine.heap.stress = stress;
+        engine
+    }
+
+    fn script(source: &str) -> Script {
+        Script::new(Arc::new(Program::new("test", source).unwrap())).with_fuel(100_000)
+    }
+
+    /// What the game does between systems: collect, with everything the
+    /// scripts hold on to as roots.
+    fn collect_world(engine: &mut ScriptEngine, scripts: &[&Script]) {
+        let mut roots: Vec<Value> = scripts.iter().flat_map(|script| script.roots()).collect();
+        roots.extend(engine.roots());
+        engine.heap.collect_world(roots);
+    }
+
+    fn call(engine: &mut ScriptEngine, script: &mut Script, hook: &str) -> String {
+        match engine.call(script, &mut GameView::default(), hook, &[]) {
+            Ok(Some(value)) => engine.heap.format(value),
+            Ok(None) => "no such hook".to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    /// Anything still in use that gets freed shows up as a panic, or as a
+    /// different answer.
+    #[test]
+    fn corpus_survives_stress() {
+        for (name, source) in PROGRAMS {
+            let expected = call(&mut engine(false), &mut script(source), "test");
+            let mut stressed = engine(true);
+            let got = call(&mut stressed, &mut script(source), "test");
+            assert_eq!(expected, got, "{name}");
+            assert!(stressed.heap.counters.allocations > 0, "{name}");
+        }
+    }
+
+    #[test]
+    fn closures_survive_between_turns() {
+        let source = "
+            fn make(start) { let n = start; fn next() { n = n + 1; return n; } return next; }
+            let a = make(10);
+            let b = make(100);
+            fn test() { a(); let s = \"x\" + \"y\"; return [a(), b(), s]; }
+        ";
+        let mut engine = engine(true);
+        let mut script = script(source);
+        let mut results = Vec::new();
+        for _ in 0..3 {
+            results.push(call(&mut engine, &mut script, "test"));
+            collect_world(&mut engine, &[&script]);
+        }
+        assert_eq!(results, ["[12, 101, xy]", "[14, 102, xy]", "[16, 103, xy]"]);
+    }
+
+    #[test]
+    fn suspended_calls_survive_between_turns() {
+        let source = "
+            fn on_turn() {
+                let trail = \"\";
+                let n = 0;
+                fn grow() { trail = trail + \"ab\"; n = n + 1; }
+                while n < 3 {
+                    grow();
+                    yield say(trail);
+                }
+                return say(\"done \" + trail);
+            }
+        ";
+        let mut engine = engine(true);
+        // other mobs take their turns before the world next collects
+        let mut other = script("fn test() { return [\"other\"]; }");
+        let mut script = script(source);
+        let mut turns = Vec::new();
+        for _ in 0..4 {
+            let intent = engine
+                .run_turn(&mut script, &mut GameView::default())
+                .unwrap()
+                .unwrap();
+            turns.push(engine.heap.format(Value::Intent(intent)));
+            call(&mut engine, &mut other, "test");
+            collect_world(&mut engine, &[&script, &other]);
+        }
+        assert_eq!(
+            turns,
+            [
+                "<say ab>",
+                "<say abab>",
+                "<say ababab>",
+                "<say done ababab>"
+            ]
+        );
+    }
+
+    #[test]
+    fn garbage_is_reclaimed() {
+        let source = "
+            let kept = [\"kept\"];
+            fn test() {
+                let i = 0;
+                while i < 100 {
+                    let junk = [i, [i], \"s\" + \"t\"];
+                    i = i + 1;
+                }
+                return i;
+            }
+        ";
+
+        // without stress the garbage piles up until the world collects
+        let mut engine = engine(false);
+        let mut script = script(source);
+        call(&mut engine, &mut script, "test");
+        collect_world(&mut engine, &[&script]);
+        let live = engine.heap.live_objects();
+        call(&mut engine, &mut script, "test");
+        assert!(engine.heap.live_objects() >= live + 200);
+        collect_world(&mut engine, &[&script]);
+        assert_eq!(engine.heap.live_objects(), live);
+
+        // with it, the VM keeps on top of it as it goes
+        engine.heap.stress = true;
+        call(&mut engine, &mut script, "test");
+        assert!(engine.heap.live_objects() <= live + 5);
+        collect_world(&mut engine, &[&script]);
+        assert_eq!(engine.heap.live_objects(), live);
+        assert_eq!(call(&mut engine, &mut script, "test"), "100");
+    }
+}
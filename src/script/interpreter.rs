@@ -0,0 +1,435 @@
+use crate::script::api::{GameView, NAMESPACES};
+use crate::script::ast::*;
+use crate::script::error::RuntimeError;
+use crate::script::heap::*;
+use crate::script::natives;
+use crate::script::ops::*;
+use crate::script::token::*;
+use crate::script::value::*;
+
+/// Deepest a script may recurse before we give up on it.
+const MAX_CALL_DEPTH: usize = 64;
+
+/// Non-local exits out of statement execution: a `return` unwinding to its
+/// call, or an error unwinding all the way out.
+enum Unwind {
+    Return(Value, usize),
+    Error(RuntimeError),
+}
+
+impl From<RuntimeError> for Unwind {
+    fn from(e: RuntimeError) -> Self {
+        Unwind::Error(e)
+    }
+}
+
+type ExecResult = Result<(), Unwind>;
+type EvalResult = Result<Value, RuntimeError>;
+
+pub struct Interpreter<'a, 'w> {
+    heap: &'a mut Heap,
+    game: &'a mut GameView<'w>,
+    globals: ObjRef,
+    environment: ObjRef,
+    depth: usize,
+    fuel: u32,
+}
+
+impl<'a, 'w> Interpreter<'a, 'w> {
+    /// `fuel` is how many statements and expressions may be evaluated before
+    /// the script is stopped.
+    pub fn new(heap: &'a mut Heap, game: &'a mut GameView<'w>, globals: ObjRef, fuel: u32) -> Self {
+        Self {
+            heap,
+            game,
+            globals,
+            environment: globals,
+            depth: 0,
+            fuel,
+        }
+    }
+
+    fn burn(&mut self, line: usize) -> Result<(), RuntimeError> {
+        if self.fuel == 0 {
+            return Err(RuntimeError::out_of_fuel(line));
+        }
+        self.fuel -= 1;
+        self.heap.counters.instructions += 1;
+        Ok(())
+    }
+
+    /// Create a fresh global scope holding the built-in functions and the
+    /// game API namespaces.
+    pub fn new_globals(heap: &mut Heap) -> ObjRef {
+        let mut env = Environment::new(None);
+        for native in natives::GLOBALS {
+            env.values
+                .insert(native.name.to_string(), Value::Native(native));
+        }
+        for namespace in NAMESPACES {
+            env.values
+                .insert(namespace.name.to_string(), Value::Namespace(namespace));
+        }
+        heap.alloc(Obj::Env(env))
+    }
+
+    pub fn execute_program(&mut self, statements: &[Stmt]) -> Result<(), RuntimeError> {
+        for stmt in statements {
+            match self.execute(stmt) {
+                Ok(()) => {}
+                Err(Unwind::Error(e)) => return Err(e),
+                Err(Unwind::Return(_, line)) => {
+                    return Err(RuntimeError::new(line, "Can't return from top-level code."))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a line typed at the console. If it ends in an expression, that
+    /// expression's value is returned so it can be shown.
+    pub fn execute_line(&mut self, statements: &[Stmt]) -> Result<Option<Value>, RuntimeError> {
+        match statements.split_last() {
+            Some((Stmt::Expression(expr), rest)) => {
+                self.execute_program(rest)?;
+                self.evaluate(expr).map(Some)
+            }
+            _ => {
+                self.execute_program(statements)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Call a function defined in the current global scope. Returns `None`
+    /// if the script doesn't define it.
+    pub fn call_global(
+        &mut self,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Option<Value>, RuntimeError> {
+        match self.heap.env(self.environment).values.get(name) {
+            Some(callee) => {
+                let callee = *callee;
+                self.call(callee, args, 0).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn call(&mut self, callee: Value, args: &[Value], line: usize) -> EvalResult {
+        match callee {
+            Value::Native(native) => {
+                check_arity(native.arity, args.len(), line)?;
+                self.heap.counters.native_calls += 1;
+                (native.function)(self.heap, self.game, args)
+                    .map_err(|e| RuntimeError::new(line, e))
+            }
+            Value::Obj(r) => {
+                let (decl, closure) = match self.heap.get(r) {
+                    Obj::Function(f) => (f.decl.clone(), f.closure),
+                    _ => return Err(RuntimeError::new(line, "Can only call functions.")),
+                };
+                check_arity(decl.params.len(), args.len(), line)?;
+                if self.depth >= MAX_CALL_DEPTH {
+                    return Err(RuntimeError::new(line, "Stack overflow."));
+                }
+
+                let mut env = Environment::new(Some(closure));
+                for (param, arg) in decl.params.iter().zip(args) {
+                    env.values.insert(param.lexeme.clone(), *arg);
+                }
+                let env = self.heap.alloc(Obj::Env(env));
+
+                // a function sees the globals it was defined in, which for
+                // an imported one are its module's rather than the caller's
+                let globals = self.outermost(closure);
+                let caller = std::mem::replace(&mut self.globals, globals);
+                self.depth += 1;
+                let result = self.execute_block(&decl.body, env);
+                self.depth -= 1;
+                self.globals = caller;
+
+                match result {
+                    Ok(()) => Ok(Value::Nil),
+                    Err(Unwind::Return(value, _)) => Ok(value),
+                    Err(Unwind::Error(e)) => Err(e),
+                }
+            }
+            _ => Err(RuntimeError::new(line, "Can only call functions.")),
+        }
+    }
+
+    fn execute(&mut self, stmt: &Stmt) -> ExecResult {
+        // a block costs nothing itself; the statements inside it do
+        if !matches!(stmt, Stmt::Block(_)) {
+            self.burn(stmt_line(stmt))?;
+        }
+        match stmt {
+            Stmt::Block(statements) => {
+                let env = self
+                    .heap
+                    .alloc(Obj::Env(Environment::new(Some(self.environment))));
+                self.execute_block(statements, env)
+            }
+            Stmt::Expression(expr) => {
+                self.evaluate(expr)?;
+                Ok(())
+            }
+            Stmt::Function(decl) => {
+                let function = self.heap.alloc(Obj::Function(Function {
+                    decl: decl.clone(),
+                    closure: self.environment,
+                }));
+                self.define(&decl.name.lexeme, Value::Obj(function));
+                Ok(())
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if !self.evaluate(condition)?.is_falsey() {
+                    self.execute(then_branch)
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch)
+                } else {
+                    Ok(())
+                }
+            }
+            Stmt::Let { name, initializer } => {
+                let value = match initializer {
+                    Some(expr) => self.evaluate(expr)?,
+                    None => Value::Nil,
+                };
+                self.define(&name.lexeme, value);
+                Ok(())
+            }
+            Stmt::Return { keyword, value } => {
+                let value = match value {
+                    Some(expr) => self.evaluate(expr)?,
+                    None => Value::Nil,
+                };
+                Err(Unwind::Return(value, keyword.span.line))
+            }
+            Stmt::While { condition, body } => {
+                while !self.evaluate(condition)?.is_falsey() {
+                    self.execute(body)?;
+                }
+                Ok(())
+            }
+            // the engine binds imported modules before the script runs
+            Stmt::Import { .. } => Ok(()),
+            // the tree-walker keeps its place on the Rust stack, so there is
+            // nowhere to put a suspended call
+            Stmt::Yield { keyword, .. } => Err(Unwind::Error(RuntimeError::new(
+                keyword.span.line,
+                "yield needs the bytecode backend.",
+            ))),
+        }
+    }
+
+    fn execute_block(&mut self, statements: &[Stmt], env: ObjRef) -> ExecResult {
+        let previous = self.environment;
+        self.environment = env;
+
+        let mut result = Ok(());
+        for stmt in statements {
+            result = self.execute(stmt);
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.environment = previous;
+        result
+    }
+
+    pub fn evaluate(&mut self, expr: &Expr) -> EvalResult {
+        self.burn(expr.span().line)?;
+        match expr {
+            Expr::Assign { name, value, depth } => {
+                let value = self.evaluate(value)?;
+                self.assign(name, *depth, value)?;
+                Ok(value)
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                let a = self.evaluate(left)?;
+                let b = self.evaluate(right)?;
+                let op = BinaryOp::from_token(operator.ttype).unwrap();
+                op.apply(self.heap, a, b)
+                    .map_err(|e| RuntimeError::new(operator.span.line, e))
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                let callee = self.evaluate(callee)?;
+                let mut args = Vec::with_capacity(arguments.len());
+                for arg in arguments {
+                    args.push(self.evaluate(arg)?);
+                }
+                self.call(callee, &args, paren.span.line)
+            }
+            Expr::Get { object, name } => {
+                let object = self.evaluate(object)?;
+                get_property(self.heap, object, &name.lexeme)
+                    .map_err(|e| RuntimeError::new(name.span.line, e))
+            }
+            Expr::Grouping(expr) => self.evaluate(expr),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                let list = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                index_get(self.heap, list, index)
+                    .map_err(|e| RuntimeError::new(bracket.span.line, e))
+            }
+            Expr::List { elements, .. } => {
+                let mut items = Vec::with_capacity(elements.len());
+                for element in elements {
+                    items.push(self.evaluate(element)?);
+                }
+                Ok(Value::Obj(self.heap.alloc(Obj::List(items))))
+            }
+            Expr::Literal { value, .. } => Ok(match value {
+                Literal::Nil => Value::Nil,
+                Literal::Bool(b) => Value::Bool(*b),
+                Literal::Number(n) => Value::Number(*n),
+                Literal::Str(s) => Value::Obj(self.heap.intern(s)),
+                Literal::Direction(d) => Value::Direction(*d),
+            }),
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                let left = self.evaluate(left)?;
+                if operator.ttype == TokenType::Or {
+                    if !left.is_falsey() {
+                        return Ok(left);
+                    }
+                } else if left.is_falsey() {
+                    return Ok(left);
+                }
+                self.evaluate(right)
+            }
+            Expr::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                let list = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                let value = self.evaluate(value)?;
+                index_set(self.heap, list, index, value)
+                    .map_err(|e| RuntimeError::new(bracket.span.line, e))?;
+                Ok(value)
+            }
+            Expr::Unary { operator, right } => {
+                let right = self.evaluate(right)?;
+                if operator.ttype == TokenType::Bang {
+                    Ok(Value::Bool(right.is_falsey()))
+                } else {
+                    negate(right).map_err(|e| RuntimeError::new(operator.span.line, e))
+                }
+            }
+            Expr::Variable { name, depth } => self.look_up(name, *depth),
+        }
+    }
+
+    fn define(&mut self, name: &str, value: Value) {
+        self.heap
+            .env_mut(self.environment)
+            .values
+            .insert(name.to_string(), value);
+    }
+
+    /// The scope `depth` steps out from the current one, as worked out by
+    /// the resolver; `None` means the global scope.
+    fn scope(&self, depth: Option<usize>) -> ObjRef {
+        let depth = match depth {
+            Some(depth) => depth,
+            None => return self.globals,
+        };
+        let mut env = self.environment;
+        for _ in 0..depth {
+            env = self
+                .heap
+                .env(env)
+                .enclosing
+                .expect("resolved past the outermost scope");
+        }
+        env
+    }
+
+    fn outermost(&self, mut env: ObjRef) -> ObjRef {
+        while let Some(enclosing) = self.heap.env(env).enclosing {
+            env = enclosing;
+        }
+        env
+    }
+
+    fn look_up(&self, name: &Token, depth: Option<usize>) -> EvalResult {
+        let scope = self.heap.env(self.scope(depth));
+        match scope.values.get(&name.lexeme) {
+            Some(value) => Ok(*value),
+            None => Err(undefined(name)),
+        }
+    }
+
+    fn assign(
+        &mut self,
+        name: &Token,
+        depth: Option<usize>,
+        value: Value,
+    ) -> Result<(), RuntimeError> {
+        let scope = self.scope(depth);
+        match self.heap.env_mut(scope).values.get_mut(&name.lexeme) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(undefined(name)),
+        }
+    }
+}
+
+fn undefined(name: &Token) -> RuntimeError {
+    RuntimeError::new(
+        name.span.line,
+        format!("Undefined variable '{}'.", name.lexeme),
+    )
+}
+
+fn check_arity(expected: usize, got: usize, line: usize) -> Result<(), RuntimeError> {
+    if expected == got {
+        Ok(())
+    } else {
+        Err(RuntimeError::new(
+            line,
+            format!("Expected {expected} arguments but got {got}."),
+        ))
+    }
+}
+
+fn stmt_line(stmt: &Stmt) -> usize {
+    match stmt {
+        Stmt::Block(statements) => statements.first().map_or(0, stmt_line),
+        Stmt::Expression(expr) => expr.span().line,
+        Stmt::Function(decl) => decl.name.span.line,
+        Stmt::If { condition, .. } | Stmt::While { condition, .. } => condition.span().line,
+        Stmt::Let { name, .. } => name.span.line,
+        Stmt::Return { keyword, .. }
+        | Stmt::Yield { keyword, .. }
+        | Stmt::Import { keyword, .. } => keyword.span.line,
+    }
+}
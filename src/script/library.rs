@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use bracket_lib::prelude::EMBED;
+
+use crate::script::engine::Program;
+
+/// Where `import` looks for modules: `import "ai/flee"` loads
+/// `scripts/ai/flee.prl`, next to the game's `resources/`.
+pub const MODULE_DIR: &str = "../scripts";
+
+/// Every script the game ships with, by the path `import` knows it by.
+/// Like the fonts, they are embedded in the binary and linked in by `main`,
+/// so they are found whatever directory the game is run from, and in the
+/// WASM build, which has no files at all.
+pub const SHIPPED: &[&str] = &[
+    "ai/docile",
+    "ai/flee",
+    "devices/injector",
+    "devices/phaser",
+    "devices/remote",
+    "maps/halls",
+    "sentry",
+];
+
+/// Every module compiled so far, by path. However many scripts import a
+/// module, it is only read and compiled once.
+static MODULES: Mutex<BTreeMap<String, Arc<Program>>> = Mutex::new(BTreeMap::new());
+
+/// The source of the module `path`. Like bracket-lib's fonts, a copy
+/// embedded in the binary wins over the file on disk.
+pub fn module_source(path: &str) -> Option<String> {
+    let file = format!("{MODULE_DIR}/{path}.prl");
+    let embedded = EMBED.lock().get_resource(file.clone());
+    match embedded {
+        Some(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        None => std::fs::read_to_string(&file).ok(),
+    }
+}
+
+/// Find and compile the module `path`. `loading` holds the modules whose
+/// imports are being loaded, to catch a module that ends up importing
+/// itself. Everything wrong with the module is reported, one message per
+/// problem.
+pub fn load_module(path: &str, loading: &mut Vec<String>) -> Result<Arc<Program>, Vec<String>> {
+    if let Some(program) = MODULES.lock().unwrap().get(path) {
+        return Ok(program.clone());
+    }
+    if loading.iter().any(|p| p == path) {
+        return Err(vec![format!("Module '{path}' imports itself.")]);
+    }
+
+    let source = module_source(path).ok_or_else(|| vec![format!("Can't find module '{path}'.")])?;
+
+    loading.push(path.to_string());
+    let program = Program::with_loading(path, &source, true, loading);
+    loading.pop();
+    let program = match program {
+        Ok(program) => Arc::new(program),
+        Err(diagnostics) => {
+            return Err(diagnostics
+                .iter()
+                .map(|diagnostic| format!("Module '{path}' has an error: {diagnostic}"))
+                .collect())
+        }
+    };
+
+    MODULES
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), program.clone());
+    Ok(program)
+}
+
+/// Find and compile the program at `path` to give to a mob, the way
+/// `import` finds a module.
+pub fn load_program(path: &str) -> Result<Arc<Program>, Vec<String>> {
+    load_module(path, &mut Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_scripts_load() {
+        crate::link_scripts();
+        for path in SHIPPED {
+            let program = load_program(path).unwrap();
+            assert_eq!(program.name, *path);
+        }
+    }
+
+    #[test]
+    fn imports_are_shared() {
+        crate::link_scripts();
+        let sentry = load_program("sentry").unwrap();
+        let flee = load_program("ai/flee").unwrap();
+        assert!(Arc::ptr_eq(&sentry.imports[0].module, &flee));
+    }
+
+    #[test]
+    fn missing_modules_are_reported() {
+        assert_eq!(
+            load_program("ai/nowhere").unwrap_err(),
+            ["Can't find module 'ai/nowhere'."]
+        );
+        let errors =
+            Program::new("test", "import \"ai/nowhere\";\nimport \"nor/here\";").unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
+}
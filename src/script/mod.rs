@@ -0,0 +1,66 @@
+mod error;
+pub use error::*;
+
+mod token;
+pub use token::*;
+
+mod scanner;
+pub use scanner::*;
+
+mod ast;
+pub use ast::*;
+
+mod parser;
+pub use parser::*;
+
+mod value;
+pub use value::*;
+
+mod heap;
+pub use heap::ObjRef;
+
+mod ops;
+
+mod api;
+pub use api::*;
+
+mod natives;
+
+mod resolver;
+pub use resolver::*;
+
+mod interpreter;
+
+mod chunk;
+
+mod compiler;
+
+mod optimise;
+
+mod disasm;
+
+mod debug;
+pub use debug::*;
+
+mod profile;
+pub use profile::*;
+
+mod library;
+pub use library::{load_program, module_source, SHIPPED};
+
+mod vm;
+
+mod engine;
+pub use engine::*;
+
+#[cfg(test)]
+pub mod corpus;
+
+/// Scan, parse and resolve a script in one go. `known` names globals that
+/// exist before the script runs, on top of the built-in ones.
+pub fn parse(source: &str, known: &[String]) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+    let tokens = Scanner::new(source).scan_tokens()?;
+    let mut statements = Parser::new(tokens).parse()?;
+    Resolver::new(known).resolve(&mut statements)?;
+    Ok(statements)
+}
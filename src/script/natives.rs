@@ -0,0 +1,136 @@
+use bevy_ecs::prelude::*;
+
+use crate::components::{Effect, Position};
+use crate::script::api::GameView;
+use crate::script::heap::Heap;
+use crate::script::value::*;
+
+pub static MOVE: Native = Native {
+    name: "move",
+    arity: 1,
+    function: native_move,
+};
+
+pub static WAIT: Native = Native {
+    name: "wait",
+    arity: 0,
+    function: native_wait,
+};
+
+pub static POSITION: Native = Native {
+    name: "position",
+    arity: 2,
+    function: native_position,
+};
+
+pub static SAY: Native = Native {
+    name: "say",
+    arity: 1,
+    function: native_say,
+};
+
+pub static DAMAGE: Native = Native {
+    name: "damage",
+    arity: 2,
+    function: native_damage,
+};
+
+pub static TELEPORT: Native = Native {
+    name: "teleport",
+    arity: 2,
+    function: native_teleport,
+};
+
+pub static REPROGRAM: Native = Native {
+    name: "reprogram",
+    arity: 2,
+    function: native_reprogram,
+};
+
+pub static AFFLICT: Native = Native {
+    name: "afflict",
+    arity: 3,
+    function: native_afflict,
+};
+
+/// Functions defined in every script's global scope.
+pub static GLOBALS: [&Native; 8] = [
+    &MOVE, &WAIT, &SAY, &POSITION, &DAMAGE, &TELEPORT, &REPROGRAM, &AFFLICT,
+];
+
+fn native_move(_: &mut Heap, _: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Direction(dir) => Ok(Value::Intent(Intent::Move(dir))),
+        _ => Err("move() expects a direction.".to_string()),
+    }
+}
+
+fn native_wait(_: &mut Heap, _: &mut GameView, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Intent(Intent::Wait))
+}
+
+fn native_say(heap: &mut Heap, _: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Obj(r) if heap.as_str(args[0]).is_some() => Ok(Value::Intent(Intent::Say(r))),
+        _ => Err("say() expects a string.".to_string()),
+    }
+}
+
+fn native_position(_: &mut Heap, _: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    match (args[0], args[1]) {
+        (Value::Number(x), Value::Number(y)) => Ok(Value::Position(Position {
+            x: x as i32,
+            y: y as i32,
+        })),
+        _ => Err("position() expects two numbers.".to_string()),
+    }
+}
+
+fn expect_entity(value: Value, function: &str) -> Result<Entity, String> {
+    match value {
+        Value::Entity(e) => Ok(e),
+        _ => Err(format!("{function}() expects an entity to act on.")),
+    }
+}
+
+fn native_damage(_: &mut Heap, _: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let target = expect_entity(args[0], "damage")?;
+    match args[1] {
+        Value::Number(n) if n >= 1.0 && n.fract() == 0.0 => {
+            Ok(Value::Intent(Intent::Damage(target, n as i32)))
+        }
+        _ => Err("damage() expects a whole number of points.".to_string()),
+    }
+}
+
+fn native_teleport(_: &mut Heap, _: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let target = expect_entity(args[0], "teleport")?;
+    match args[1] {
+        Value::Position(p) => Ok(Value::Intent(Intent::Teleport(target, p))),
+        _ => Err("teleport() expects a position to go to.".to_string()),
+    }
+}
+
+fn native_reprogram(heap: &mut Heap, _: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let target = expect_entity(args[0], "reprogram")?;
+    match args[1] {
+        Value::Obj(r) if heap.as_str(args[1]).is_some() => {
+            Ok(Value::Intent(Intent::Reprogram(target, r)))
+        }
+        _ => Err("reprogram() expects the path of a program.".to_string()),
+    }
+}
+
+fn native_afflict(heap: &mut Heap, _: &mut GameView, args: &[Value]) -> Result<Value, String> {
+    let target = expect_entity(args[0], "afflict")?;
+    let effect = heap
+        .as_str(args[1])
+        .and_then(Effect::from_name)
+        .ok_or("afflict() expects \"poison\", \"stun\", \"haste\" or \"regen\".")?;
+    match args[2] {
+        Value::Number(n) if n >= 1.0 && n.fract() == 0.0 => {
+            Ok(Value::Intent(Intent::Afflict(target, effect, n as i32)))
+        }
+        _ => Err("afflict() expects a whole number of turns.".to_string()),
+    }
+}
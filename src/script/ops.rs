@@ -0,0 +1,136 @@
+use crate::script::heap::*;
+use crate::script::token::TokenType;
+use crate::script::value::Value;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+}
+
+impl BinaryOp {
+    pub fn from_token(ttype: TokenType) -> Option<Self> {
+        Some(match ttype {
+            TokenType::Plus => BinaryOp::Add,
+            TokenType::Minus => BinaryOp::Subtract,
+            TokenType::Star => BinaryOp::Multiply,
+            TokenType::Slash => BinaryOp::Divide,
+            TokenType::Percent => BinaryOp::Modulo,
+            TokenType::Greater => BinaryOp::Greater,
+            TokenType::GreaterEqual => BinaryOp::GreaterEqual,
+            TokenType::Less => BinaryOp::Less,
+            TokenType::LessEqual => BinaryOp::LessEqual,
+            TokenType::EqualEqual => BinaryOp::Equal,
+            TokenType::BangEqual => BinaryOp::NotEqual,
+            _ => return None,
+        })
+    }
+
+    /// Apply the operator. Both backends go through here so that they can't
+    /// disagree about what `"a" + "b"` or `1 < nil` means.
+    pub fn apply(self, heap: &mut Heap, a: Value, b: Value) -> Result<Value, String> {
+        match self {
+            BinaryOp::Equal => return Ok(Value::Bool(a == b)),
+            BinaryOp::NotEqual => return Ok(Value::Bool(a != b)),
+            BinaryOp::Add => {
+                if let (Some(x), Some(y)) = (heap.as_str(a), heap.as_str(b)) {
+                    let joined = format!("{x}{y}");
+                    return Ok(Value::Obj(heap.intern(&joined)));
+                }
+            }
+            _ => {}
+        }
+
+        let (x, y) = match (a, b) {
+            (Value::Number(x), Value::Number(y)) => (x, y),
+            _ if self == BinaryOp::Add => {
+                return Err("Operands must be two numbers or two strings.".to_string())
+            }
+            _ => return Err("Operands must be numbers.".to_string()),
+        };
+
+        Ok(match self {
+            BinaryOp::Add => Value::Number(x + y),
+            BinaryOp::Subtract => Value::Number(x - y),
+            BinaryOp::Multiply => Value::Number(x * y),
+            BinaryOp::Divide => Value::Number(x / y),
+            BinaryOp::Modulo => Value::Number(x % y),
+            BinaryOp::Greater => Value::Bool(x > y),
+            BinaryOp::GreaterEqual => Value::Bool(x >= y),
+            BinaryOp::Less => Value::Bool(x < y),
+            BinaryOp::LessEqual => Value::Bool(x <= y),
+            BinaryOp::Equal | BinaryOp::NotEqual => unreachable!(),
+        })
+    }
+}
+
+/// Look up `object.name`. Positions have `x` and `y`; namespaces hold the
+/// natives that make up the game API; modules hold whatever their top level
+/// defined.
+pub fn get_property(heap: &Heap, object: Value, name: &str) -> Result<Value, String> {
+    let found = match object {
+        Value::Position(p) => match name {
+            "x" => Some(Value::Number(p.x as f64)),
+            "y" => Some(Value::Number(p.y as f64)),
+            _ => None,
+        },
+        Value::Namespace(ns) => ns.get(name).map(Value::Native),
+        Value::Obj(r) => match heap.get(r) {
+            Obj::Module(module) => heap.env(module.globals).values.get(name).copied(),
+            _ => return Err(format!("A {} has no properties.", object.type_name(heap))),
+        },
+        _ => return Err(format!("A {} has no properties.", object.type_name(heap))),
+    };
+    found.ok_or_else(|| format!("Undefined property '{name}'."))
+}
+
+pub fn negate(value: Value) -> Result<Value, String> {
+    match value {
+        Value::Number(n) => Ok(Value::Number(-n)),
+        _ => Err("Operand must be a number.".to_string()),
+    }
+}
+
+fn list_slot(heap: &Heap, list: Value, index: Value) -> Result<(ObjRef, usize), String> {
+    let r = match list {
+        Value::Obj(r) if matches!(heap.get(r), Obj::List(_)) => r,
+        _ => return Err("Only lists can be indexed.".to_string()),
+    };
+    let len = match heap.get(r) {
+        Obj::List(items) => items.len(),
+        _ => unreachable!(),
+    };
+    match index {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && (n as usize) < len => {
+            Ok((r, n as usize))
+        }
+        Value::Number(n) => Err(format!("List index {n} is out of range.")),
+        _ => Err("List index must be a number.".to_string()),
+    }
+}
+
+pub fn index_get(heap: &Heap, list: Value, index: Value) -> Result<Value, String> {
+    let (r, i) = list_slot(heap, list, index)?;
+    match heap.get(r) {
+        Obj::List(items) => Ok(items[i]),
+        _ => unreachable!(),
+    }
+}
+
+pub fn index_set(heap: &mut Heap, list: Value, index: Value, value: Value) -> Result<(), String> {
+    let (r, i) = list_slot(heap, list, index)?;
+    match heap.get_mut(r) {
+        Obj::List(items) => items[i] = value,
+        _ => unreachable!(),
+    }
+    Ok(())
+}
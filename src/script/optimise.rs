@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, HashSet};
+use std::mem;
+use std::sync::Arc;
+
+use crate::script::ast::*;
+use crate::script::chunk::*;
+use crate::script::ops::BinaryOp;
+use crate::script::token::{Span, TokenType};
+
+/// Fold constant expressions and drop branches that can never run. This
+/// happens after the resolver, so nothing here may add or remove a scope:
+/// an `if` with a constant condition is replaced by the block it would
+/// have run, which keeps its own scope.
+pub fn fold(statements: &mut [Stmt]) {
+    for stmt in statements {
+        fold_stmt(stmt);
+    }
+}
+
+fn fold_stmt(stmt: &mut Stmt) {
+    match stmt {
+        Stmt::Block(statements) => fold(statements),
+        Stmt::Expression(expr) => fold_expr(expr),
+        Stmt::Function(decl) => fold(&mut Arc::make_mut(decl).body),
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            fold_expr(condition);
+            fold_stmt(then_branch);
+            if let Some(else_branch) = else_branch {
+                fold_stmt(else_branch);
+            }
+            if let Some(truthy) = constant(condition).map(is_truthy) {
+                let branches = mem::replace(stmt, Stmt::Block(Vec::new()));
+                if let Stmt::If {
+                    then_branch,
+                    else_branch,
+                    ..
+                } = branches
+                {
+                    if truthy {
+                        *stmt = *then_branch;
+                    } else if let Some(else_branch) = else_branch {
+                        *stmt = *else_branch;
+                    }
+                }
+            }
+        }
+        Stmt::Let { initializer, .. } => {
+            if let Some(initializer) = initializer {
+                fold_expr(initializer);
+            }
+        }
+        Stmt::Return { value, .. } | Stmt::Yield { value, .. } => {
+            if let Some(value) = value {
+                fold_expr(value);
+            }
+        }
+        Stmt::While { condition, body } => {
+            fold_expr(condition);
+            fold_stmt(body);
+            if constant(condition).is_some_and(|value| !is_truthy(value)) {
+                *stmt = Stmt::Block(Vec::new());
+            }
+        }
+        Stmt::Import { .. } => {}
+    }
+}
+
+fn fold_expr(expr: &mut Expr) {
+    match expr {
+        Expr::Assign { value, .. } => fold_expr(value),
+        Expr::Binary { left, right, .. } => {
+            fold_expr(left);
+            fold_expr(right);
+        }
+        Expr::Call {
+            callee, arguments, ..
+        } => {
+            fold_expr(callee);
+            arguments.iter_mut().for_each(fold_expr);
+        }
+        Expr::Get { object, .. } => fold_expr(object),
+        Expr::Grouping(inner) => fold_expr(inner),
+        Expr::Index { object, index, .. } => {
+            fold_expr(object);
+            fold_expr(index);
+        }
+        Expr::List { elements, .. } => elements.iter_mut().for_each(fold_expr),
+        Expr::Logical { left, right, .. } => {
+            fold_expr(left);
+            fold_expr(right);
+        }
+        Expr::SetIndex {
+            object,
+            index,
+            value,
+            ..
+        } => {
+            fold_expr(object);
+            fold_expr(index);
+            fold_expr(value);
+        }
+        Expr::Unary { right, .. } => fold_expr(right),
+        Expr::Literal { .. } | Expr::Variable { .. } => {}
+    }
+
+    let span = expr.span();
+    let folded = match expr {
+        Expr::Grouping(inner) => constant(inner).cloned(),
+        Expr::Unary { operator, right } => {
+            constant(right).and_then(|value| match (operator.ttype, value) {
+                (TokenType::Bang, value) => Some(Literal::Bool(!is_truthy(value))),
+                (_, Literal::Number(n)) => Some(Literal::Number(-n)),
+                _ => None,
+            })
+        }
+        Expr::Binary {
+            left,
+            operator,
+            right,
+        } => match (constant(left), constant(right)) {
+            (Some(a), Some(b)) => {
+                BinaryOp::from_token(operator.ttype).and_then(|op| binary(op, a, b))
+            }
+            _ => None,
+        },
+        Expr::Logical { left, operator, .. } => {
+            // `and` and `or` hand back one of their operands, so a
+            // constant on the left decides which
+            let is_or = operator.ttype == TokenType::Or;
+            if let Some(truthy) = constant(left).map(is_truthy) {
+                let operands = mem::replace(expr, nil(span));
+                if let Expr::Logical { left, right, .. } = operands {
+                    *expr = if truthy == is_or { *left } else { *right };
+                }
+            }
+            None
+        }
+        _ => None,
+    };
+    if let Some(value) = folded {
+        *expr = Expr::Literal { value, span };
+    }
+}
+
+fn nil(span: Span) -> Expr {
+    Expr::Literal {
+        value: Literal::Nil,
+        span,
+    }
+}
+
+fn constant(expr: &Expr) -> Option<&Literal> {
+    match expr {
+        Expr::Literal { value, .. } => Some(value),
+        _ => None,
+    }
+}
+
+fn is_truthy(value: &Literal) -> bool {
+    !matches!(value, Literal::Nil | Literal::Bool(false))
+}
+
+/// Work out `a op b` ahead of time, the way `BinaryOp::apply` would at run
+/// time. Anything that would be a runtime error is left for the script to
+/// run into, on the line it was written.
+fn binary(op: BinaryOp, a: &Literal, b: &Literal) -> Option<Literal> {
+    use Literal::*;
+
+    Some(match (op, a, b) {
+        (BinaryOp::Equal, a, b) => Bool(a == b),
+        (BinaryOp::NotEqual, a, b) => Bool(a != b),
+        (BinaryOp::Add, Str(x), Str(y)) => Str(format!("{x}{y}")),
+        (op, Number(x), Number(y)) => {
+            let (x, y) = (*x, *y);
+            match op {
+                BinaryOp::Add => Number(x + y),
+                BinaryOp::Subtract => Number(x - y),
+                BinaryOp::Multiply => Number(x * y),
+                BinaryOp::Divide => Number(x / y),
+                BinaryOp::Modulo => Number(x % y),
+                BinaryOp::Greater => Bool(x > y),
+                BinaryOp::GreaterEqual => Bool(x >= y),
+                BinaryOp::Less => Bool(x < y),
+                BinaryOp::LessEqual => Bool(x <= y),
+                BinaryOp::Equal | BinaryOp::NotEqual => unreachable!(),
+            }
+        }
+        _ => return None,
+    })
+}
+
+/// One decoded instruction. Jumps hold the offset they land on rather than
+/// a distance, so that code can move about before they are encoded again.
+struct Instruction {
+    op: OpCode,
+    operands: Vec<u8>,
+    line: usize,
+    target: Option<usize>,
+}
+
+/// Merge common pairs of instructions into one, in `function` and every
+/// function it defines:
+///
+/// * `Nil Return` becomes `ReturnNil`
+/// * `SetLocal Pop` becomes `StoreLocal`, and `SetGlobal Pop` `StoreGlobal`
+/// * a run of `Pop`s becomes one `PopN`
+///
+/// A pair is only merged if nothing jumps to its second half.
+pub fn peephole(function: &mut FunctionProto) {
+    for constant in &mut function.chunk.constants {
+        if let Constant::Function(inner) = constant {
+            peephole(Arc::make_mut(inner));
+        }
+    }
+
+    let (instructions, offsets) = decode(&function.chunk);
+    let targets: HashSet<usize> = instructions.iter().filter_map(|i| i.target).collect();
+
+    // keyed by the offset each merged instruction started at
+    let mut merged: Vec<(usize, Instruction)> = Vec::new();
+    for (offset, instruction) in offsets.into_iter().zip(instructions) {
+        let previous = match merged.last_mut() {
+            Some((_, previous)) if !targets.contains(&offset) => previous,
+            _ => {
+                merged.push((offset, instruction));
+                continue;
+            }
+        };
+        let fused = match (previous.op, instruction.op) {
+            (OpCode::Nil, OpCode::Return) => Some((OpCode::ReturnNil, vec![])),
+            (OpCode::SetLocal, OpCode::Pop) => {
+                Some((OpCode::StoreLocal, previous.operands.clone()))
+            }
+            (OpCode::SetGlobal, OpCode::Pop) => {
+                Some((OpCode::StoreGlobal, previous.operands.clone()))
+            }
+            (OpCode::Pop, OpCode::Pop) => Some((OpCode::PopN, vec![2])),
+            (OpCode::PopN, OpCode::Pop) if previous.operands[0] < u8::MAX => {
+                Some((OpCode::PopN, vec![previous.operands[0] + 1]))
+            }
+            _ => None,
+        };
+        match fused {
+            Some((op, operands)) => {
+                previous.op = op;
+                previous.operands = operands;
+            }
+            None => merged.push((offset, instruction)),
+        }
+    }
+
+    encode(function, merged);
+}
+
+/// Split a chunk into instructions, along with the offset of each.
+fn decode(chunk: &Chunk) -> (Vec<Instruction>, Vec<usize>) {
+    let mut instructions = Vec::new();
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::from_byte(chunk.code[offset]).expect("compiler wrote a bad opcode");
+        let next = offset + chunk.instruction_len(offset);
+        let target = match op {
+            OpCode::Jump | OpCode::JumpIfFalse => Some(next + jump_distance(chunk, offset)),
+            OpCode::Loop => Some(next - jump_distance(chunk, offset)),
+            _ => None,
+        };
+        instructions.push(Instruction {
+            op,
+            operands: chunk.code[offset + 1..next].to_vec(),
+            line: chunk.lines[offset],
+            target,
+        });
+        offsets.push(offset);
+        offset = next;
+    }
+    (instructions, offsets)
+}
+
+fn jump_distance(chunk: &Chunk, offset: usize) -> usize {
+    ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize
+}
+
+/// Write `instructions` back into the function's chunk, pointing jumps and
+/// the debugger's local ranges at where things have moved to.
+fn encode(function: &mut FunctionProto, instructions: Vec<(usize, Instruction)>) {
+    // every old offset maps to the start of the instruction that now
+    // covers it, and the old end of the code to the new end
+    let mut moved = BTreeMap::new();
+    let mut offset = 0;
+    for (old, instruction) in &instructions {
+        moved.insert(*old, offset);
+        offset += 1 + instruction.operands.len();
+    }
+    moved.insert(function.chunk.code.len(), offset);
+    let new_offset = |old: usize| *moved.range(..=old).next_back().unwrap().1;
+
+    let mut chunk = Chunk {
+        constants: mem::take(&mut function.chunk.constants),
+        ..Default::default()
+    };
+    for (_, instruction) in &instructions {
+        let here = chunk.code.len();
+        chunk.write(instruction.op as u8, instruction.line);
+        let mut operands = instruction.operands.clone();
+        if let Some(target) = instruction.target {
+            let target = new_offset(target);
+            let distance = if instruction.op == OpCode::Loop {
+                here + 3 - target
+            } else {
+                target - (here + 3)
+            };
+            operands = vec![(distance >> 8) as u8, distance as u8];
+        }
+        for byte in operands {
+            chunk.write(byte, instruction.line);
+        }
+    }
+
+    for local in &mut function.locals {
+        local.start = new_offset(local.start);
+        if local.end != usize::MAX {
+            local.end = new_offset(local.end);
+        }
+    }
+    function.chunk = chunk;
+}
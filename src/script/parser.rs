@@ -0,0 +1,636 @@
+use std::sync::Arc;
+
+use crate::script::ast::*;
+use crate::script::error::Diagnostic;
+use crate::script::token::*;
+
+const MAX_ARGS: usize = 255;
+
+type ParseResult<T> = Result<T, Diagnostic>;
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    errors: Vec<Diagnostic>,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Self {
+            tokens,
+            current: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Parse the whole token stream. After an error the parser skips ahead
+    /// to the next statement and keeps going, so one typo doesn't hide every
+    /// other error in the script.
+    pub fn parse(mut self) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+        let mut statements = Vec::new();
+
+        while !self.is_at_end() {
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        let result = if self.is_match(&[TokenType::Fn]) {
+            self.function().map(|decl| Stmt::Function(Arc::new(decl)))
+        } else if self.is_match(&[TokenType::Let]) {
+            self.let_declaration()
+        } else if self.is_match(&[TokenType::Import]) {
+            self.import_declaration()
+        } else {
+            self.statement()
+        };
+
+        match result {
+            Ok(stmt) => Some(stmt),
+            Err(e) => {
+                self.errors.push(e);
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    fn function(&mut self) -> ParseResult<FunctionDecl> {
+        let name = self.consume(TokenType::Identifier, "Expect function name.")?;
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+
+        let mut params = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() >= MAX_ARGS {
+                    let e = self.error(self.peek(), "Can't have more than 255 parameters.");
+                    self.errors.push(e);
+                }
+                params.push(self.consume(TokenType::Identifier, "Expect parameter name.")?);
+                if !self.is_match(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+        let body = self.block()?;
+
+        Ok(FunctionDecl { name, params, body })
+    }
+
+    fn let_declaration(&mut self) -> ParseResult<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
+        let initializer = if self.is_match(&[TokenType::Equal]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+
+        Ok(Stmt::Let { name, initializer })
+    }
+
+    /// A module is imported under the last part of its path, so that path
+    /// has to end in something that could be a variable name.
+    fn import_declaration(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        let token = self.consume(TokenType::String, "Expect module path after 'import'.")?;
+        let path = token.lexeme[1..token.lexeme.len() - 1].to_string();
+        let name = path.rsplit('/').next().unwrap_or_default().to_string();
+        let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && TokenType::keyword(&name).is_none();
+        if !is_name {
+            return Err(Diagnostic::new(
+                token.span,
+                format!("Module path '{path}' must end in a name to import it as."),
+            ));
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after module path.")?;
+
+        Ok(Stmt::Import {
+            keyword,
+            path,
+            name,
+        })
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        if self.is_match(&[TokenType::If]) {
+            self.if_statement()
+        } else if self.is_match(&[TokenType::While]) {
+            self.while_statement()
+        } else if self.is_match(&[TokenType::Return]) {
+            self.return_statement()
+        } else if self.is_match(&[TokenType::Yield]) {
+            self.yield_statement()
+        } else if self.is_match(&[TokenType::LeftBrace]) {
+            Ok(Stmt::Block(self.block()?))
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        let condition = self.expression()?;
+        self.consume(TokenType::LeftBrace, "Expect '{' after if condition.")?;
+        let then_branch = Box::new(Stmt::Block(self.block()?));
+
+        let else_branch = if self.is_match(&[TokenType::Else]) {
+            if self.is_match(&[TokenType::If]) {
+                Some(Box::new(self.if_statement()?))
+            } else {
+                self.consume(TokenType::LeftBrace, "Expect '{' after else.")?;
+                Some(Box::new(Stmt::Block(self.block()?)))
+            }
+        } else {
+            None
+        };
+
+        Ok(Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+
+    fn while_statement(&mut self) -> ParseResult<Stmt> {
+        let condition = self.expression()?;
+        self.consume(TokenType::LeftBrace, "Expect '{' after while condition.")?;
+        let body = Box::new(Stmt::Block(self.block()?));
+
+        Ok(Stmt::While { condition, body })
+    }
+
+    fn return_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        let value = if self.check(TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+
+        Ok(Stmt::Return { keyword, value })
+    }
+
+    fn yield_statement(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous().clone();
+        let value = if self.check(TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after yield value.")?;
+
+        Ok(Stmt::Yield { keyword, value })
+    }
+
+    fn expression_statement(&mut self) -> ParseResult<Stmt> {
+        let expr = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+        Ok(Stmt::Expression(expr))
+    }
+
+    fn block(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut statements = Vec::new();
+
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+
+    fn expression(&mut self) -> ParseResult<Expr> {
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> ParseResult<Expr> {
+        let expr = self.or()?;
+
+        if self.is_match(&[TokenType::Equal]) {
+            let equals = self.previous().clone();
+            let value = Box::new(self.assignment()?);
+
+            return match expr {
+                Expr::Variable { name, .. } => Ok(Expr::Assign {
+                    name,
+                    value,
+                    depth: None,
+                }),
+                Expr::Index {
+                    object,
+                    bracket,
+                    index,
+                } => Ok(Expr::SetIndex {
+                    object,
+                    bracket,
+                    index,
+                    value,
+                }),
+                _ => {
+                    // report it, but there is no need to resynchronize
+                    let e = self.error(&equals, "Invalid assignment target.");
+                    self.errors.push(e);
+                    Ok(*value)
+                }
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.and()?;
+
+        while self.is_match(&[TokenType::Or]) {
+            let operator = self.previous().clone();
+            let right = Box::new(self.and()?);
+            expr = Expr::Logical {
+                left: Box::new(expr),
+                operator,
+                right,
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.equality()?;
+
+        while self.is_match(&[TokenType::And]) {
+            let operator = self.previous().clone();
+            let right = Box::new(self.equality()?);
+            expr = Expr::Logical {
+                left: Box::new(expr),
+                operator,
+                right,
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn binary(
+        &mut self,
+        operators: &[TokenType],
+        operand: fn(&mut Self) -> ParseResult<Expr>,
+    ) -> ParseResult<Expr> {
+        let mut expr = operand(self)?;
+
+        while self.is_match(operators) {
+            let operator = self.previous().clone();
+            let right = Box::new(operand(self)?);
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right,
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> ParseResult<Expr> {
+        self.binary(
+            &[TokenType::BangEqual, TokenType::EqualEqual],
+            Self::comparison,
+        )
+    }
+
+    fn comparison(&mut self) -> ParseResult<Expr> {
+        self.binary(
+            &[
+                TokenType::Greater,
+                TokenType::GreaterEqual,
+                TokenType::Less,
+                TokenType::LessEqual,
+            ],
+            Self::term,
+        )
+    }
+
+    fn term(&mut self) -> ParseResult<Expr> {
+        self.binary(&[TokenType::Minus, TokenType::Plus], Self::factor)
+    }
+
+    fn factor(&mut self) -> ParseResult<Expr> {
+        self.binary(
+            &[TokenType::Slash, TokenType::Star, TokenType::Percent],
+            Self::unary,
+        )
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        if self.is_match(&[TokenType::Bang, TokenType::Minus]) {
+            let operator = self.previous().clone();
+            let right = Box::new(self.unary()?);
+            return Ok(Expr::Unary { operator, right });
+        }
+
+        self.call()
+    }
+
+    fn call(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.primary()?;
+
+        loop {
+            if self.is_match(&[TokenType::LeftParen]) {
+                expr = self.finish_call(expr)?;
+            } else if self.is_match(&[TokenType::Dot]) {
+                let name = self.consume(TokenType::Identifier, "Expect name after '.'.")?;
+                expr = Expr::Get {
+                    object: Box::new(expr),
+                    name,
+                };
+            } else if self.is_match(&[TokenType::LeftBracket]) {
+                let bracket = self.previous().clone();
+                let index = Box::new(self.expression()?);
+                self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+                expr = Expr::Index {
+                    object: Box::new(expr),
+                    bracket,
+                    index,
+                };
+            } else {
+                break;
+            }
+        }
+
+        Ok(expr)
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> ParseResult<Expr> {
+        let arguments = self.arguments(TokenType::RightParen)?;
+        let paren = self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+
+        Ok(Expr::Call {
+            callee: Box::new(callee),
+            paren,
+            arguments,
+        })
+    }
+
+    fn arguments(&mut self, closing: TokenType) -> ParseResult<Vec<Expr>> {
+        let mut arguments = Vec::new();
+
+        if !self.check(closing) {
+            loop {
+                if arguments.len() >= MAX_ARGS {
+                    let e = self.error(self.peek(), "Can't have more than 255 arguments.");
+                    self.errors.push(e);
+                }
+                arguments.push(self.expression()?);
+                if !self.is_match(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+
+        Ok(arguments)
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr> {
+        // advance() stays put at the end, which would leave the previous
+        // token to be parsed all over again
+        if self.is_at_end() {
+            return Err(self.error(self.peek(), "Expect expression."));
+        }
+
+        let token = self.advance().clone();
+        let value = match token.ttype {
+            TokenType::False => Literal::Bool(false),
+            TokenType::True => Literal::Bool(true),
+            TokenType::Nil => Literal::Nil,
+            TokenType::Number => match token.lexeme.parse() {
+                Ok(n) => Literal::Number(n),
+                Err(_) => return Err(self.error(&token, "Invalid number.")),
+            },
+            TokenType::String => Literal::Str(token.lexeme[1..token.lexeme.len() - 1].to_string()),
+            TokenType::Direction(dir) => Literal::Direction(dir),
+            TokenType::Identifier => {
+                return Ok(Expr::Variable {
+                    name: token,
+                    depth: None,
+                })
+            }
+            TokenType::LeftParen => {
+                let expr = self.expression()?;
+                self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+                return Ok(Expr::Grouping(Box::new(expr)));
+            }
+            TokenType::LeftBracket => {
+                let elements = self.arguments(TokenType::RightBracket)?;
+                self.consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+                return Ok(Expr::List {
+                    bracket: token,
+                    elements,
+                });
+            }
+            _ => {
+                // leave the offending token for synchronize() to look at
+                self.current -= 1;
+                return Err(self.error(&token, "Expect expression."));
+            }
+        };
+
+        Ok(Expr::Literal {
+            value,
+            span: token.span,
+        })
+    }
+
+    fn is_match(&mut self, types: &[TokenType]) -> bool {
+        for ttype in types {
+            if self.check(*ttype) {
+                self.advance();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn consume(&mut self, ttype: TokenType, message: &str) -> ParseResult<Token> {
+        if self.check(ttype) {
+            Ok(self.advance().clone())
+        } else {
+            Err(self.error(self.peek(), message))
+        }
+    }
+
+    fn check(&self, ttype: TokenType) -> bool {
+        !self.is_at_end() && self.peek().ttype == ttype
+    }
+
+    fn advance(&mut self) -> &Token {
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+        self.previous()
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.peek().ttype == TokenType::Eof
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current - 1]
+    }
+
+    fn error(&self, token: &Token, message: &str) -> Diagnostic {
+        if token.ttype == TokenType::Eof {
+            Diagnostic::new(token.span, format!("at end: {message}"))
+        } else {
+            Diagnostic::new(token.span, format!("at '{}': {message}", token.lexeme))
+        }
+    }
+
+    /// Discard tokens until we're at the start of the next statement.
+    fn synchronize(&mut self) {
+        self.advance();
+
+        while !self.is_at_end() {
+            if self.previous().ttype == TokenType::Semicolon {
+                return;
+            }
+
+            match self.peek().ttype {
+                TokenType::Fn
+                | TokenType::Import
+                | TokenType::Let
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Return
+                | TokenType::Yield => return,
+                _ => {}
+            }
+
+            self.advance();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::library::{module_source, SHIPPED};
+    use crate::script::scanner::Scanner;
+
+    fn parse(source: &str) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+        let tokens = Scanner::new(source).scan_tokens()?;
+        Parser::new(tokens).parse()
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        parse(source)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.message.clone())
+            .collect()
+    }
+
+    /// Printing a script and parsing the result has to give back the same
+    /// script: printing it again changes nothing.
+    fn round_trip(source: &str) -> String {
+        let printed = to_source(&parse(source).unwrap());
+        let reprinted = to_source(&parse(&printed).unwrap());
+        assert_eq!(printed, reprinted);
+        printed
+    }
+
+    #[test]
+    fn prints_back_what_it_read() {
+        let source = "\
+import \"ai/flee\";
+let heading = east;
+let spare;
+fn pick(a, b) {
+    if a > b {
+        return a;
+    } else if a == b {
+        return nil;
+    } else {
+        return b;
+    }
+}
+fn on_turn() {
+    let xs = [1, 2.5, \"three\", true, [north]];
+    xs[0] = -xs[1] * (2 + 3) % 4;
+    while !done and xs[0] <= 10 or false {
+        xs[0] = xs[0] + 1;
+    }
+    {
+        heading = self.position().x;
+    }
+    yield wait();
+    yield;
+    return;
+}
+";
+        assert_eq!(round_trip(source), source);
+    }
+
+    #[test]
+    fn shipped_scripts_round_trip() {
+        crate::link_scripts();
+        for path in SHIPPED {
+            round_trip(&module_source(path).unwrap());
+        }
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        for source in [
+            "(",
+            "[",
+            "-",
+            "foo(",
+            "foo[",
+            "foo.",
+            "[1, ",
+            "fn on_turn() { return move(north); }\nfoo(",
+        ] {
+            let found = errors(source);
+            assert!(
+                found.iter().any(|e| e.starts_with("at end:")),
+                "{source:?} gave {found:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_operand_is_reported_at_end() {
+        assert_eq!(errors("let x = 1 +"), vec!["at end: Expect expression."]);
+    }
+
+    #[test]
+    fn recovers_at_statement_boundaries() {
+        let found = parse("let = 1;\nlet y = 2;\nlet z = ;\nfn f() { return 1; }").unwrap_err();
+        let lines: Vec<usize> = found.iter().map(|e| e.span.line).collect();
+        assert_eq!(lines, vec![1, 3]);
+        assert_eq!(found[0].message, "at '=': Expect variable name.");
+        assert_eq!(found[1].message, "at ';': Expect expression.");
+    }
+}
@@ -0,0 +1,37 @@
+use std::ops::{AddAssign, Sub};
+use std::time::Duration;
+
+/// What running scripts has cost. The heap keeps a running total, and each
+/// script is charged the difference across every call the game makes to
+/// it; see `Script::cost`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Counters {
+    /// Instructions run, or on the tree-walker, statements and expressions
+    /// evaluated.
+    pub instructions: u64,
+    pub native_calls: u64,
+    pub allocations: u64,
+    pub time: Duration,
+}
+
+impl AddAssign for Counters {
+    fn add_assign(&mut self, other: Self) {
+        self.instructions += other.instructions;
+        self.native_calls += other.native_calls;
+        self.allocations += other.allocations;
+        self.time += other.time;
+    }
+}
+
+impl Sub for Counters {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            instructions: self.instructions - other.instructions,
+            native_calls: self.native_calls - other.native_calls,
+            allocations: self.allocations - other.allocations,
+            time: self.time - other.time,
+        }
+    }
+}
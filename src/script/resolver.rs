@@ -0,0 +1,425 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::script::api::NAMESPACES;
+use crate::script::ast::*;
+use crate::script::error::Diagnostic;
+use crate::script::natives;
+use crate::script::token::*;
+
+/// A static pass over a parsed script, run before it is allowed anywhere
+/// near a mob. It binds every variable use to the scope it was declared in
+/// (filling in the `depth` of `Variable` and `Assign`), and catches the
+/// mistakes that don't need the script to run: undefined variables, a local
+/// read in its own initializer, a `return` or `yield` outside any function,
+/// an `import` that isn't at the top level, and code that can never be
+/// reached.
+pub struct Resolver {
+    /// Local scopes, innermost last. A name maps to whether its initializer
+    /// has finished.
+    scopes: Vec<HashMap<String, bool>>,
+    globals: HashSet<String>,
+    function_depth: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    /// `known` names globals that exist before the script runs, on top of
+    /// the built-in ones.
+    pub fn new(known: &[String]) -> Self {
+        let mut globals: HashSet<String> = known.iter().cloned().collect();
+        globals.extend(natives::GLOBALS.iter().map(|n| n.name.to_string()));
+        globals.extend(NAMESPACES.iter().map(|ns| ns.name.to_string()));
+        Self {
+            scopes: Vec::new(),
+            globals,
+            function_depth: 0,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn resolve(mut self, statements: &mut [Stmt]) -> Result<(), Vec<Diagnostic>> {
+        // top-level names are visible everywhere, so that functions can call
+        // each other whichever order they are written in
+        for stmt in statements.iter() {
+            match stmt {
+                Stmt::Let { name, .. } => self.globals.insert(name.lexeme.clone()),
+                Stmt::Function(decl) => self.globals.insert(decl.name.lexeme.clone()),
+                Stmt::Import { name, .. } => self.globals.insert(name.clone()),
+                _ => false,
+            };
+        }
+
+        self.statements(statements);
+
+        if self.diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(self.diagnostics)
+        }
+    }
+
+    fn error(&mut self, span: Span, message: &str) {
+        self.diagnostics.push(Diagnostic::new(span, message));
+    }
+
+    /// Resolve a run of statements. Returns true if they always return.
+    fn statements(&mut self, statements: &mut [Stmt]) -> bool {
+        let mut returns = false;
+        let mut reported = false;
+        for stmt in statements {
+            // one report is enough for a whole run of dead code
+            if returns && !reported {
+                if let Some(span) = stmt_span(stmt) {
+                    self.error(span, "Unreachable code.");
+                }
+                reported = true;
+            }
+            returns |= self.statement(stmt);
+        }
+        returns
+    }
+
+    /// Resolve one statement. Returns true if it always returns.
+    fn statement(&mut self, stmt: &mut Stmt) -> bool {
+        match stmt {
+            Stmt::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                let returns = self.statements(statements);
+                self.scopes.pop();
+                returns
+            }
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                false
+            }
+            Stmt::Function(decl) => {
+                let decl = Arc::make_mut(decl);
+                self.declare(&decl.name);
+                self.define(&decl.name);
+                self.function(decl);
+                false
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let then_returns = self.statement(then_branch);
+                match else_branch {
+                    Some(else_branch) => self.statement(else_branch) && then_returns,
+                    None => false,
+                }
+            }
+            Stmt::Import { keyword, .. } => {
+                if !self.scopes.is_empty() {
+                    self.error(keyword.span, "Imports must be at the top level.");
+                }
+                false
+            }
+            Stmt::Let { name, initializer } => {
+                self.declare(name);
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.define(name);
+                false
+            }
+            Stmt::Return { keyword, value } => {
+                if self.function_depth == 0 {
+                    self.error(keyword.span, "Can't return from top-level code.");
+                }
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+                true
+            }
+            Stmt::While { condition, body } => {
+                self.expression(condition);
+                self.statement(body);
+                false
+            }
+            Stmt::Yield { keyword, value } => {
+                if self.function_depth == 0 {
+                    self.error(keyword.span, "Can't yield from top-level code.");
+                }
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+                false
+            }
+        }
+    }
+
+    fn function(&mut self, decl: &mut FunctionDecl) {
+        self.function_depth += 1;
+        self.scopes.push(HashMap::new());
+        for param in &decl.params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.statements(&mut decl.body);
+        self.scopes.pop();
+        self.function_depth -= 1;
+    }
+
+    fn declare(&mut self, name: &Token) {
+        let duplicate = match self.scopes.last_mut() {
+            Some(scope) => scope.insert(name.lexeme.clone(), false).is_some(),
+            None => false,
+        };
+        if duplicate {
+            self.error(
+                name.span,
+                "Already a variable with this name in this scope.",
+            );
+        }
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.lexeme.clone(), true);
+        }
+    }
+
+    /// Work out how many scopes out `name` lives, or `None` for a global.
+    fn resolve_local(&mut self, name: &Token) -> Option<usize> {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if scope.contains_key(&name.lexeme) {
+                return Some(depth);
+            }
+        }
+        if !self.globals.contains(&name.lexeme) {
+            self.error(name.span, &format!("Undefined variable '{}'.", name.lexeme));
+        }
+        None
+    }
+
+    fn expression(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Assign { name, value, depth } => {
+                self.expression(value);
+                *depth = self.resolve_local(name);
+            }
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+            Expr::Get { object, .. } => self.expression(object),
+            Expr::Grouping(expr) => self.expression(expr),
+            Expr::Index { object, index, .. } => {
+                self.expression(object);
+                self.expression(index);
+            }
+            Expr::List { elements, .. } => {
+                for element in elements {
+                    self.expression(element);
+                }
+            }
+            Expr::Literal { .. } => {}
+            Expr::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+            }
+            Expr::Unary { right, .. } => self.expression(right),
+            Expr::Variable { name, depth } => {
+                let uninitialized =
+                    self.scopes.last().and_then(|scope| scope.get(&name.lexeme)) == Some(&false);
+                if uninitialized {
+                    self.error(
+                        name.span,
+                        "Can't read local variable in its own initializer.",
+                    );
+                }
+                *depth = self.resolve_local(name);
+            }
+        }
+    }
+}
+
+/// Where to point at a statement when reporting it. An empty block has
+/// nothing to point at.
+fn stmt_span(stmt: &Stmt) -> Option<Span> {
+    match stmt {
+        Stmt::Block(statements) => statements.first().and_then(stmt_span),
+        Stmt::Expression(expr) => Some(expr.span()),
+        Stmt::Function(decl) => Some(decl.name.span),
+        Stmt::If { condition, .. } | Stmt::While { condition, .. } => Some(condition.span()),
+        Stmt::Let { name, .. } => Some(name.span),
+        Stmt::Return { keyword, .. }
+        | Stmt::Yield { keyword, .. }
+        | Stmt::Import { keyword, .. } => Some(keyword.span),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::parser::Parser;
+    use crate::script::scanner::Scanner;
+
+    fn resolve(source: &str) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+        let tokens = Scanner::new(source).scan_tokens()?;
+        let mut statements = Parser::new(tokens).parse()?;
+        Resolver::new(&[]).resolve(&mut statements)?;
+        Ok(statements)
+    }
+
+    fn errors(source: &str) -> Vec<Diagnostic> {
+        resolve(source).unwrap_err()
+    }
+
+    fn at(line: usize, col: usize, len: usize, message: &str) -> Diagnostic {
+        Diagnostic::new(Span { line, col, len }, message)
+    }
+
+    /// Every variable read, in the order they appear, with the depth the
+    /// resolver gave it.
+    fn depths(statements: &[Stmt]) -> Vec<(String, Option<usize>)> {
+        fn stmt(s: &Stmt, out: &mut Vec<(String, Option<usize>)>) {
+            match s {
+                Stmt::Block(body) => body.iter().for_each(|s| stmt(s, out)),
+                Stmt::Function(decl) => decl.body.iter().for_each(|s| stmt(s, out)),
+                Stmt::Expression(e) => expr(e, out),
+                Stmt::Let {
+                    initializer: Some(e),
+                    ..
+                }
+                | Stmt::Return { value: Some(e), .. } => expr(e, out),
+                _ => {}
+            }
+        }
+        fn expr(e: &Expr, out: &mut Vec<(String, Option<usize>)>) {
+            match e {
+                Expr::Variable { name, depth } => out.push((name.lexeme.clone(), *depth)),
+                Expr::Binary { left, right, .. } => {
+                    expr(left, out);
+                    expr(right, out);
+                }
+                _ => {}
+            }
+        }
+        let mut out = Vec::new();
+        statements.iter().for_each(|s| stmt(s, &mut out));
+        out
+    }
+
+    #[test]
+    fn undefined_variables_are_reported() {
+        assert_eq!(
+            errors("fn f() { return nope; }"),
+            [at(1, 17, 4, "Undefined variable 'nope'.")]
+        );
+    }
+
+    #[test]
+    fn a_local_cannot_read_itself() {
+        assert_eq!(
+            errors("fn f() { let a = a; }"),
+            [at(
+                1,
+                18,
+                1,
+                "Can't read local variable in its own initializer."
+            )]
+        );
+    }
+
+    #[test]
+    fn locals_cannot_be_declared_twice() {
+        assert_eq!(
+            errors("fn f() {\n    let a = 1;\n    let a = 2;\n}"),
+            [at(
+                3,
+                9,
+                1,
+                "Already a variable with this name in this scope."
+            )]
+        );
+        // shadowing in an inner scope is fine
+        assert!(resolve("fn f() { let a = 1; { let a = 2; } }").is_ok());
+    }
+
+    #[test]
+    fn return_and_yield_need_a_function() {
+        assert_eq!(
+            errors("return 1;"),
+            [at(1, 1, 6, "Can't return from top-level code.")]
+        );
+        assert_eq!(
+            errors("if true {\n    yield wait();\n}"),
+            [at(2, 5, 5, "Can't yield from top-level code.")]
+        );
+    }
+
+    #[test]
+    fn imports_must_be_at_the_top_level() {
+        assert_eq!(
+            errors("fn f() {\n    import \"ai/flee\";\n}"),
+            [at(2, 5, 6, "Imports must be at the top level.")]
+        );
+    }
+
+    #[test]
+    fn unreachable_code_is_reported_once() {
+        assert_eq!(
+            errors("fn f() {\n    return 1;\n    let late = 1;\n    wait();\n}"),
+            [at(3, 9, 4, "Unreachable code.")]
+        );
+        // both branches return, so nothing after the `if` can run
+        assert_eq!(
+            errors("fn f(a) {\n    if a { return 1; } else { return 2; }\n    let late = 1;\n}"),
+            [at(3, 9, 4, "Unreachable code.")]
+        );
+    }
+
+    #[test]
+    fn closures_see_their_enclosing_scopes() {
+        let source = "\
+let g = 1;
+fn outer(a) {
+    let b = a;
+    fn inner() {
+        let c = b;
+        {
+            let d = c;
+            return a + d + g;
+        }
+    }
+    return inner;
+}";
+        let statements = resolve(source).unwrap();
+        let names = |list: &[(&str, Option<usize>)]| {
+            list.iter()
+                .map(|(n, d)| (n.to_string(), *d))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            depths(&statements),
+            names(&[
+                ("a", Some(0)),
+                ("b", Some(1)),
+                ("c", Some(1)),
+                ("a", Some(2)),
+                ("d", Some(0)),
+                ("g", None),
+                ("inner", Some(0)),
+            ])
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use crate::script::error::Diagnostic;
+use crate::script::token::*;
+
+pub struct Scanner {
+    source: Vec<char>,
+    start: usize,
+    current: usize,
+    line: usize,
+    col: usize,
+    start_col: usize,
+    at_end: bool,
+}
+
+impl Scanner {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.chars().collect(),
+            start: 0,
+            current: 0,
+            line: 1,
+            col: 1,
+            start_col: 1,
+            at_end: false,
+        }
+    }
+
+    /// Scan the whole source. Scanning carries on past a bad character so
+    /// that every lexical error is reported, not just the first one.
+    pub fn scan_tokens(self) -> Result<Vec<Token>, Vec<Diagnostic>> {
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+
+        for result in self {
+            match result {
+                Ok(token) => tokens.push(token),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current >= self.source.len()
+    }
+
+    fn advance(&mut self) -> char {
+        let c = self.source[self.current];
+        self.current += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        c
+    }
+
+    fn is_match(&mut self, expected: char) -> bool {
+        if self.peek() == expected {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn peek(&self) -> char {
+        self.source.get(self.current).copied().unwrap_or('\0')
+    }
+
+    fn peek_next(&self) -> char {
+        self.source.get(self.current + 1).copied().unwrap_or('\0')
+    }
+
+    fn lexeme(&self) -> String {
+        self.source[self.start..self.current].iter().collect()
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            col: self.start_col,
+            len: self.current - self.start,
+        }
+    }
+
+    fn make_token(&self, ttype: TokenType) -> Token {
+        Token::new(ttype, self.lexeme(), self.span())
+    }
+
+    fn error<T: ToString>(&self, message: T) -> Diagnostic {
+        Diagnostic::new(self.span(), message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                ' ' | '\r' | '\t' | '\n' => {
+                    self.advance();
+                }
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<Token, Diagnostic> {
+        while self.peek() != '"' && self.peek() != '\n' && !self.is_at_end() {
+            self.advance();
+        }
+
+        if self.peek() != '"' {
+            return Err(self.error("Unterminated string."));
+        }
+
+        self.advance();
+        Ok(self.make_token(TokenType::String))
+    }
+
+    fn number(&mut self) -> Token {
+        while self.peek().is_ascii_digit() {
+            self.advance();
+        }
+
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
+            self.advance();
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
+        }
+
+        self.make_token(TokenType::Number)
+    }
+
+    fn identifier(&mut self) -> Token {
+        while is_alpha(self.peek()) || self.peek().is_ascii_digit() {
+            self.advance();
+        }
+
+        let ttype = TokenType::keyword(&self.lexeme()).unwrap_or(TokenType::Identifier);
+        self.make_token(ttype)
+    }
+
+    fn scan_token(&mut self) -> Result<Token, Diagnostic> {
+        self.skip_whitespace();
+        self.start = self.current;
+        self.start_col = self.col;
+
+        if self.is_at_end() {
+            return Ok(self.make_token(TokenType::Eof));
+        }
+
+        let c = self.advance();
+        let ttype = match c {
+            '(' => TokenType::LeftParen,
+            ')' => TokenType::RightParen,
+            '{' => TokenType::LeftBrace,
+            '}' => TokenType::RightBrace,
+            '[' => TokenType::LeftBracket,
+            ']' => TokenType::RightBracket,
+            ',' => TokenType::Comma,
+            '.' => TokenType::Dot,
+            '-' => TokenType::Minus,
+            '+' => TokenType::Plus,
+            ';' => TokenType::Semicolon,
+            '/' => TokenType::Slash,
+            '*' => TokenType::Star,
+            '%' => TokenType::Percent,
+            '!' if self.is_match('=') => TokenType::BangEqual,
+            '!' => TokenType::Bang,
+            '=' if self.is_match('=') => TokenType::EqualEqual,
+            '=' => TokenType::Equal,
+            '<' if self.is_match('=') => TokenType::LessEqual,
+            '<' => TokenType::Less,
+            '>' if self.is_match('=') => TokenType::GreaterEqual,
+            '>' => TokenType::Greater,
+            '"' => return self.string(),
+            c if c.is_ascii_digit() => return Ok(self.number()),
+            c if is_alpha(c) => return Ok(self.identifier()),
+            c => return Err(self.error(format!("Unexpected character '{c}'."))),
+        };
+
+        Ok(self.make_token(ttype))
+    }
+}
+
+/// Yields tokens one at a time, ending with a single `Eof` token. Lexical
+/// errors are yielded in place of the token they spoil, so a caller that
+/// only wants to colour the source (such as an editor) can skip over them.
+impl Iterator for Scanner {
+    type Item = Result<Token, Diagnostic>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.at_end {
+            return None;
+        }
+
+        let result = self.scan_token();
+        if let Ok(Token {
+            ttype: TokenType::Eof,
+            ..
+        }) = result
+        {
+            self.at_end = true;
+        }
+        Some(result)
+    }
+}
+
+fn is_alpha(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::Direction;
+
+    fn types(source: &str) -> Vec<TokenType> {
+        Scanner::new(source)
+            .scan_tokens()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.ttype)
+            .collect()
+    }
+
+    #[test]
+    fn punctuation() {
+        use TokenType::*;
+        assert_eq!(
+            types("(){}[],.-+;/*%"),
+            vec![
+                LeftParen,
+                RightParen,
+                LeftBrace,
+                RightBrace,
+                LeftBracket,
+                RightBracket,
+                Comma,
+                Dot,
+                Minus,
+                Plus,
+                Semicolon,
+                Slash,
+                Star,
+                Percent,
+                Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn operators() {
+        use TokenType::*;
+        assert_eq!(
+            types("! != = == > >= < <="),
+            vec![
+                Bang,
+                BangEqual,
+                Equal,
+                EqualEqual,
+                Greater,
+                GreaterEqual,
+                Less,
+                LessEqual,
+                Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn literals() {
+        let tokens = Scanner::new(r#"rat_2 "hello" 42 3.5 7."#)
+            .scan_tokens()
+            .unwrap();
+        let found: Vec<(TokenType, &str)> = tokens
+            .iter()
+            .map(|t| (t.ttype, t.lexeme.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (TokenType::Identifier, "rat_2"),
+                (TokenType::String, "\"hello\""),
+                (TokenType::Number, "42"),
+                (TokenType::Number, "3.5"),
+                // a trailing dot is not part of the number
+                (TokenType::Number, "7"),
+                (TokenType::Dot, "."),
+                (TokenType::Eof, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn keywords() {
+        use TokenType::*;
+        assert_eq!(
+            types("and else false fn if import let nil or return true while yield"),
+            vec![And, Else, False, Fn, If, Import, Let, Nil, Or, Return, True, While, Yield, Eof,]
+        );
+        // a keyword inside a longer word is just an identifier
+        assert_eq!(types("iffy lettuce"), vec![Identifier, Identifier, Eof]);
+    }
+
+    #[test]
+    fn directions() {
+        let expected = [
+            ("north", Direction::North),
+            ("south", Direction::South),
+            ("east", Direction::East),
+            ("west", Direction::West),
+            ("northwest", Direction::NorthWest),
+            ("northeast", Direction::NorthEast),
+            ("southwest", Direction::SouthWest),
+            ("southeast", Direction::SouthEast),
+        ];
+        for (word, dir) in expected {
+            assert_eq!(
+                types(word),
+                vec![TokenType::Direction(dir), TokenType::Eof],
+                "{word}"
+            );
+        }
+        assert_eq!(
+            types("northerly"),
+            vec![TokenType::Identifier, TokenType::Eof]
+        );
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        use TokenType::*;
+        assert_eq!(
+            types("a // b c\n/ d"),
+            vec![Identifier, Slash, Identifier, Eof]
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let tokens = Scanner::new("let x = 10;\n  move(north)")
+            .scan_tokens()
+            .unwrap();
+        let spans: Vec<(usize, usize, usize)> = tokens
+            .iter()
+            .map(|t| (t.span.line, t.span.col, t.span.len))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (1, 1, 3),
+                (1, 5, 1),
+                (1, 7, 1),
+                (1, 9, 2),
+                (1, 11, 1),
+                (2, 3, 4),
+                (2, 7, 1),
+                (2, 8, 5),
+                (2, 13, 1),
+                (2, 14, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let errors = Scanner::new("say(\"hello\nfoo").scan_tokens().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Unterminated string.");
+        assert_eq!((errors[0].span.line, errors[0].span.col), (1, 5));
+
+        let errors = Scanner::new("\"never closed").scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Unterminated string.");
+    }
+
+    #[test]
+    fn unknown_characters_are_values() {
+        let results: Vec<Result<Token, Diagnostic>> = Scanner::new("a @ b #").collect();
+        assert_eq!(results.len(), 5);
+        assert!(results[0].is_ok());
+        let error = results[1].as_ref().unwrap_err();
+        assert_eq!(error.message, "Unexpected character '@'.");
+        assert_eq!((error.span.line, error.span.col), (1, 3));
+        // scanning carries on past the bad character
+        assert_eq!(results[2].as_ref().unwrap().lexeme, "b");
+        assert!(results[3].is_err());
+        assert_eq!(results[4].as_ref().unwrap().ttype, TokenType::Eof);
+
+        let errors = Scanner::new("a @ b #").scan_tokens().unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
+}
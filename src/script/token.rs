@@ -0,0 +1,124 @@
+use std::fmt;
+
+use crate::map::Direction;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TokenType {
+    // single-character tokens
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Dot,
+    Minus,
+    Plus,
+    Semicolon,
+    Slash,
+    Star,
+    Percent,
+
+    // one or two character tokens
+    Bang,
+    BangEqual,
+    Equal,
+    EqualEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+
+    // literals
+    Identifier,
+    String,
+    Number,
+
+    // keywords
+    And,
+    Else,
+    False,
+    Fn,
+    If,
+    Import,
+    Let,
+    Nil,
+    Or,
+    Return,
+    True,
+    While,
+    Yield,
+
+    // game words
+    Direction(Direction),
+
+    Eof,
+}
+
+impl TokenType {
+    pub fn keyword(word: &str) -> Option<Self> {
+        Some(match word {
+            "and" => TokenType::And,
+            "else" => TokenType::Else,
+            "false" => TokenType::False,
+            "fn" => TokenType::Fn,
+            "if" => TokenType::If,
+            "import" => TokenType::Import,
+            "let" => TokenType::Let,
+            "nil" => TokenType::Nil,
+            "or" => TokenType::Or,
+            "return" => TokenType::Return,
+            "true" => TokenType::True,
+            "while" => TokenType::While,
+            "yield" => TokenType::Yield,
+            "north" => TokenType::Direction(Direction::North),
+            "south" => TokenType::Direction(Direction::South),
+            "east" => TokenType::Direction(Direction::East),
+            "west" => TokenType::Direction(Direction::West),
+            "northwest" => TokenType::Direction(Direction::NorthWest),
+            "northeast" => TokenType::Direction(Direction::NorthEast),
+            "southwest" => TokenType::Direction(Direction::SouthWest),
+            "southeast" => TokenType::Direction(Direction::SouthEast),
+            _ => return None,
+        })
+    }
+}
+
+/// Location of a piece of source text. Lines and columns start at 1, `len`
+/// is counted in characters.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub ttype: TokenType,
+    pub lexeme: String,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn new(ttype: TokenType, lexeme: String, span: Span) -> Self {
+        Self {
+            ttype,
+            lexeme,
+            span,
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} '{}' at {}", self.ttype, self.lexeme, self.span)
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+
+use bevy_ecs::prelude::*;
+
+use crate::components::{Effect, Position};
+use crate::map::Direction;
+use crate::script::api::{GameView, Namespace};
+use crate::script::ast::direction_name;
+use crate::script::heap::{Heap, ObjRef};
+
+/// What a mob wants to do this turn. Scripts never change the world
+/// directly; they hand one of these back and the game carries it out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Intent {
+    Move(Direction),
+    Wait,
+    /// Show a line of text (an interned string) in the message log.
+    Say(ObjRef),
+    /// Hurt a mob, or the player. Only devices can do this so far.
+    Damage(Entity, i32),
+    /// Put a mob, or the player, somewhere else on the map.
+    Teleport(Entity, Position),
+    /// Swap a mob's script for the module at a path (an interned string),
+    /// as `import` would find it.
+    Reprogram(Entity, ObjRef),
+    /// Put a status effect on a mob, or the player, for a number of turns.
+    Afflict(Entity, Effect, i32),
+}
+
+impl fmt::Display for Intent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Intent::Move(dir) => write!(f, "<move {}>", direction_name(*dir)),
+            Intent::Wait => write!(f, "<wait>"),
+            Intent::Say(_) => write!(f, "<say>"),
+            Intent::Damage(target, amount) => write!(f, "<damage {} by {amount}>", target.id()),
+            Intent::Teleport(target, p) => {
+                write!(f, "<teleport {} to ({}, {})>", target.id(), p.x, p.y)
+            }
+            Intent::Reprogram(target, _) => write!(f, "<reprogram {}>", target.id()),
+            Intent::Afflict(target, effect, turns) => {
+                write!(f, "<{} {} for {turns}>", effect.name(), target.id())
+            }
+        }
+    }
+}
+
+pub type NativeFn = fn(&mut Heap, &mut GameView, &[Value]) -> Result<Value, String>;
+
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Direction(Direction),
+    Intent(Intent),
+    Position(Position),
+    Entity(Entity),
+    Native(&'static Native),
+    Namespace(&'static Namespace),
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn type_name(&self, heap: &Heap) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::Direction(_) => "direction",
+            Value::Intent(_) => "intent",
+            Value::Position(_) => "position",
+            Value::Entity(_) => "entity",
+            Value::Native(_) => "function",
+            Value::Namespace(_) => "namespace",
+            Value::Obj(r) => heap.get(*r).type_name(),
+        }
+    }
+}
+
+/// Strings are interned, so every heap value can be compared by reference.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Direction(a), Value::Direction(b)) => a == b,
+            (Value::Intent(a), Value::Intent(b)) => a == b,
+            (Value::Position(a), Value::Position(b)) => a == b,
+            (Value::Entity(a), Value::Entity(b)) => a == b,
+            (Value::Native(a), Value::Native(b)) => std::ptr::eq(*a, *b),
+            (Value::Namespace(a), Value::Namespace(b)) => std::ptr::eq(*a, *b),
+            (Value::Obj(a), Value::Obj(b)) => a == b,
+            _ => false,
+        }
+    }
+}
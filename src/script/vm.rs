@@ -0,0 +1,576 @@
+use std::sync::Arc;
+
+use crate::script::api::GameView;
+use crate::script::chunk::*;
+use crate::script::compiler::direction_from_byte;
+use crate::script::debug::{Breakpoints, Pause};
+use crate::script::error::RuntimeError;
+use crate::script::heap::*;
+use crate::script::ops::*;
+use crate::script::value::*;
+
+/// Deepest a script may recurse before we give up on it.
+const FRAMES_MAX: usize = 64;
+
+struct CallFrame {
+    closure: ObjRef,
+    function: Arc<FunctionProto>,
+    globals: ObjRef,
+    ip: usize,
+    slots: usize,
+}
+
+/// A call that stopped at a `yield`, put aside so that it can carry on from
+/// the same spot later. Frames and upvalues point into the stack by index,
+/// so all three are kept together.
+pub struct Coroutine {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    open_upvalues: Vec<ObjRef>,
+    last_line: Option<(usize, usize)>,
+}
+
+impl Coroutine {
+    /// Every heap value the suspended call still needs.
+    pub fn roots(&self) -> impl Iterator<Item = Value> + '_ {
+        let closures = self.frames.iter().map(|frame| frame.closure);
+        let objects = closures.chain(self.open_upvalues.iter().copied());
+        self.stack.iter().copied().chain(objects.map(Value::Obj))
+    }
+}
+
+/// A stack-based virtual machine for compiled scripts.
+pub struct Vm<'a, 'w> {
+    heap: &'a mut Heap,
+    game: &'a mut GameView<'w>,
+    globals: ObjRef,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    open_upvalues: Vec<ObjRef>,
+    fuel: u32,
+    /// What the last `yield` handed back, until the caller picks it up.
+    yielded: Option<Value>,
+    breakpoints: Option<Breakpoints>,
+    /// The call depth and source line of the last instruction, so that a
+    /// breakpoint stops once per visit to its line rather than on every
+    /// instruction.
+    last_line: Option<(usize, usize)>,
+    paused: bool,
+}
+
+type VmResult<T> = Result<T, RuntimeError>;
+
+impl<'a, 'w> Vm<'a, 'w> {
+    /// `fuel` is how many instructions may run before the script is stopped.
+    pub fn new(heap: &'a mut Heap, game: &'a mut GameView<'w>, globals: ObjRef, fuel: u32) -> Self {
+        Self {
+            heap,
+            game,
+            globals,
+            stack: Vec::with_capacity(256),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            open_upvalues: Vec::new(),
+            fuel,
+            yielded: None,
+            breakpoints: None,
+            last_line: None,
+            paused: false,
+        }
+    }
+
+    /// Stop before running any line in `breakpoints`. The call is left
+    /// suspended, just as if it had yielded nothing.
+    pub fn with_breakpoints(mut self, breakpoints: Option<Breakpoints>) -> Self {
+        self.breakpoints = breakpoints;
+        self
+    }
+
+    /// Run the top level of a compiled script. Nothing would ever resume a
+    /// top level that yielded, whether it was the top level itself or a
+    /// function it called, so that is an error.
+    pub fn run_script(&mut self, script: Arc<FunctionProto>) -> VmResult<()> {
+        let closure = self.heap.alloc(Obj::Closure(Closure {
+            function: script,
+            upvalues: Vec::new(),
+            globals: self.globals,
+        }));
+        self.call_value(Value::Obj(closure), &[])?;
+        if !self.frames.is_empty() {
+            return Err(self.error("Only on_turn() can yield, not top-level code."));
+        }
+        Ok(())
+    }
+
+    /// Call a function defined in the script's global scope. Returns `None`
+    /// if the script doesn't define it.
+    pub fn call_global(&mut self, name: &str, args: &[Value]) -> VmResult<Option<Value>> {
+        match self.heap.env(self.globals).values.get(name) {
+            Some(callee) => {
+                let callee = *callee;
+                self.call_value(callee, args).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Call any callable value from Rust and run it until it returns or
+    /// yields. Either way, the value it handed back is returned; after a
+    /// yield, `suspend` takes what is needed to carry on.
+    pub fn call_value(&mut self, callee: Value, args: &[Value]) -> VmResult<Value> {
+        let base = self.frames.len();
+        self.stack.push(callee);
+        self.stack.extend_from_slice(args);
+        self.call(callee, args.len())?;
+        if self.frames.len() > base {
+            self.run(base)?;
+        }
+        Ok(self.finish())
+    }
+
+    /// Carry on with a call that yielded earlier, from just after its
+    /// `yield`, until it returns or yields again.
+    pub fn resume(&mut self, coroutine: Coroutine) -> VmResult<Value> {
+        self.stack = coroutine.stack;
+        self.frames = coroutine.frames;
+        self.open_upvalues = coroutine.open_upvalues;
+        self.last_line = coroutine.last_line;
+        self.run(0)?;
+        Ok(self.finish())
+    }
+
+    /// Take the suspended call out of the VM, if the last call yielded.
+    pub fn suspend(self) -> Option<Coroutine> {
+        if self.frames.is_empty() {
+            return None;
+        }
+        Some(Coroutine {
+            stack: self.stack,
+            frames: self.frames,
+            open_upvalues: self.open_upvalues,
+            last_line: self.last_line,
+        })
+    }
+
+    /// Where the last call stopped at a breakpoint, if it did.
+    pub fn pause(&self) -> Option<Pause> {
+        if !self.paused {
+            return None;
+        }
+        let frame = self.frames.last()?;
+        let locals = frame
+            .function
+            .locals
+            .iter()
+            .filter(|local| (local.start..local.end).contains(&frame.ip))
+            .filter_map(|local| {
+                let value = self.stack.get(frame.slots + local.slot)?;
+                Some((local.name.clone(), self.heap.format(*value)))
+            })
+            .collect();
+        let function = match frame.function.name.as_str() {
+            "" => "<script>".to_string(),
+            name => name.to_string(),
+        };
+        Some(Pause {
+            line: frame.function.chunk.lines[frame.ip],
+            function,
+            locals,
+        })
+    }
+
+    fn finish(&mut self) -> Value {
+        if self.paused {
+            return Value::Nil;
+        }
+        match self.yielded.take() {
+            Some(value) => value,
+            None => self.stack.pop().unwrap_or(Value::Nil),
+        }
+    }
+
+    /// Whether the next instruction starts a line the debugger wants to
+    /// stop on.
+    fn at_breakpoint(&mut self) -> bool {
+        let breakpoints = match &self.breakpoints {
+            Some(breakpoints) => breakpoints,
+            None => return false,
+        };
+        let frame = self.frames.last().unwrap();
+        let here = (self.frames.len(), frame.function.chunk.lines[frame.ip]);
+        if self.last_line == Some(here) {
+            return false;
+        }
+        self.last_line = Some(here);
+        breakpoints.stepping || breakpoints.lines.contains(&here.1)
+    }
+
+    /// Collect garbage with everything the VM is working on as roots. This
+    /// only happens between instructions, when every value in use is on the
+    /// stack.
+    fn collect_garbage(&mut self) {
+        let mut roots = self.stack.clone();
+        roots.extend(self.frames.iter().map(|frame| Value::Obj(frame.closure)));
+        roots.extend(self.open_upvalues.iter().map(|r| Value::Obj(*r)));
+        roots.push(Value::Obj(self.globals));
+        self.heap.collect(&roots);
+    }
+
+    /// The global scope of the function currently running.
+    fn frame_globals(&self) -> ObjRef {
+        self.frames.last().unwrap().globals
+    }
+
+    /// Line of the instruction currently executing.
+    pub fn line(&self) -> usize {
+        match self.frames.last() {
+            Some(frame) => frame.function.chunk.lines[frame.ip.saturating_sub(1)],
+            None => 0,
+        }
+    }
+
+    fn error<T: ToString>(&self, message: T) -> RuntimeError {
+        RuntimeError::new(self.line(), message)
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn call(&mut self, callee: Value, arg_count: usize) -> VmResult<()> {
+        match callee {
+            Value::Native(native) => {
+                if native.arity != arg_count {
+                    return Err(self.error(format!(
+                        "Expected {} arguments but got {arg_count}.",
+                        native.arity
+                    )));
+                }
+                let start = self.stack.len() - arg_count;
+                self.heap.counters.native_calls += 1;
+                let result = (native.function)(self.heap, self.game, &self.stack[start..])
+                    .map_err(|e| self.error(e))?;
+                self.stack.truncate(start - 1);
+                self.stack.push(result);
+                Ok(())
+            }
+            Value::Obj(r) => {
+                let (function, globals) = match self.heap.get(r) {
+                    Obj::Closure(closure) => (closure.function.clone(), closure.globals),
+                    _ => return Err(self.error("Can only call functions.")),
+                };
+                if function.arity != arg_count {
+                    return Err(self.error(format!(
+                        "Expected {} arguments but got {arg_count}.",
+                        function.arity
+                    )));
+                }
+                if self.frames.len() >= FRAMES_MAX {
+                    return Err(self.error("Stack overflow."));
+                }
+                self.frames.push(CallFrame {
+                    closure: r,
+                    function,
+                    globals,
+                    ip: 0,
+                    slots: self.stack.len() - arg_count - 1,
+                });
+                Ok(())
+            }
+            _ => Err(self.error("Can only call functions.")),
+        }
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        for r in &self.open_upvalues {
+            if let Obj::Upvalue(Upvalue::Open(s)) = self.heap.get(*r) {
+                if *s == slot {
+                    return *r;
+                }
+            }
+        }
+        let r = self.heap.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push(r);
+        r
+    }
+
+    fn close_upvalues(&mut self, last: usize) {
+        let heap = &mut *self.heap;
+        let stack = &self.stack;
+        self.open_upvalues.retain(|r| match heap.get_mut(*r) {
+            Obj::Upvalue(upvalue) => match *upvalue {
+                Upvalue::Open(slot) if slot >= last => {
+                    *upvalue = Upvalue::Closed(stack[slot]);
+                    false
+                }
+                _ => true,
+            },
+            _ => false,
+        });
+    }
+
+    fn upvalue(&self, index: usize) -> ObjRef {
+        let frame = self.frames.last().unwrap();
+        match self.heap.get(frame.closure) {
+            Obj::Closure(closure) => closure.upvalues[index],
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().unwrap();
+        let byte = frame.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_short(&mut self) -> usize {
+        let hi = self.read_byte() as usize;
+        let lo = self.read_byte() as usize;
+        (hi << 8) | lo
+    }
+
+    fn read_constant(&mut self) -> Constant {
+        let idx = self.read_byte() as usize;
+        self.frames.last().unwrap().function.chunk.constants[idx].clone()
+    }
+
+    fn read_string(&mut self) -> Arc<str> {
+        match self.read_constant() {
+            Constant::Str(s) => s,
+            other => panic!("expected a string constant, found {other:?}"),
+        }
+    }
+
+    /// Execute until the frame stack drops back to `base` frames, or until
+    /// something yields.
+    fn run(&mut self, base: usize) -> VmResult<()> {
+        loop {
+            if self.at_breakpoint() {
+                self.paused = true;
+                return Ok(());
+            }
+            if self.fuel == 0 {
+                return Err(RuntimeError::out_of_fuel(self.line()));
+            }
+            self.fuel -= 1;
+            self.heap.counters.instructions += 1;
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+
+            let byte = self.read_byte();
+            let op = match OpCode::from_byte(byte) {
+                Some(op) => op,
+                None => return Err(self.error(format!("Unknown opcode {byte}."))),
+            };
+
+            if let Some(binary) = op.binary_op() {
+                let b = self.pop();
+                let a = self.pop();
+                let result = binary.apply(self.heap, a, b).map_err(|e| self.error(e))?;
+                self.stack.push(result);
+                continue;
+            }
+
+            match op {
+                OpCode::Constant => {
+                    let value = match self.read_constant() {
+                        Constant::Number(n) => Value::Number(n),
+                        Constant::Str(s) => Value::Obj(self.heap.intern(&s)),
+                        Constant::Function(_) => unreachable!("functions load through Closure"),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Bool(true)),
+                OpCode::False => self.stack.push(Value::Bool(false)),
+                OpCode::Direction => {
+                    let dir = direction_from_byte(self.read_byte());
+                    self.stack.push(Value::Direction(dir));
+                }
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::PopN => {
+                    let count = self.read_byte() as usize;
+                    self.stack.truncate(self.stack.len() - count);
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frames.last().unwrap().slots;
+                    self.stack.push(self.stack[base + slot]);
+                }
+                OpCode::SetLocal | OpCode::StoreLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frames.last().unwrap().slots;
+                    self.stack[base + slot] = self.peek(0);
+                    if op == OpCode::StoreLocal {
+                        self.pop();
+                    }
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    match self.heap.env(self.frame_globals()).values.get(&*name) {
+                        Some(value) => {
+                            let value = *value;
+                            self.stack.push(value);
+                        }
+                        None => {
+                            return Err(self.error(format!("Undefined variable '{name}'.")));
+                        }
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    let value = self.pop();
+                    let globals = self.frame_globals();
+                    self.heap
+                        .env_mut(globals)
+                        .values
+                        .insert(name.to_string(), value);
+                }
+                OpCode::SetGlobal | OpCode::StoreGlobal => {
+                    let name = self.read_string();
+                    let value = self.peek(0);
+                    let globals = self.frame_globals();
+                    match self.heap.env_mut(globals).values.get_mut(&*name) {
+                        Some(slot) => *slot = value,
+                        None => {
+                            return Err(self.error(format!("Undefined variable '{name}'.")));
+                        }
+                    }
+                    if op == OpCode::StoreGlobal {
+                        self.pop();
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let value = match self.heap.get(self.upvalue(index)) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Obj::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => unreachable!(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let value = self.peek(0);
+                    let r = self.upvalue(index);
+                    match self.heap.get_mut(r) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => {
+                            let slot = *slot;
+                            self.stack[slot] = value;
+                        }
+                        Obj::Upvalue(upvalue) => *upvalue = Upvalue::Closed(value),
+                        _ => unreachable!(),
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    let object = self.pop();
+                    let value =
+                        get_property(self.heap, object, &name).map_err(|e| self.error(e))?;
+                    self.stack.push(value);
+                }
+                OpCode::GetIndex => {
+                    let index = self.pop();
+                    let list = self.pop();
+                    let value = index_get(self.heap, list, index).map_err(|e| self.error(e))?;
+                    self.stack.push(value);
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let list = self.pop();
+                    index_set(self.heap, list, index, value).map_err(|e| self.error(e))?;
+                    self.stack.push(value);
+                }
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Bool(value.is_falsey()));
+                }
+                OpCode::Negate => {
+                    let value = self.pop();
+                    let result = negate(value).map_err(|e| self.error(e))?;
+                    self.stack.push(result);
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.frames.last_mut().unwrap().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.frames.last_mut().unwrap().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.frames.last_mut().unwrap().ip -= offset;
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.peek(arg_count);
+                    self.call(callee, arg_count)?;
+                }
+                OpCode::Closure => {
+                    let function = match self.read_constant() {
+                        Constant::Function(function) => function,
+                        other => panic!("expected a function constant, found {other:?}"),
+                    };
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let base = self.frames.last().unwrap().slots;
+                            upvalues.push(self.capture_upvalue(base + index));
+                        } else {
+                            upvalues.push(self.upvalue(index));
+                        }
+                    }
+                    let globals = self.frame_globals();
+                    let closure = self.heap.alloc(Obj::Closure(Closure {
+                        function,
+                        upvalues,
+                        globals,
+                    }));
+                    self.stack.push(Value::Obj(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::List => {
+                    let count = self.read_byte() as usize;
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    let list = self.heap.alloc(Obj::List(items));
+                    self.stack.push(Value::Obj(list));
+                }
+                OpCode::Return | OpCode::ReturnNil => {
+                    let result = if op == OpCode::Return {
+                        self.pop()
+                    } else {
+                        Value::Nil
+                    };
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    self.stack.push(result);
+                    if self.frames.len() == base {
+                        return Ok(());
+                    }
+                }
+                OpCode::Yield => {
+                    self.yielded = Some(self.pop());
+                    return Ok(());
+                }
+                _ => unreachable!("binary operators are handled above"),
+            }
+        }
+    }
+}
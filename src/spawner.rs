@@ -0,0 +1,192 @@
+use std::sync::Arc;
+
+use bevy_ecs::event::Events;
+use bevy_ecs::prelude::*;
+use bracket_lib::prelude::*;
+
+use crate::actions::MeleeEvent;
+use crate::combat::DealDamage;
+use crate::components::*;
+use crate::drawable::DrawList;
+use crate::game_state::{RunSummary, Selection};
+use crate::keyboard::KeyboardEvent;
+use crate::map::*;
+use crate::messages::Messages;
+use crate::scheduler::Scheduler;
+use crate::script::*;
+use crate::system::Viewshed;
+use crate::{random_walkable, HEIGHT, WIDTH};
+
+/// Set up a fresh run: a new map from the `MapFactory`, with the player,
+/// their devices and some mobs on it. Anything left over from the last run
+/// is cleared away first, but the tools (the console, the debugger, the
+/// profiler and so on) carry on as they were.
+pub fn start_run(world: &mut World) {
+    // despawned one at a time, rather than cleared, so the old run's
+    // entities can't be mistaken for the new one's
+    let old: Vec<Entity> = world.query::<Entity>().iter(world).collect();
+    for entity in old {
+        world.despawn(entity);
+    }
+    world
+        .get_resource_mut::<Events<KeyboardEvent>>()
+        .unwrap()
+        .clear();
+    world
+        .get_resource_mut::<Events<MeleeEvent>>()
+        .unwrap()
+        .clear();
+    world
+        .get_resource_mut::<Events<DealDamage>>()
+        .unwrap()
+        .clear();
+    world.insert_resource(DrawList { items: Vec::new() });
+    world.insert_resource(Scheduler::default());
+    world.insert_resource(Messages::default());
+    world.insert_resource(Selection::default());
+    world.insert_resource(RunSummary::default());
+
+    let mut map = world.resource_scope(|world, factory: Mut<MapFactory>| {
+        factory.create_map(world, WIDTH * 2, HEIGHT * 2)
+    });
+    let starting_position = map.center_of();
+
+    // temporarly spawn some mobs
+    let mut rng = RandomNumberGenerator::new();
+    for _ in 0..10 {
+        let pos = random_walkable(&map, &mut rng);
+        let id = world
+            .spawn()
+            .insert(Mob { glyph: 'r' })
+            .insert(Stats::new(2, 2).with_attack(0, DiceType::new(1, 3, 0), 0))
+            .insert(Speed::new(NORMAL_SPEED))
+            .insert(Viewshed::new(2))
+            .insert(pos)
+            .insert(Name {
+                name: "Rat".to_string(),
+            })
+            .insert(Blackboard::default())
+            .id();
+        map.add_entity(&pos, id);
+    }
+
+    let sentry = Arc::new(
+        Program::new("sentry", include_str!("../scripts/sentry.prl"))
+            .expect("sentry script should compile"),
+    );
+    for _ in 0..3 {
+        let pos = random_walkable(&map, &mut rng);
+        let id = world
+            .spawn()
+            .insert(Mob { glyph: 's' })
+            .insert(
+                Stats::new(4, 2)
+                    .with_attack(1, DiceType::new(1, 4, 0), 0)
+                    .with_defence(1),
+            )
+            .insert(Speed::new(NORMAL_SPEED))
+            .insert(Viewshed::new(4))
+            .insert(pos)
+            .insert(Name {
+                name: "Sentry".to_string(),
+            })
+            .insert(Blackboard::default())
+            .insert(Script::new(sentry.clone()))
+            .id();
+        map.add_entity(&pos, id);
+    }
+
+    world.insert_resource(map);
+
+    let player = world
+        .spawn()
+        .insert(Player {})
+        .insert(CodeScanner)
+        .insert(starting_position)
+        .insert(Viewshed::new(5))
+        .insert(
+            Stats::new(10, 10)
+                .with_attack(2, DiceType::new(1, 6, 0), 0)
+                .with_defence(2),
+        )
+        .insert(Speed::new(NORMAL_SPEED))
+        .insert(Name {
+            name: "you".to_string(),
+        })
+        .id();
+    world.insert_resource(player);
+
+    let devices = [
+        (
+            "remote control",
+            3,
+            include_str!("../scripts/devices/remote.prl"),
+        ),
+        ("phaser", 1, include_str!("../scripts/devices/phaser.prl")),
+        (
+            "injector",
+            2,
+            include_str!("../scripts/devices/injector.prl"),
+        ),
+    ];
+    for (name, charge, source) in devices {
+        let program = Program::new(name, source).expect("device scripts should compile");
+        world
+            .spawn()
+            .insert(Device { charge })
+            .insert(CarriedBy { owner: player })
+            .insert(Name {
+                name: name.to_string(),
+            })
+            .insert(Script::new(Arc::new(program)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world() -> World {
+        crate::link_scripts();
+        let mut world = World::new();
+        let mut factory = MapFactory::new();
+        factory.add_builder(Box::new(RectRoomMapGenerator));
+        world.insert_resource(factory);
+        world.insert_resource(RandomNumberGenerator::seeded(1));
+        world.init_resource::<Events<KeyboardEvent>>();
+        world.init_resource::<Events<MeleeEvent>>();
+        world.init_resource::<Events<DealDamage>>();
+        world
+    }
+
+    #[test]
+    fn a_new_run_starts_from_scratch() {
+        let mut world = world();
+        start_run(&mut world);
+        let old_player = *world.get_resource::<Entity>().unwrap();
+        let spawned = world.query::<Entity>().iter(&world).count();
+
+        // the first run goes badly
+        let leftover = world.spawn().insert(Position { x: 0, y: 0 }).id();
+        world.insert_resource(Map::new(3, 3, 1, 1));
+        world.insert_resource(RunSummary {
+            turns: 12,
+            kills: 3,
+            depth: 1,
+            cause: Some("Killed by a Rat.".to_string()),
+        });
+        start_run(&mut world);
+
+        let player = *world.get_resource::<Entity>().unwrap();
+        assert!(world.get_entity(old_player).is_none());
+        assert!(world.get_entity(leftover).is_none());
+        assert!(world.get::<Player>(player).is_some());
+        assert_eq!(world.query::<Entity>().iter(&world).count(), spawned);
+
+        let map = world.get_resource::<Map>().unwrap();
+        assert_eq!((map.width(), map.height()), (WIDTH * 2, HEIGHT * 2));
+        let summary = world.get_resource::<RunSummary>().unwrap();
+        assert_eq!((summary.turns, summary.kills), (0, 0));
+        assert!(summary.cause.is_none());
+    }
+}
@@ -0,0 +1,29 @@
+use bevy_ecs::prelude::*;
+
+use crate::components::Blackboard;
+use crate::repl::Repl;
+use crate::script::*;
+
+/// Free the script objects that nothing holds on to any more, once the heap
+/// has grown enough to be worth it. The roots are every value a component
+/// keeps between turns: each mob's globals and suspended call, each mob's
+/// blackboard, the console's globals, and every imported module.
+pub fn collect_garbage(
+    mut engine: ResMut<ScriptEngine>,
+    repl: Res<Repl>,
+    scripts: Query<&Script>,
+    memories: Query<&Blackboard>,
+) {
+    if !engine.heap.should_collect_world() {
+        return;
+    }
+
+    let mut roots: Vec<Value> = scripts.iter().flat_map(Script::roots).collect();
+    for memory in memories.iter() {
+        roots.extend(memory.values.values());
+    }
+    roots.extend(repl.globals().map(Value::Obj));
+    roots.extend(engine.roots());
+
+    engine.heap.collect_world(roots);
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use bevy_ecs::prelude::*;
+use bracket_lib::prelude::*;
+
+use crate::combat::{Blow, DealDamage};
+use crate::components::*;
+use crate::map::Map;
+use crate::messages::Messages;
+use crate::script::*;
+use crate::system::Viewshed;
+
+pub type ScriptedMob<'a> = (
+    Entity,
+    &'a Position,
+    &'a Name,
+    Option<&'a Stats>,
+    Option<&'a Viewshed>,
+    &'a mut Script,
+    Option<&'a mut Blackboard>,
+);
+
+/// Deal with what a script handed back. A line to say is shown straight
+/// away, running out of fuel is worth telling the player about, and any
+/// other error goes to the log. Anything else the script wants to do is
+/// returned for the caller to carry out.
+pub fn script_outcome(
+    result: Result<Option<Intent>, RuntimeError>,
+    engine: &ScriptEngine,
+    messages: &mut Messages,
+    name: &Name,
+    script: &Script,
+) -> Option<Intent> {
+    match result {
+        Ok(Some(Intent::Say(text))) => {
+            messages.add(format!(
+                "The {} says \"{}\"",
+                name.name,
+                engine.heap.format(Value::Obj(text))
+            ));
+            None
+        }
+        Ok(intent) => intent,
+        Err(e) if e.kind == ErrorKind::OutOfFuel => {
+            messages.add(format!(
+                "The {} stops: its {} script ran out of fuel.",
+                name.name, script.program.name
+            ));
+            None
+        }
+        Err(e) => {
+            console::log(format!(
+                "{}: {} script error: {e}",
+                name.name, script.program.name
+            ));
+            None
+        }
+    }
+}
+
+/// Call a hook that happens outside the mob's turn. Only `on_turn` gets to
+/// move the mob, so all these hooks can do is speak.
+fn call_hook(
+    engine: &mut ScriptEngine,
+    messages: &mut Messages,
+    game: &mut GameView,
+    name: &Name,
+    script: &mut Script,
+    hook: &str,
+    args: &[Value],
+) {
+    let result = engine.run_hook(script, game, hook, args);
+    script_outcome(result, engine, messages, name, script);
+}
+
+/// Call `on_spawn` for every script handed to a mob since the last turn.
+pub fn spawn_hooks(
+    mut engine: ResMut<ScriptEngine>,
+    mut rng: ResMut<RandomNumberGenerator>,
+    mut messages: ResMut<Messages>,
+    map: Res<Map>,
+    player: Res<Entity>,
+    positions: Query<&Position>,
+    mut query: Query<ScriptedMob, Added<Script>>,
+) {
+    let player = positions.get(*player).ok().map(|p| (*player, *p));
+
+    for (_, position, name, stats, viewshed, mut script, mut memory) in query.iter_mut() {
+        let mut game = GameView {
+            map: Some(&map),
+            rng: Some(&mut rng),
+            player,
+            actor: Some(Actor::new(position, name, stats, viewshed)),
+            memory: memory.as_deref_mut(),
+            level: None,
+        };
+        call_hook(
+            &mut engine,
+            &mut messages,
+            &mut game,
+            name,
+            &mut script,
+            "on_spawn",
+            &[],
+        );
+    }
+}
+
+/// Call `on_see_player` for every mob that can see the player this turn but
+/// couldn't last turn.
+pub fn sight_hooks(
+    mut engine: ResMut<ScriptEngine>,
+    mut rng: ResMut<RandomNumberGenerator>,
+    mut messages: ResMut<Messages>,
+    map: Res<Map>,
+    player: Res<Entity>,
+    positions: Query<&Position>,
+    mut query: Query<ScriptedMob>,
+) {
+    let player_pos = match positions.get(*player) {
+        Ok(p) => *p,
+        Err(_) => return,
+    };
+
+    for (_, position, name, stats, viewshed, mut script, mut memory) in query.iter_mut() {
+        let sees_player = viewshed.is_some_and(|vs| vs.visible_tiles.contains(&player_pos.point()));
+        let first_sight = sees_player && !script.saw_player;
+        script.saw_player = sees_player;
+        if !first_sight {
+            continue;
+        }
+
+        let mut game = GameView {
+            map: Some(&map),
+            rng: Some(&mut rng),
+            player: Some((*player, player_pos)),
+            actor: Some(Actor::new(position, name, stats, viewshed)),
+            memory: memory.as_deref_mut(),
+            level: None,
+        };
+        call_hook(
+            &mut engine,
+            &mut messages,
+            &mut game,
+            name,
+            &mut script,
+            "on_see_player",
+            &[],
+        );
+    }
+}
+
+/// Call `on_hit` for every blow a mob took this turn, in the order they
+/// landed, and `on_death` for the ones that took too much. This runs after
+/// `deal_damage`, so the mob's stats already show the hit, but before the
+/// dead are cleared away.
+#[allow(clippy::too_many_arguments)]
+pub fn damage_hooks(
+    mut reader: EventReader<DealDamage>,
+    mut engine: ResMut<ScriptEngine>,
+    mut rng: ResMut<RandomNumberGenerator>,
+    mut messages: ResMut<Messages>,
+    map: Res<Map>,
+    player: Res<Entity>,
+    positions: Query<&Position>,
+    mut query: Query<ScriptedMob>,
+) {
+    let mut hits: HashMap<Entity, Vec<(Entity, i32)>> = HashMap::new();
+    for event in reader.iter().filter(|event| event.blow != Blow::Miss) {
+        hits.entry(event.target)
+            .or_default()
+            .push((event.source, event.amount));
+    }
+    if hits.is_empty() {
+        return;
+    }
+
+    let player = positions.get(*player).ok().map(|p| (*player, *p));
+
+    for (entity, position, name, stats, viewshed, mut script, mut memory) in query.iter_mut() {
+        let blows = match hits.get(&entity) {
+            Some(blows) => blows,
+            None => continue,
+        };
+
+        let mut game = GameView {
+            map: Some(&map),
+            rng: Some(&mut rng),
+            player,
+            actor: Some(Actor::new(position, name, stats, viewshed)),
+            memory: memory.as_deref_mut(),
+            level: None,
+        };
+        for (source, amount) in blows {
+            call_hook(
+                &mut engine,
+                &mut messages,
+                &mut game,
+                name,
+                &mut script,
+                "on_hit",
+                &[Value::Entity(*source), Value::Number(*amount as f64)],
+            );
+        }
+
+        if stats.is_some_and(|stats| stats.hp.cur < 0) {
+            call_hook(
+                &mut engine,
+                &mut messages,
+                &mut game,
+                name,
+                &mut script,
+                "on_death",
+                &[],
+            );
+        }
+    }
+}
@@ -1,2 +1,11 @@
 mod visibility;
 pub use visibility::*;
+
+mod hooks;
+pub use hooks::*;
+
+mod gc;
+pub use gc::*;
+
+mod status;
+pub use status::*;
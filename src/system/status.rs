@@ -0,0 +1,187 @@
+use bevy_ecs::prelude::*;
+
+use crate::combat::{Blow, DealDamage};
+use crate::components::*;
+use crate::messages::Messages;
+use crate::scheduler::Scheduler;
+
+/// Apply a turn's worth of every status effect for each turn that has gone
+/// by, and take away turns from anything that is stunned. Poison hurts
+/// through `DealDamage`, like any other blow, so the hooks hear about it
+/// and a poisoned player can die of it.
+pub fn tick_status_effects(
+    scheduler: Res<Scheduler>,
+    mut damage: EventWriter<DealDamage>,
+    mut messages: ResMut<Messages>,
+    player: Res<Entity>,
+    mut query: Query<(
+        Entity,
+        &mut StatusEffects,
+        Option<&mut Stats>,
+        Option<&mut Speed>,
+    )>,
+) {
+    for (entity, mut status, mut stats, speed) in query.iter_mut() {
+        let is_player = entity == *player;
+        for _ in 0..scheduler.turns_passed {
+            for effect in &status.effects {
+                match effect.effect {
+                    Effect::Poison => damage.send(DealDamage {
+                        source: entity,
+                        target: entity,
+                        name: effect.effect.name().to_string(),
+                        amount: 1,
+                        blow: Blow::Hit,
+                    }),
+                    Effect::Regen => {
+                        if let Some(stats) = stats.as_deref_mut() {
+                            stats.hp.cur = (stats.hp.cur + 1).min(stats.hp.max);
+                        }
+                    }
+                    Effect::Stun | Effect::Haste => {}
+                }
+            }
+
+            for effect in status.effects.iter_mut() {
+                effect.turns -= 1;
+                if effect.turns <= 0 && is_player {
+                    messages.add(format!("You are no longer {}.", effect.effect.adjective()));
+                }
+            }
+            status.effects.retain(|effect| effect.turns > 0);
+        }
+
+        if let Some(mut speed) = speed {
+            speed.speed = if status.has(Effect::Haste) {
+                speed.base * 2
+            } else {
+                speed.base
+            };
+            if status.has(Effect::Stun) && speed.ready() {
+                speed.spend();
+                if is_player {
+                    messages.add("You are stunned!");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_ecs::event::{Events, ManualEventReader};
+
+    use super::*;
+
+    fn world() -> (World, Entity) {
+        let mut world = World::new();
+        world.init_resource::<Scheduler>();
+        world.init_resource::<Events<DealDamage>>();
+        world.insert_resource(Messages::default());
+        let player = world
+            .spawn()
+            .insert(StatusEffects::default())
+            .insert(Stats::new(10, 0))
+            .insert(Speed::new(NORMAL_SPEED))
+            .id();
+        world.insert_resource(player);
+        (world, player)
+    }
+
+    /// Let `turns` turns go by in one step of the clock.
+    fn pass(world: &mut World, turns: u64) {
+        world.get_resource_mut::<Scheduler>().unwrap().turns_passed = turns;
+        let mut stage = SystemStage::single_threaded().with_system(tick_status_effects);
+        stage.run(world);
+    }
+
+    fn afflict(world: &mut World, entity: Entity, effect: Effect, turns: i32) {
+        let mut status = world.get_mut::<StatusEffects>(entity).unwrap();
+        status.add(effect, turns);
+    }
+
+    fn messages(world: &mut World) -> Vec<String> {
+        let mut messages = world.get_resource_mut::<Messages>().unwrap();
+        let mut all = Vec::new();
+        while let Some(message) = messages.current() {
+            all.push(message.trim_end_matches(" -More-").to_string());
+            messages.advance();
+        }
+        all
+    }
+
+    #[test]
+    fn poison_hurts_every_turn_until_it_wears_off() {
+        let (mut world, player) = world();
+        afflict(&mut world, player, Effect::Poison, 2);
+        let mut reader = ManualEventReader::<DealDamage>::default();
+        let mut blows = Vec::new();
+        for _ in 0..3 {
+            pass(&mut world, 1);
+            let events = world.get_resource::<Events<DealDamage>>().unwrap();
+            blows.push(reader.iter(events).count());
+        }
+        assert_eq!(blows, [1, 1, 0]);
+        assert!(world
+            .get::<StatusEffects>(player)
+            .unwrap()
+            .effects
+            .is_empty());
+        assert_eq!(messages(&mut world), ["You are no longer poisoned."]);
+    }
+
+    #[test]
+    fn poison_is_a_point_of_self_harm() {
+        let (mut world, player) = world();
+        afflict(&mut world, player, Effect::Poison, 5);
+        pass(&mut world, 1);
+        let events = world.get_resource::<Events<DealDamage>>().unwrap();
+        let mut reader = events.get_reader();
+        let event = reader.iter(events).next().unwrap();
+        assert_eq!((event.source, event.target), (player, player));
+        assert_eq!((event.amount, event.name.as_str()), (1, "poison"));
+    }
+
+    #[test]
+    fn regen_stops_at_max_hp() {
+        let (mut world, player) = world();
+        world.get_mut::<Stats>(player).unwrap().hp.cur = 7;
+        afflict(&mut world, player, Effect::Regen, 10);
+        pass(&mut world, 2);
+        assert_eq!(world.get::<Stats>(player).unwrap().hp.cur, 9);
+        pass(&mut world, 3);
+        assert_eq!(world.get::<Stats>(player).unwrap().hp.cur, 10);
+        let status = world.get::<StatusEffects>(player).unwrap();
+        assert_eq!(status.effects[0].turns, 5);
+    }
+
+    #[test]
+    fn stun_takes_the_turn() {
+        let (mut world, player) = world();
+        world.get_mut::<Speed>(player).unwrap().energy = TURN_COST;
+        afflict(&mut world, player, Effect::Stun, 2);
+        pass(&mut world, 0);
+        assert!(!world.get::<Speed>(player).unwrap().ready());
+        assert_eq!(messages(&mut world), ["You are stunned!"]);
+
+        // once it wears off, turns are kept again
+        pass(&mut world, 2);
+        world.get_mut::<Speed>(player).unwrap().energy = TURN_COST;
+        pass(&mut world, 0);
+        assert!(world.get::<Speed>(player).unwrap().ready());
+    }
+
+    #[test]
+    fn haste_lasts_as_long_as_the_effect() {
+        let (mut world, player) = world();
+        afflict(&mut world, player, Effect::Haste, 2);
+        pass(&mut world, 1);
+        assert_eq!(world.get::<Speed>(player).unwrap().speed, 2 * NORMAL_SPEED);
+        pass(&mut world, 1);
+        assert_eq!(world.get::<Speed>(player).unwrap().speed, NORMAL_SPEED);
+        assert!(!world
+            .get::<StatusEffects>(player)
+            .unwrap()
+            .has(Effect::Haste));
+    }
+}